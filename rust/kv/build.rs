@@ -1,7 +1,23 @@
 fn main() {
     let mut config = prost_build::Config::new();
-    config.bytes(&["."]);
+    config.bytes(["."]);
     config.type_attribute(".", "#[derive(PartialOrd)]");
+    config.type_attribute(
+        "abi.Compression",
+        "#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = \"lowercase\")]",
+    );
+    config.type_attribute(
+        "abi.ContentType",
+        "#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = \"lowercase\")]",
+    );
+    config.type_attribute(
+        "abi.ErrorKind",
+        "#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = \"snake_case\")]",
+    );
+    config.type_attribute(
+        "abi.Feature",
+        "#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = \"snake_case\")]",
+    );
     config
         .out_dir("src/pb")
         .compile_protos(&["abi.proto"], &["."])
@@ -0,0 +1,38 @@
+use anyhow::Result;
+use kv::{
+    AuthConfig, ClientConfig, ClientTlsConfig, GeneralConfig, LogConfig, ServerConfig,
+    ServerTlsConfig, StorageConfig,
+};
+use tokio::fs;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let addr = "127.0.0.1:9527";
+
+    let server_config = ServerConfig {
+        general: GeneralConfig::new(addr),
+        storage: StorageConfig::SledDb("/tmp/kv_server".into()),
+        tls: ServerTlsConfig {
+            cert: "fixtures/server.cert".into(),
+            key: "fixtures/server.key".into(),
+            ca: None,
+        },
+        log: LogConfig::default(),
+        auth: AuthConfig::default(),
+        metrics: None,
+        replication: None,
+    };
+    fs::write("fixtures/server.conf", toml::to_string_pretty(&server_config)?).await?;
+
+    let client_config = ClientConfig {
+        general: GeneralConfig::new(addr),
+        tls: ClientTlsConfig {
+            domain: "kvserver.acme.inc".into(),
+            identity: None,
+            ca: Some("fixtures/ca.cert".into()),
+        },
+    };
+    fs::write("fixtures/client.conf", toml::to_string_pretty(&client_config)?).await?;
+
+    Ok(())
+}
@@ -21,8 +21,10 @@ async fn main() -> Result<()> {
             let mut stream = 
                 AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
             while let Some(Ok(cmd)) = stream.next().await {
-                let res = svc.execute(cmd);
-                stream.send(res).await.unwrap();
+                let mut res = svc.execute(cmd);
+                while let Some(data) = res.next().await {
+                    stream.send(data.as_ref().clone()).await.unwrap();
+                }
             }
             info!("Client {:?} disconnected", addr);
         });
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use bytes::BytesMut;
 use futures::prelude::*;
 use kv::{CommandRequest, MemTable, Service, ServiceInner};
 use prost::Message;
@@ -21,13 +22,15 @@ async fn main() -> Result<()> {
             // let mut stream =
             //     AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
             let mut stream = Framed::new(stream, LengthDelimitedCodec::new());
-            while let Some(Ok(mut buf)) = stream.next().await {
+            while let Some(Ok(buf)) = stream.next().await {
                 let cmd = CommandRequest::decode(&buf[..]).unwrap();
                 info!("Got a new command: {:?}", cmd);
-                let res = svc.execute(cmd);
-                buf.clear();
-                res.encode(&mut buf).unwrap();
-                stream.send(buf.freeze()).await.unwrap();
+                let mut res = svc.execute(cmd);
+                while let Some(data) = res.next().await {
+                    let mut buf = BytesMut::new();
+                    data.encode(&mut buf).unwrap();
+                    stream.send(buf.freeze()).await.unwrap();
+                }
             }
             info!("Client {:?} disconnected", addr);
         });
@@ -1,13 +1,15 @@
 use anyhow::Result;
-use async_prost::AsyncProstStream;
-use futures::prelude::*;
-use kv::{CommandRequest, CommandResponse, Service, ServiceInner, SledDb};
+use kv::{ProstServerStream, Service, ServiceInner, SledDb, TlsServerAcceptor};
 use tokio::net::TcpListener;
 use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
+    let server_cert = include_str!("../fixtures/server.cert");
+    let server_key = include_str!("../fixtures/server.key");
+    let acceptor = TlsServerAcceptor::new(server_cert, server_key, None)?;
+
     // 初始化 service
     // let service: Service = ServiceInner::new(MemTable::new()).into();
     let service: Service<SledDb> = ServiceInner::new(SledDb::new("tmp/kvserver"))
@@ -19,17 +21,11 @@ async fn main() -> Result<()> {
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
     loop {
+        let tls = acceptor.clone();
         let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
-        let svc = service.clone();
-        tokio::spawn(async move {
-            let mut stream =
-                AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
-            while let Some(Ok(cmd)) = stream.next().await {
-                let res = svc.execute(cmd);
-                stream.send(res).await.unwrap();
-            }
-            info!("Client {:?} disconnected", addr);
-        });
+        let stream = tls.accept(stream).await?;
+        let stream = ProstServerStream::new(stream, service.clone());
+        tokio::spawn(async move { stream.process().await });
     }
-}
\ No newline at end of file
+}
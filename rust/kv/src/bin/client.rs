@@ -1,32 +1,577 @@
-use anyhow::Result;
-use kv::{CommandRequest, ProstClientStream, TlsClientConnector};
-use tokio::net::TcpStream;
-use tracing::info;
+use anyhow::{anyhow, bail, Result};
+use clap::{Parser, Subcommand};
+use futures::StreamExt;
+use kv::{
+    start_client_with_config, value, ClientConfig, CommandRequest, CommandResponse, ContentType,
+    Kvpair, ProstClientStream, Value, Watch,
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::{fmt::Write, path::PathBuf, time::Duration};
 
-use std::future::Future;
-use std::marker::Unpin;
+/// KV client，配置从 toml 文件读取，命令行参数可以覆盖配置文件中的值
+///
+/// 给出命令时执行这一个命令就退出，否则进入交互模式。
+/// value 的写法：42 是整数，4.2 是浮点数，true/false 是布尔值，0x6869 是字节，
+/// 其它的都是字符串，想要写成字符串的数字可以加上 str: 前缀，比如 str:42，
+/// json: 前缀表示序列化成 JSON 的值，比如 'json:{"name":"tyr"}'
+#[derive(Parser, Debug)]
+#[command(name = "kvc")]
+struct Args {
+    /// 配置文件路径
+    #[arg(short, long, default_value = "fixtures/client.conf")]
+    config: String,
+    /// 服务器地址
+    #[arg(long)]
+    addr: Option<String>,
+    /// 服务器证书中的域名
+    #[arg(long)]
+    domain: Option<String>,
+    /// 签发服务器证书的 CA 证书路径
+    #[arg(long)]
+    ca: Option<String>,
+    /// 客户端证书路径，需要和 --key 一起使用
+    #[arg(long, requires = "key")]
+    cert: Option<String>,
+    /// 客户端私钥路径
+    #[arg(long, requires = "cert")]
+    key: Option<String>,
+    /// 连接之后用这个 token 登录
+    #[arg(long)]
+    token: Option<String>,
+    /// 要执行的命令，不给出的话进入交互模式
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// 交互模式下输入的一行
+#[derive(Parser, Debug)]
+#[command(multicall = true)]
+struct Line {
+    #[command(subcommand)]
+    command: ReplCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ReplCommand {
+    #[command(flatten)]
+    Kv(Command),
+    /// 显示输入过的命令
+    History,
+    /// 退出交互模式
+    #[command(alias = "quit")]
+    Exit,
+}
+
+/// 所有的 KV 命令，和 CommandRequest 一一对应
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 获取 key 的 value
+    Hget { table: String, key: String },
+    /// 获取 table 里所有的 kv pair
+    Hgetall { table: String },
+    /// 获取一组 key 的 value
+    Hmget {
+        table: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// 设置 key 的 value
+    Hset {
+        table: String,
+        key: String,
+        #[arg(value_parser = parse_value, allow_hyphen_values = true)]
+        value: Value,
+        /// 过期时间，比如 500ms、10s、5m、1h
+        #[arg(long, value_parser = parse_duration)]
+        ttl: Option<Duration>,
+    },
+    /// 设置一组 kv pair：KEY VALUE [KEY VALUE ...]
+    Hmset {
+        table: String,
+        #[arg(required = true, allow_hyphen_values = true)]
+        pairs: Vec<String>,
+        /// 过期时间，比如 500ms、10s、5m、1h
+        #[arg(long, value_parser = parse_duration)]
+        ttl: Option<Duration>,
+    },
+    /// 删除 key
+    Hdel { table: String, key: String },
+    /// 删除一组 key
+    Hmdel {
+        table: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// 查看 key 是否存在
+    Hexist { table: String, key: String },
+    /// 查看一组 key 是否存在
+    Hmexist {
+        table: String,
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// 设置 key 的过期时间
+    Expire {
+        table: String,
+        key: String,
+        #[arg(value_parser = parse_duration)]
+        ttl: Duration,
+    },
+    /// 查看 key 还有多久过期
+    Ttl { table: String, key: String },
+    /// 去掉 key 的过期时间
+    Persist { table: String, key: String },
+    /// 按顺序获取 key 在 [start, end) 范围内的 kv pair
+    Hrange {
+        table: String,
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long, default_value = "")]
+        start: String,
+        #[arg(long, default_value = "")]
+        end: String,
+    },
+    /// 分页获取 key 大于 start_after 的 kv pair
+    Hscan {
+        table: String,
+        #[arg(long, default_value = "")]
+        prefix: String,
+        #[arg(long, default_value = "")]
+        start_after: String,
+        #[arg(long, default_value_t = 100)]
+        limit: u32,
+    },
+    /// 把 key 的整数 value 加上 delta
+    Hincrby {
+        table: String,
+        key: String,
+        #[arg(allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// 把 key 的浮点数 value 加上 delta
+    Hincrbyfloat {
+        table: String,
+        key: String,
+        #[arg(allow_negative_numbers = true)]
+        delta: f64,
+    },
+    /// key 的 value 等于 --expected 时设置成 new，不给出 --expected 表示 key 必须不存在
+    Hcas {
+        table: String,
+        key: String,
+        #[arg(value_parser = parse_value, allow_hyphen_values = true)]
+        new: Value,
+        #[arg(long, value_parser = parse_value, allow_hyphen_values = true)]
+        expected: Option<Value>,
+    },
+    /// 订阅 topic，交互模式下在后台打印收到的数据
+    Subscribe { topic: String },
+    /// 取消订阅，id 是 subscribe 返回的 id
+    Unsubscribe { topic: String, id: u32 },
+    /// 往 topic 里发布数据
+    Publish {
+        topic: String,
+        #[arg(required = true, value_parser = parse_value, allow_hyphen_values = true)]
+        data: Vec<Value>,
+    },
+    /// 在一个事务里执行一组命令，每个命令是一个字符串，比如 "hset t1 k1 v1"
+    Txn {
+        #[arg(required = true)]
+        commands: Vec<String>,
+        /// 事务执行时 key 的值必须是 value：TABLE:KEY=VALUE，不给出 =VALUE 表示 key 必须不存在
+        #[arg(long = "watch", value_parser = parse_watch)]
+        watches: Vec<Watch>,
+    },
+    /// 用 token 登录
+    Auth { token: String },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    // 以后用配置替换
-    let ca_cert = include_str!("../../fixtures/ca.cert");
+    let args = Args::parse();
+    let mut config = ClientConfig::load(&args.config)?;
+    if let Some(addr) = args.addr {
+        config.general.addr = addr;
+    }
+    if let Some(domain) = args.domain {
+        config.tls.domain = domain;
+    }
+    if let Some(ca) = args.ca {
+        config.tls.ca = Some(ca);
+    }
+    if let (Some(cert), Some(key)) = (args.cert, args.key) {
+        config.tls.identity = Some((cert, key));
+    }
 
-    let addr = "127.0.0.1:9527";
     // 连接服务器
-    let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(ca_cert))?;
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
+    let client = start_client_with_config(&config).await?;
+    if let Some(token) = args.token {
+        let res = client.execute(CommandRequest::new_auth(token)).await?;
+        if res.status != 200 {
+            bail!("Failed to login: {}", res.message);
+        }
+    }
 
-    let mut client = ProstClientStream::new(stream);
+    match args.command {
+        Some(cmd) => {
+            let status = run(&client, cmd, false).await?;
+            if status >= 400 {
+                std::process::exit(1);
+            }
+        }
+        None => repl(&client).await?,
+    }
+
+    Ok(())
+}
 
-    // 生成一个 HSET 命令
-    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+/// 交互模式：读一行，执行一行，直到 exit 或者 Ctrl-D
+async fn repl(client: &ProstClientStream) -> Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // 第一次使用时还没有历史记录
+        let _ = editor.load_history(path);
+    }
 
-    // 发送 HSET 命令
-    let data = client.execute(cmd).await?;
-    info!("Got response {:?}", data);
+    loop {
+        // readline 会阻塞，不能卡住 runtime 里的其它任务（比如订阅）
+        let line = match tokio::task::block_in_place(|| editor.readline("kv> ")) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
 
+        let cmd = match parse_line(line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        match cmd {
+            ReplCommand::Kv(cmd) => {
+                if let Err(e) = run(client, cmd, true).await {
+                    println!("(error) {}", e);
+                }
+            }
+            ReplCommand::History => {
+                for (i, line) in editor.history().iter().enumerate() {
+                    println!("{:>4}  {}", i + 1, line);
+                }
+            }
+            ReplCommand::Exit => break,
+        }
+    }
+
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// 执行一个命令并打印结果，返回 response 的状态码
+async fn run(client: &ProstClientStream, cmd: Command, interactive: bool) -> Result<u32> {
+    let topic = match &cmd {
+        Command::Subscribe { topic } => topic.clone(),
+        _ => {
+            let res = client.execute(cmd.into_request()?).await?;
+            println!("{}", format_response(&res));
+            return Ok(res.status);
+        }
+    };
+
+    let mut stream = client.execute_streaming(cmd.into_request()?).await?;
+    println!("Subscribed to {}, id {}", topic, stream.id);
+    let printer = async move {
+        while let Some(res) = stream.next().await {
+            match res {
+                Ok(res) => println!("[{}] {}", topic, format_response(&res)),
+                Err(e) => println!("[{}] (error) {}", topic, e),
+            }
+        }
+    };
+    // 交互模式下在后台打印，可以继续输入其它命令
+    if interactive {
+        tokio::spawn(printer);
+    } else {
+        printer.await;
+    }
+    Ok(200)
+}
+
+impl Command {
+    fn into_request(self) -> Result<CommandRequest> {
+        let cmd = match self {
+            Command::Hget { table, key } => CommandRequest::new_hget(table, key),
+            Command::Hgetall { table } => CommandRequest::new_hgetall(table),
+            Command::Hmget { table, keys } => CommandRequest::new_hmget(table, keys),
+            Command::Hset { table, key, value, ttl: None } => {
+                CommandRequest::new_hset(table, key, value)
+            }
+            Command::Hset { table, key, value, ttl: Some(ttl) } => {
+                CommandRequest::new_hset_with_ttl(table, key, value, ttl)
+            }
+            Command::Hmset { table, pairs, ttl } => {
+                if !pairs.len().is_multiple_of(2) {
+                    bail!("hmset needs KEY VALUE pairs, but got {} arguments", pairs.len());
+                }
+                let pairs = pairs
+                    .chunks(2)
+                    .map(|kv| Ok(Kvpair::new(kv[0].as_str(), parse_value(&kv[1])?)))
+                    .collect::<Result<Vec<_>, String>>()
+                    .map_err(|e| anyhow!(e))?;
+                match ttl {
+                    Some(ttl) => CommandRequest::new_hmset_with_ttl(table, pairs, ttl),
+                    None => CommandRequest::new_hmset(table, pairs),
+                }
+            }
+            Command::Hdel { table, key } => CommandRequest::new_hdel(table, key),
+            Command::Hmdel { table, keys } => CommandRequest::new_hmdel(table, keys),
+            Command::Hexist { table, key } => CommandRequest::new_hexist(table, key),
+            Command::Hmexist { table, keys } => CommandRequest::new_hmexist(table, keys),
+            Command::Expire { table, key, ttl } => CommandRequest::new_expire(table, key, ttl),
+            Command::Ttl { table, key } => CommandRequest::new_ttl(table, key),
+            Command::Persist { table, key } => CommandRequest::new_persist(table, key),
+            Command::Hrange { table, prefix, start, end } => {
+                CommandRequest::new_hrange(table, prefix, start, end)
+            }
+            Command::Hscan { table, prefix, start_after, limit } => {
+                CommandRequest::new_hscan(table, prefix, start_after, limit)
+            }
+            Command::Hincrby { table, key, delta } => CommandRequest::new_hincrby(table, key, delta),
+            Command::Hincrbyfloat { table, key, delta } => {
+                CommandRequest::new_hincrbyfloat(table, key, delta)
+            }
+            Command::Hcas { table, key, new, expected } => {
+                CommandRequest::new_hcas(table, key, expected, new)
+            }
+            Command::Subscribe { topic } => CommandRequest::new_subscribe(topic),
+            Command::Unsubscribe { topic, id } => CommandRequest::new_unsubscribe(topic, id),
+            Command::Publish { topic, data } => CommandRequest::new_publish(topic, data),
+            Command::Txn { commands, watches } => {
+                let commands = commands
+                    .iter()
+                    .map(|line| match parse_line(line)? {
+                        ReplCommand::Kv(cmd) => cmd.into_request(),
+                        _ => bail!("{} can't be used in a transaction", line),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                CommandRequest::new_transaction(commands, watches)
+            }
+            Command::Auth { token } => CommandRequest::new_auth(token),
+        };
+        Ok(cmd)
+    }
+}
+
+/// 按 shell 的规则切分一行输入，再解析成命令
+fn parse_line(line: &str) -> Result<ReplCommand> {
+    let words = shlex::split(line).ok_or_else(|| anyhow!("Unbalanced quotes in: {}", line))?;
+    let line = Line::try_parse_from(words)?;
+    Ok(line.command)
+}
+
+/// 解析 value 的字面量
+fn parse_value(s: &str) -> Result<Value, String> {
+    if let Some(s) = s.strip_prefix("str:") {
+        return Ok(s.into());
+    }
+    if let Some(json) = s.strip_prefix("json:") {
+        let v: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        return Value::from_serialize(&v, ContentType::Json).map_err(|e| e.to_string());
+    }
+    if let Some(hex) = s.strip_prefix("0x") {
+        return decode_hex(hex).map(|data| bytes::Bytes::from(data).into());
+    }
+    if let Ok(v) = s.parse::<i64>() {
+        return Ok(v.into());
+    }
+    if let Ok(v) = s.parse::<f64>() {
+        return Ok(v.into());
+    }
+    match s {
+        "true" => Ok(true.into()),
+        "false" => Ok(false.into()),
+        _ => Ok(s.into()),
+    }
+}
+
+/// 解析 500ms、10s、5m、1h 这样的时间，没有单位时是秒
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| format!("Invalid duration: {}", s))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "" | "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 3600)),
+        _ => Err(format!("Invalid duration unit: {}", unit)),
+    }
+}
+
+/// 解析 TABLE:KEY=VALUE 或者 TABLE:KEY
+fn parse_watch(s: &str) -> Result<Watch, String> {
+    let (table, rest) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Watch should be TABLE:KEY[=VALUE], but got {}", s))?;
+    match rest.split_once('=') {
+        Some((key, value)) => Ok(Watch::new(table, key, Some(parse_value(value)?))),
+        None => Ok(Watch::new(table, rest, None)),
+    }
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("Hex string should have even length: {}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("Invalid hex string: {}", s))
+        })
+        .collect()
+}
+
+/// 把 response 格式化成适合人看的样子
+fn format_response(res: &CommandResponse) -> String {
+    if res.status >= 400 {
+        return format!("(error {}) {}", res.status, res.message);
+    }
+
+    let mut out = String::new();
+    // 事务的结果：每个命令一个 response
+    if !res.responses.is_empty() {
+        for (i, res) in res.responses.iter().enumerate() {
+            let _ = writeln!(out, "{}) {}", i + 1, format_response(res).replace('\n', "\n   "));
+        }
+        out.pop();
+        return out;
+    }
+
+    match (res.values.as_slice(), res.pairs.as_slice()) {
+        ([], []) => "OK".into(),
+        ([v], []) => format_value(v),
+        (values, pairs) => {
+            for (i, v) in values.iter().enumerate() {
+                let _ = writeln!(out, "{}) {}", i + 1, format_value(v));
+            }
+            for pair in pairs {
+                let v = pair.value.as_ref().map_or_else(|| "(nil)".into(), format_value);
+                let _ = writeln!(out, "{} => {}", pair.key, v);
+            }
+            out.pop();
+            out
+        }
+    }
+}
+
+fn format_value(v: &Value) -> String {
+    match &v.value {
+        None => "(nil)".into(),
+        Some(value::Value::String(s)) => format!("{:?}", s),
+        Some(value::Value::Integer(i)) => format!("(integer) {}", i),
+        Some(value::Value::Float(f)) => format!("(float) {}", f),
+        Some(value::Value::Bool(b)) => format!("(bool) {}", b),
+        Some(value::Value::Binary(data)) => format!("(bytes) 0x{}", encode_hex(data)),
+        Some(value::Value::Encoded(e)) => match ContentType::from_i32(e.content_type) {
+            Some(ContentType::Json) => format!("(json) {}", String::from_utf8_lossy(&e.data)),
+            Some(content_type) => format!("({}) 0x{}", content_type.name(), encode_hex(&e.data)),
+            None => format!("(encoded) 0x{}", encode_hex(&e.data)),
+        },
+    }
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 历史记录保存在 $HOME/.kvc_history
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvc_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_literals_should_be_parsed() {
+        assert_eq!(parse_value("42"), Ok(42.into()));
+        assert_eq!(parse_value("-4.5"), Ok((-4.5).into()));
+        assert_eq!(parse_value("true"), Ok(true.into()));
+        assert_eq!(parse_value("0x6869"), Ok(b"hi".into()));
+        assert_eq!(parse_value("hello"), Ok("hello".into()));
+        assert_eq!(parse_value("str:42"), Ok("42".into()));
+        let v = parse_value(r#"json:{"a": [1, 2]}"#).unwrap();
+        assert_eq!(format_value(&v), r#"(json) {"a":[1,2]}"#);
+        assert!(parse_value("json:{").is_err());
+        assert!(parse_value("0x123").is_err());
+    }
+
+    #[test]
+    fn durations_and_watches_should_be_parsed() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("2d").is_err());
+
+        assert_eq!(parse_watch("t1:k1=1"), Ok(Watch::new("t1", "k1", Some(1.into()))));
+        assert_eq!(parse_watch("t1:k1"), Ok(Watch::new("t1", "k1", None)));
+        assert!(parse_watch("k1").is_err());
+    }
+
+    #[test]
+    fn lines_should_be_parsed_into_requests() -> Result<()> {
+        let request = |line: &str| match parse_line(line)? {
+            ReplCommand::Kv(cmd) => cmd.into_request(),
+            cmd => bail!("unexpected command {:?}", cmd),
+        };
+
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "k 1", 42.into(), Duration::from_secs(5));
+        assert_eq!(request("hset t1 'k 1' 42 --ttl 5s")?, cmd);
+        let pairs = vec![Kvpair::new("k1", "v1".into()), Kvpair::new("k2", (-1).into())];
+        assert_eq!(request("hmset t1 k1 v1 k2 -1")?, CommandRequest::new_hmset("t1", pairs));
+        assert_eq!(request("hincrby t1 k1 -3")?, CommandRequest::new_hincrby("t1", "k1", -3));
+        assert!(request("hmset t1 k1").is_err());
+
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hdel("t1", "k2"),
+        ];
+        let watches = vec![Watch::new("t1", "k1", None)];
+        let txn = request(r#"txn "hset t1 k1 v1" "hdel t1 k2" --watch t1:k1"#)?;
+        assert_eq!(txn, CommandRequest::new_transaction(cmds, watches));
+
+        assert!(matches!(parse_line("quit")?, ReplCommand::Exit));
+        assert!(parse_line("hget t1").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn responses_should_be_pretty_printed() {
+        let res: CommandResponse = Value::from(42).into();
+        assert_eq!(format_response(&res), "(integer) 42");
+
+        let res: CommandResponse = vec![Value::from("v1"), Value::default()].into();
+        assert_eq!(format_response(&res), "1) \"v1\"\n2) (nil)");
+
+        let res: CommandResponse = vec![Kvpair::new("k1", b"hi".into())].into();
+        assert_eq!(format_response(&res), "k1 => (bytes) 0x6869");
+
+        let res: CommandResponse = kv::KvError::NotFound("t1".into(), "k1".into()).into();
+        assert_eq!(format_response(&res), "(error 404) Not found for table: t1, key: k1");
+
+        assert_eq!(format_response(&CommandResponse::ok()), "OK");
+    }
+}
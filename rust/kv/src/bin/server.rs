@@ -1,27 +1,177 @@
 use anyhow::Result;
-use kv::{MemTable, ProstServerStream, Service, ServiceInner, TlsServerAcceptor};
-use tokio::net::TcpListener;
-use tracing::info;
+use clap::{Parser, ValueEnum};
+use kv::{start_server_with_shutdown, KvError, ServerConfig, StorageConfig};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// KV server，配置从 toml 文件读取，命令行参数可以覆盖配置文件中的值
+#[derive(Parser, Debug)]
+#[command(name = "kvs")]
+struct Args {
+    /// 配置文件路径
+    #[arg(short, long, default_value = "fixtures/server.conf")]
+    config: String,
+    /// 监听地址
+    #[arg(long)]
+    addr: Option<String>,
+    /// 存储后端
+    #[arg(long, value_enum)]
+    storage: Option<Backend>,
+    /// SledDb 或者 Durable 存储的数据路径
+    #[arg(long)]
+    storage_path: Option<String>,
+    /// 服务器证书路径
+    #[arg(long)]
+    cert: Option<String>,
+    /// 服务器私钥路径
+    #[arg(long)]
+    key: Option<String>,
+    /// 签发客户端证书的 CA 证书路径，提供之后要求客户端证书
+    #[arg(long)]
+    ca: Option<String>,
+    /// 日志级别
+    #[arg(long)]
+    log_level: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Backend {
+    Memtable,
+    Sleddb,
+    /// 带日志和 snapshot 的 MemTable
+    Durable,
+}
+
+impl Args {
+    /// 用命令行参数覆盖配置文件
+    fn apply(self, mut config: ServerConfig) -> Result<ServerConfig, KvError> {
+        if let Some(addr) = self.addr {
+            config.general.addr = addr;
+        }
+
+        config.storage = match (self.storage, self.storage_path, config.storage) {
+            (Some(Backend::Memtable), None, _) => StorageConfig::MemTable,
+            // MemTable 不存盘，给出的路径用不上，多半是参数写错了
+            (Some(Backend::Memtable), Some(_), _) | (None, Some(_), StorageConfig::MemTable) => {
+                return Err(KvError::ConfigError(
+                    self.config,
+                    "memtable storage doesn't take --storage-path".into(),
+                ));
+            }
+            (Some(Backend::Sleddb), Some(path), _) | (None, Some(path), StorageConfig::SledDb(_)) => {
+                StorageConfig::SledDb(path)
+            }
+            (Some(Backend::Durable), Some(path), _)
+            | (None, Some(path), StorageConfig::DurableMemTable(_)) => {
+                StorageConfig::DurableMemTable(path)
+            }
+            // 没有给出路径时，沿用配置文件里同一种存储的路径
+            (Some(Backend::Sleddb), None, storage @ StorageConfig::SledDb(_))
+            | (Some(Backend::Durable), None, storage @ StorageConfig::DurableMemTable(_)) => {
+                storage
+            }
+            (Some(backend), None, _) => {
+                let name = backend.to_possible_value().map(|v| v.get_name().to_string());
+                return Err(KvError::ConfigError(
+                    self.config,
+                    format!("{} storage requires --storage-path", name.unwrap_or_default()),
+                ));
+            }
+            (_, _, storage) => storage,
+        };
+
+        if let Some(cert) = self.cert {
+            config.tls.cert = cert;
+        }
+        if let Some(key) = self.key {
+            config.tls.key = key;
+        }
+        if let Some(ca) = self.ca {
+            config.tls.ca = Some(ca);
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+        Ok(config)
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let addr = "127.0.0.1:9527";
-
-    // 以后从配置文件取
-    let server_cert = include_str!("../../fixtures/server.cert");
-    let server_key = include_str!("../../fixtures/server.key");
-
-    let acceptor = TlsServerAcceptor::new(server_cert, server_key, None)?;
-    let service: Service = ServiceInner::new(MemTable::new()).into();
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start listening on {}", addr);
-    loop {
-        let tls = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {:?} connected", addr);
-        let stream = tls.accept(stream).await?;
-        let stream = ProstServerStream::new(stream, service.clone());
-        tokio::spawn(async move { stream.process().await });
+    let args = Args::parse();
+    let args_config = args.config.clone();
+    let config = ServerConfig::load(&args.config)?;
+    let config = args.apply(config)?;
+
+    let filter = EnvFilter::try_new(&config.log.level)
+        .map_err(|e| KvError::ConfigError(args_config, e.to_string()))?;
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    start_server_with_shutdown(&config, shutdown_signal()).await?;
+    Ok(())
+}
+
+/// 等待 Ctrl-C 或者 SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            // 注册不了信号处理的话，就只能被强行杀掉了
+            warn!("Failed to listen for Ctrl-C: {:?}", e);
+            futures::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {:?}", e);
+                futures::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Got Ctrl-C"),
+        _ = terminate => info!("Got SIGTERM"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(args: &[&str], storage: StorageConfig) -> Result<StorageConfig, KvError> {
+        let mut config = ServerConfig::load("fixtures/server.conf").unwrap();
+        config.storage = storage;
+        let args = Args::try_parse_from(std::iter::once("kvs").chain(args.iter().copied()));
+        Ok(args.unwrap().apply(config)?.storage)
+    }
+
+    #[test]
+    fn storage_args_should_override_config() {
+        let sled = StorageConfig::SledDb("/tmp/a".into());
+        let res = apply(&["--storage-path", "/tmp/b"], sled.clone());
+        assert_eq!(res.unwrap(), StorageConfig::SledDb("/tmp/b".into()));
+        let res = apply(&["--storage", "durable", "--storage-path", "/tmp/b"], sled.clone());
+        assert_eq!(res.unwrap(), StorageConfig::DurableMemTable("/tmp/b".into()));
+        let res = apply(&["--storage", "memtable"], sled.clone());
+        assert_eq!(res.unwrap(), StorageConfig::MemTable);
+        assert!(apply(&["--storage", "durable"], sled).is_err());
+    }
+
+    #[test]
+    fn storage_path_should_be_rejected_for_memtable() {
+        let args = ["--storage", "memtable", "--storage-path", "/tmp/b"];
+        let res = apply(&args, StorageConfig::SledDb("/tmp/a".into()));
+        assert!(matches!(res, Err(KvError::ConfigError(..))));
+        let res = apply(&["--storage-path", "/tmp/b"], StorageConfig::MemTable);
+        assert!(matches!(res, Err(KvError::ConfigError(..))));
     }
-}
\ No newline at end of file
+}
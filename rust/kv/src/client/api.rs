@@ -0,0 +1,401 @@
+use std::time::Duration;
+
+use crate::{CommandRequest, CommandResponse, KvError, Kvpair, StreamResult, Value};
+
+use super::KvClient;
+
+/// 类型化的接口：帮调用者构造 CommandRequest，检查状态码，把结果转换成需要的类型
+///
+/// 非 2xx 的 response 转换成对应的 KvError，比如 key 不存在时返回 KvError::NotFound；
+/// 结果通过 TryFrom<Value> 转换，类型不对时返回 KvError::ConvertError
+impl KvClient {
+    /// 读取一个 key，key 不存在时返回 KvError::NotFound
+    pub async fn hget<T>(&self, table: &str, key: &str) -> Result<T, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let res = self.call(CommandRequest::new_hget(table, key)).await?;
+        decode(first_value(res)?)
+    }
+
+    /// 读取多个 key，结果和 keys 一一对应，不存在的 key 返回 None
+    pub async fn hmget<T>(&self, table: &str, keys: &[&str]) -> Result<Vec<Option<T>>, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let cmd = CommandRequest::new_hmget(table, to_strings(keys));
+        let res = self.call(cmd).await?;
+        res.values.into_iter().map(|v| optional(v).map(decode).transpose()).collect()
+    }
+
+    /// 读取 table 里所有的 kv pair
+    pub async fn hgetall<T>(&self, table: &str) -> Result<Vec<(String, T)>, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let res = self.call(CommandRequest::new_hgetall(table)).await?;
+        decode_pairs(res.pairs)
+    }
+
+    /// 按 key 的顺序读取 [start, end) 范围里以 prefix 开头的 kv pair
+    ///
+    /// start 或者 end 为空表示不限制
+    pub async fn hrange<T>(
+        &self,
+        table: &str,
+        prefix: &str,
+        start: &str,
+        end: &str,
+    ) -> Result<Vec<(String, T)>, KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let res = self.call(CommandRequest::new_hrange(table, prefix, start, end)).await?;
+        decode_pairs(res.pairs)
+    }
+
+    /// 分页读取 start_after 之后以 prefix 开头的 kv pair
+    ///
+    /// 同时返回下一页的 cursor，没有下一页时为 None
+    pub async fn hscan<T>(
+        &self,
+        table: &str,
+        prefix: &str,
+        start_after: &str,
+        limit: u32,
+    ) -> Result<(Vec<(String, T)>, Option<String>), KvError>
+    where
+        T: TryFrom<Value>,
+        KvError: From<T::Error>,
+    {
+        let cmd = CommandRequest::new_hscan(table, prefix, start_after, limit);
+        let mut res = self.call(cmd).await?;
+        let cursor = decode::<String>(res.values.pop().unwrap_or_default())?;
+        let cursor = Some(cursor).filter(|c| !c.is_empty());
+        Ok((decode_pairs(res.pairs)?, cursor))
+    }
+
+    /// 写入一个 key，返回之前的值
+    pub async fn hset(
+        &self,
+        table: &str,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let res = self.call(CommandRequest::new_hset(table, key, value.into())).await?;
+        Ok(optional(first_value(res)?))
+    }
+
+    /// 写入一个 key，ttl 之后过期，返回之前的值
+    pub async fn hset_with_ttl(
+        &self,
+        table: &str,
+        key: &str,
+        value: impl Into<Value>,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hset_with_ttl(table, key, value.into(), ttl);
+        let res = self.call(cmd).await?;
+        Ok(optional(first_value(res)?))
+    }
+
+    /// 写入多个 key，返回每个 key 之前的值
+    pub async fn hmset<K, V>(
+        &self,
+        table: &str,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Vec<Option<Value>>, KvError>
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        let pairs = pairs.into_iter().map(|(k, v)| Kvpair::new(k, v.into())).collect();
+        let res = self.call(CommandRequest::new_hmset(table, pairs)).await?;
+        Ok(res.values.into_iter().map(optional).collect())
+    }
+
+    /// 删除一个 key，返回之前的值
+    pub async fn hdel(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let res = self.call(CommandRequest::new_hdel(table, key)).await?;
+        Ok(optional(first_value(res)?))
+    }
+
+    /// 删除多个 key，返回每个 key 之前的值
+    pub async fn hmdel(&self, table: &str, keys: &[&str]) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.call(CommandRequest::new_hmdel(table, to_strings(keys))).await?;
+        Ok(res.values.into_iter().map(optional).collect())
+    }
+
+    /// key 是否存在
+    pub async fn hexist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let res = self.call(CommandRequest::new_hexist(table, key)).await?;
+        decode(first_value(res)?)
+    }
+
+    /// 多个 key 是否存在，结果和 keys 一一对应
+    pub async fn hmexist(&self, table: &str, keys: &[&str]) -> Result<Vec<bool>, KvError> {
+        let res = self.call(CommandRequest::new_hmexist(table, to_strings(keys))).await?;
+        res.values.into_iter().map(decode).collect()
+    }
+
+    /// 设置 key 的过期时间，key 不存在时返回 false
+    pub async fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let res = self.call(CommandRequest::new_expire(table, key, ttl)).await?;
+        decode(first_value(res)?)
+    }
+
+    /// key 剩下的存活时间，没有过期时间时返回 None，key 不存在时返回 KvError::NotFound
+    pub async fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let res = self.call(CommandRequest::new_ttl(table, key)).await?;
+        match decode::<i64>(first_value(res)?)? {
+            -2 => Err(KvError::NotFound(table.into(), key.into())),
+            -1 => Ok(None),
+            ms => Ok(Some(Duration::from_millis(ms as u64))),
+        }
+    }
+
+    /// 去掉 key 的过期时间，key 不存在或者本来就没有过期时间时返回 false
+    pub async fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let res = self.call(CommandRequest::new_persist(table, key)).await?;
+        decode(first_value(res)?)
+    }
+
+    /// 给整数加上 delta，返回加完之后的值
+    pub async fn hincrby(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let res = self.call(CommandRequest::new_hincrby(table, key, delta)).await?;
+        decode(first_value(res)?)
+    }
+
+    /// 给浮点数加上 delta，返回加完之后的值
+    pub async fn hincrbyfloat(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let res = self.call(CommandRequest::new_hincrbyfloat(table, key, delta)).await?;
+        decode(first_value(res)?)
+    }
+
+    /// 当前的值等于 expected 时写入 new，expected 为 None 表示 key 必须不存在
+    ///
+    /// 返回是否写入成功，以及写入前的值
+    pub async fn hcas(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<Value>,
+        new: impl Into<Value>,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        let cmd = CommandRequest::new_hcas(table, key, expected, new.into());
+        let mut values = self.call(cmd).await?.values.into_iter();
+        match (values.next(), values.next()) {
+            (Some(swapped), Some(current)) => Ok((decode(swapped)?, optional(current))),
+            _ => Err(KvError::Internal("Missing values in response".into())),
+        }
+    }
+
+    /// 发布消息到 topic
+    pub async fn publish(&self, topic: &str, data: Vec<Value>) -> Result<(), KvError> {
+        self.call(CommandRequest::new_publish(topic, data)).await?;
+        Ok(())
+    }
+
+    /// 订阅 topic，drop 掉返回的 stream 就不再接收消息
+    pub async fn subscribe(&self, topic: &str) -> Result<StreamResult, KvError> {
+        self.execute_streaming(CommandRequest::new_subscribe(topic)).await
+    }
+
+    /// 执行命令，非 2xx 的 response 转换成 KvError
+    async fn call(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.execute(cmd).await?.into_result()
+    }
+}
+
+fn first_value(res: CommandResponse) -> Result<Value, KvError> {
+    res.values
+        .into_iter()
+        .next()
+        .ok_or_else(|| KvError::Internal("Missing value in response".into()))
+}
+
+fn decode<T>(v: Value) -> Result<T, KvError>
+where
+    T: TryFrom<Value>,
+    KvError: From<T::Error>,
+{
+    Ok(T::try_from(v)?)
+}
+
+fn decode_pairs<T>(pairs: Vec<Kvpair>) -> Result<Vec<(String, T)>, KvError>
+where
+    T: TryFrom<Value>,
+    KvError: From<T::Error>,
+{
+    pairs
+        .into_iter()
+        .map(|pair| Ok((pair.key, decode(pair.value.unwrap_or_default())?)))
+        .collect()
+}
+
+/// 服务器用 Value::default() 表示值不存在
+fn optional(v: Value) -> Option<Value> {
+    v.value.is_some().then_some(v)
+}
+
+fn to_strings(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|k| k.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{
+        AuthConfig, ContentType, MemTable, ProstClientStream, ProstServerStream, Serde, Service,
+        ServiceInner, TableAcl,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn typed_api_should_decode_values() -> Result<()> {
+        let client = start_client(ServiceInner::new(MemTable::new()).into()).await?;
+
+        assert_eq!(client.hset("t1", "count", 10).await?, None);
+        assert_eq!(client.hset("t1", "count", 42).await?, Some(10.into()));
+        assert_eq!(client.hget::<i64>("t1", "count").await?, 42);
+        assert_eq!(client.hget::<Value>("t1", "count").await?, 42.into());
+        assert_eq!(client.hincrby("t1", "count", 8).await?, 50);
+
+        let pairs = vec![("a", "x"), ("b", "y")];
+        assert_eq!(client.hmset("t2", pairs).await?, vec![None, None]);
+        let values = client.hmget::<String>("t2", &["a", "nope", "b"]).await?;
+        assert_eq!(values, vec![Some("x".into()), None, Some("y".into())]);
+        let mut all = client.hgetall::<String>("t2").await?;
+        all.sort();
+        assert_eq!(all, vec![("a".into(), "x".into()), ("b".into(), "y".into())]);
+        let (page, cursor) = client.hscan::<String>("t2", "", "", 1).await?;
+        assert_eq!(page, vec![("a".into(), "x".into())]);
+        assert_eq!(cursor.as_deref(), Some("a"));
+        let (page, cursor) = client.hscan::<String>("t2", "", "a", 1).await?;
+        assert_eq!(page, vec![("b".into(), "y".into())]);
+        assert_eq!(cursor, None);
+
+        assert!(client.hexist("t2", "a").await?);
+        assert_eq!(client.hmexist("t2", &["a", "c"]).await?, vec![true, false]);
+        assert_eq!(client.hdel("t2", "a").await?, Some("x".into()));
+        assert_eq!(client.hdel("t2", "a").await?, None);
+
+        let bin = Bytes::from_static(b"\x00\x01");
+        client.hset("t3", "bin", bin.clone()).await?;
+        assert_eq!(client.hget::<Bytes>("t3", "bin").await?, bin);
+        let (swapped, current) = client.hcas("t3", "bin", None, true).await?;
+        assert!(!swapped);
+        assert_eq!(current, Some(bin.into()));
+
+        assert_eq!(client.ttl("t3", "bin").await?, None);
+        assert!(client.expire("t3", "bin", Duration::from_secs(60)).await?);
+        assert!(client.ttl("t3", "bin").await?.unwrap() > Duration::from_secs(50));
+        assert!(client.persist("t3", "bin").await?);
+
+        let v = Value::from_serialize(&vec![1, 2, 3], ContentType::Cbor)?;
+        client.hset("t3", "list", v).await?;
+        let Serde(list) = client.hget::<Serde<Vec<i32>>>("t3", "list").await?;
+        assert_eq!(list, vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn typed_api_should_map_errors() -> Result<()> {
+        let mut tables = std::collections::HashMap::new();
+        let acl = TableAcl {
+            read: vec!["alice".into()],
+            write: vec!["alice".into()],
+        };
+        tables.insert("secret".to_string(), acl);
+        let config = AuthConfig {
+            tokens: Default::default(),
+            tables,
+        };
+        let client = start_client(ServiceInner::new(MemTable::new()).auth(config).into()).await?;
+
+        let res = client.hget::<i64>("t1", "k1").await;
+        assert!(matches!(res, Err(KvError::NotFound(t, k)) if t == "t1" && k == "k1"));
+        let res = client.ttl("t1", "k1").await;
+        assert!(matches!(res, Err(KvError::NotFound(..))));
+        let res = client.hget::<i64>("secret", "k1").await;
+        assert!(matches!(res, Err(KvError::Unauthenticated(_))));
+
+        // 类型不对时返回 ConvertError
+        client.hset("t1", "k1", "hello").await?;
+        let res = client.hget::<i64>("t1", "k1").await;
+        assert!(matches!(res, Err(KvError::ConvertError(_, "Integer"))));
+
+        // 原始的 execute 还可以用，状态码由调用者自己处理
+        let res = client.execute(CommandRequest::new_hget("t1", "k2")).await?;
+        assert_eq!(res.status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn typed_api_should_subscribe_and_publish() -> Result<()> {
+        let client = start_client(ServiceInner::new(MemTable::new()).into()).await?;
+
+        let mut stream = client.subscribe("news").await?;
+        client.publish("news", vec!["hello".into()]).await?;
+        let res = stream.next().await.unwrap()?;
+        assert_eq!(res.values, vec!["hello".into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn error_responses_should_convert_back_to_errors() {
+        let errors = vec![
+            KvError::NotFound("t".into(), "k".into()),
+            KvError::WatchFailed("t".into(), "k".into()),
+            KvError::Unauthenticated("invalid token".into()),
+            KvError::PermissionDenied("bob".into(), "write", "my table".into()),
+            KvError::Redirect("127.0.0.1:9527".into()),
+            KvError::InvalidCommand("Hget".into()),
+            KvError::MalformedFrame("bad".into()),
+            KvError::Internal("oops".into()),
+        ];
+        for e in errors {
+            let expected = e.to_string();
+            let res: CommandResponse = e.into();
+            let e = res.into_result().unwrap_err();
+            assert_eq!(e.to_string(), expected);
+            assert!(!matches!(e, KvError::Internal(_)) || expected.starts_with("Internal"));
+        }
+        assert!(CommandResponse::ok().into_result().is_ok());
+
+        // 还原错误用的是结构化的信息，不依赖 message 的措辞
+        let mut res: CommandResponse = KvError::NotFound("t".into(), "k".into()).into();
+        res.message = "no such key".into();
+        assert!(matches!(res.into_result(), Err(KvError::NotFound(t, k)) if t == "t" && k == "k"));
+        let res = CommandResponse {
+            status: 404,
+            message: "no such key".into(),
+            ..Default::default()
+        };
+        assert!(matches!(res.into_result(), Err(KvError::Internal(msg)) if msg == "no such key"));
+    }
+
+    async fn start_client(service: Service) -> Result<KvClient> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(ProstServerStream::new(stream, service.clone()).process());
+            }
+        });
+        Ok(KvClient::new(move || async move {
+            let stream = TcpStream::connect(addr).await?;
+            ProstClientStream::new(stream).await
+        }))
+    }
+}
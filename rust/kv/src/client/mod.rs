@@ -0,0 +1,459 @@
+mod api;
+
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::Mutex,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    CommandRequest, CommandResponse, KvError, KvpairStream, ProstClientStream, StreamResult,
+};
+
+/// 健康检查时查询的 table，服务器有任何回应（哪怕是没有权限）都说明连接是好的
+const HEALTH_CHECK_TABLE: &str = "__health__";
+
+/// 建立一个新的连接，包括 TCP，TLS 和握手
+type ConnectFn = dyn Fn() -> BoxFuture<'static, Result<ProstClientStream, KvError>> + Send + Sync;
+
+/// 连接池的选项
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolOptions {
+    /// 连接池里的连接数
+    pub size: usize,
+    /// 每次调用的超时时间，包括等待连接和重试
+    pub timeout: Duration,
+    /// 只读的命令因为连接出错而失败时，最多重试的次数
+    pub retries: usize,
+    /// 连接失败后等待的时间，每失败一次翻倍
+    pub initial_backoff: Duration,
+    /// 连接失败后最多等待的时间
+    pub max_backoff: Duration,
+}
+
+/// 连接同一个服务器的连接池，连接断开后自动重连
+///
+/// 每个连接都可以同时执行多个命令，所以调用者只是轮流使用池子里的连接
+#[derive(Clone)]
+pub struct KvClient {
+    inner: Arc<KvClientInner>,
+}
+
+struct KvClientInner {
+    connect: Box<ConnectFn>,
+    options: PoolOptions,
+    slots: Vec<Mutex<Slot>>,
+    next: AtomicUsize,
+}
+
+/// 连接池里的一个位置，连接断开之后在这里重连
+struct Slot {
+    conn: Option<ProstClientStream>,
+    // 上一次被使用的时间，健康检查只检查空闲的连接
+    last_used: Instant,
+    // 下一次连接失败后要等待的时间
+    backoff: Duration,
+    // 连接失败后，在这个时间之前不再尝试
+    retry_at: Option<Instant>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            size: 4,
+            timeout: Duration::from_secs(5),
+            retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl KvClient {
+    /// 使用默认的选项创建连接池，connect 用来建立新的连接
+    pub fn new<F, Fut>(connect: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ProstClientStream, KvError>> + Send + 'static,
+    {
+        Self::with_options(connect, PoolOptions::default())
+    }
+
+    /// 创建连接池，连接在第一次使用的时候才建立
+    pub fn with_options<F, Fut>(connect: F, options: PoolOptions) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ProstClientStream, KvError>> + Send + 'static,
+    {
+        let slots = (0..options.size.max(1))
+            .map(|_| Mutex::new(Slot::new(options.initial_backoff)))
+            .collect();
+        let inner = KvClientInner {
+            connect: Box::new(move || connect().boxed()),
+            options,
+            slots,
+            next: AtomicUsize::new(0),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// 连接池的选项
+    pub fn options(&self) -> &PoolOptions {
+        &self.inner.options
+    }
+
+    /// 执行命令，超时时间使用连接池的默认值
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.execute_with_timeout(cmd, self.inner.options.timeout).await
+    }
+
+    /// 执行命令，在 timeout 之内没有完成的话返回 KvError::Timeout
+    ///
+    /// 只读的命令在连接出错时会换一个连接重试，其它命令可能已经执行了，直接返回错误
+    pub async fn execute_with_timeout(
+        &self,
+        cmd: CommandRequest,
+        timeout: Duration,
+    ) -> Result<CommandResponse, KvError> {
+        let call = self.inner.call(cmd, |conn, cmd| async move { conn.execute(cmd).await });
+        time::timeout(timeout, call)
+            .await
+            .map_err(|_| KvError::Timeout(timeout))?
+    }
+
+    /// 执行流式命令（比如 subscribe），超时时间只限制拿到第一个 response 之前
+    pub async fn execute_streaming(&self, cmd: CommandRequest) -> Result<StreamResult, KvError> {
+        let timeout = self.inner.options.timeout;
+        let call = self.inner.call(cmd, |conn, cmd| async move {
+            conn.execute_streaming(cmd).await
+        });
+        time::timeout(timeout, call)
+            .await
+            .map_err(|_| KvError::Timeout(timeout))?
+    }
+
+    /// 执行遍历类的命令（比如 hgetall），超时时间只限制拿到第一个 response 之前
+    pub async fn execute_scan(&self, cmd: CommandRequest) -> Result<KvpairStream, KvError> {
+        let timeout = self.inner.options.timeout;
+        let call = self.inner.call(cmd, |conn, cmd| async move { conn.execute_scan(cmd).await });
+        time::timeout(timeout, call)
+            .await
+            .map_err(|_| KvError::Timeout(timeout))?
+    }
+
+    /// 在后台定期检查空闲了 interval 的连接，没有回应的连接会被关掉，下次使用时重连
+    ///
+    /// 后台任务只持有连接池的弱引用，所有的 KvClient 都被 drop 之后自动结束
+    pub fn start_health_check(&self, interval: Duration) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                let inner = match inner.upgrade() {
+                    Some(inner) => inner,
+                    None => break,
+                };
+                inner.check_idle(interval).await;
+            }
+        })
+    }
+}
+
+impl KvClientInner {
+    /// 在池子里的连接上执行 f，只读的命令遇到连接错误时重试
+    async fn call<T, F, Fut>(&self, mut cmd: CommandRequest, f: F) -> Result<T, KvError>
+    where
+        F: Fn(ProstClientStream, CommandRequest) -> Fut,
+        Fut: Future<Output = Result<T, KvError>>,
+    {
+        let retries = if cmd.is_idempotent() { self.options.retries } else { 0 };
+        let mut attempt = 0;
+        loop {
+            let (index, conn) = self.acquire().await?;
+            // 还可能重试的话要留一份命令
+            let req = if attempt < retries { cmd.clone() } else { std::mem::take(&mut cmd) };
+            match f(conn.clone(), req).await {
+                Ok(v) => return Ok(v),
+                // 请求太大，没有发出去，连接也还能用
+                Err(KvError::FrameError) => return Err(KvError::FrameError),
+                Err(e) => {
+                    self.invalidate(index, &conn).await;
+                    if attempt >= retries {
+                        return Err(e);
+                    }
+                    warn!("Command {} failed on connection error, retry: {:?}", cmd.name(), e);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// 轮流取池子里的连接，连接不存在或者已经断开时重新连接
+    async fn acquire(&self) -> Result<(usize, ProstClientStream), KvError> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        // 同一个位置同时只有一个调用者在重连，其它的调用者等着用它连好的连接
+        let mut slot = self.slots[index].lock().await;
+        slot.last_used = Instant::now();
+        loop {
+            if let Some(conn) = slot.conn.as_ref().filter(|conn| !conn.is_closed()) {
+                return Ok((index, conn.clone()));
+            }
+            slot.conn = None;
+
+            if let Some(at) = slot.retry_at {
+                time::sleep_until(at).await;
+            }
+            match (self.connect)().await {
+                Ok(conn) => {
+                    debug!("Connection {} is established", index);
+                    slot.conn = Some(conn);
+                    slot.retry_at = None;
+                    slot.backoff = self.options.initial_backoff;
+                }
+                // 服务器还没起来或者网络不通，等一会儿再试
+                Err(KvError::IoError(e)) => {
+                    warn!("Failed to connect, retry in {:?}: {:?}", slot.backoff, e);
+                    slot.retry_at = Some(Instant::now() + slot.backoff);
+                    slot.backoff = (slot.backoff * 2).min(self.options.max_backoff);
+                }
+                // TLS 或者握手失败，重试也没有用
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 连接出错之后把它从池子里去掉，如果它还没有被别人换掉的话
+    async fn invalidate(&self, index: usize, conn: &ProstClientStream) {
+        let mut slot = self.slots[index].lock().await;
+        if slot.conn.as_ref().is_some_and(|c| c.is_same(conn)) {
+            slot.conn = None;
+        }
+    }
+
+    /// 检查空闲了 idle 的连接，在超时时间内没有回应的话关掉它
+    async fn check_idle(&self, idle: Duration) {
+        for (index, slot) in self.slots.iter().enumerate() {
+            // 正在被使用（比如正在重连）的位置不用检查
+            let conn = match slot.try_lock() {
+                Ok(slot) if slot.last_used.elapsed() >= idle => slot.conn.clone(),
+                _ => None,
+            };
+            let conn = match conn {
+                Some(conn) => conn,
+                None => continue,
+            };
+
+            let cmd = CommandRequest::new_hexist(HEALTH_CHECK_TABLE, "");
+            match time::timeout(self.options.timeout, conn.execute(cmd)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    warn!("Connection {} failed health check: {:?}", index, e);
+                    self.invalidate(index, &conn).await;
+                }
+                Err(_) => {
+                    warn!("Connection {} didn't answer health check", index);
+                    self.invalidate(index, &conn).await;
+                }
+            }
+        }
+    }
+}
+
+impl Slot {
+    fn new(backoff: Duration) -> Self {
+        Self {
+            conn: None,
+            last_used: Instant::now(),
+            backoff,
+            retry_at: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::{
+        assert_res_ok, network::handshake::server_handshake, utils::serve, HandshakeOptions,
+        MemTable, ServiceInner, Value,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn client_should_reconnect_with_backoff_until_server_is_up() -> Result<()> {
+        let addr = free_addr()?;
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let client = KvClient::new(counting_connect(vec![addr], attempts.clone()));
+
+        // 服务器过一会儿才启动，之前的连接都会被拒绝
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            let listener = TcpListener::bind(addr).await.unwrap();
+            serve(listener, ServiceInner::new(MemTable::new()).into()).await;
+        });
+
+        let res = client.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        assert!(attempts.load(Ordering::SeqCst) > 1);
+
+        // 连好之后不再建立新的连接
+        let attempts_before = attempts.load(Ordering::SeqCst);
+        let client = client.clone();
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&res, &["v1".into()], &[]);
+        assert!(attempts.load(Ordering::SeqCst) <= attempts_before + 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_retry_idempotent_commands_on_broken_connection() -> Result<()> {
+        let broken = start_broken_server().await?;
+        let good = start_server().await?;
+
+        // 第一个连接握手之后就被服务器关掉，之后的连接是好的
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let options = PoolOptions {
+            size: 1,
+            ..Default::default()
+        };
+        let connect = counting_connect(vec![broken, good], attempts.clone());
+        let client = KvClient::with_options(connect, options);
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_eq!(res.status, 404);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // 写命令不重试，但是下一次调用会重连
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let options = PoolOptions {
+            size: 1,
+            ..Default::default()
+        };
+        let connect = counting_connect(vec![broken, good], attempts.clone());
+        let client = KvClient::with_options(connect, options);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert!(client.execute(cmd.clone()).await.is_err());
+        let res = client.execute(cmd).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_time_out_when_server_does_not_answer() -> Result<()> {
+        let addr = start_hung_server().await?;
+        let client = KvClient::new(counting_connect(vec![addr], Default::default()));
+
+        let timeout = Duration::from_millis(100);
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute_with_timeout(cmd, timeout).await;
+        assert!(matches!(res, Err(KvError::Timeout(t)) if t == timeout));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_check_should_drop_connections_without_answer() -> Result<()> {
+        let hung = start_hung_server().await?;
+        let good = start_server().await?;
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let options = PoolOptions {
+            size: 1,
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let connect = counting_connect(vec![hung, good], attempts.clone());
+        let client = KvClient::with_options(connect, options);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert!(matches!(client.execute(cmd.clone()).await, Err(KvError::Timeout(_))));
+
+        // 健康检查发现连接没有回应，把它关掉，下一次调用会重连到正常的服务器
+        let checker = client.start_health_check(Duration::from_millis(50));
+        time::sleep(Duration::from_millis(300)).await;
+        assert!(client.inner.slots[0].lock().await.conn.is_none());
+
+        let res = client.execute(cmd).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // 正常的连接不会被健康检查关掉
+        time::sleep(Duration::from_millis(300)).await;
+        assert!(client.inner.slots[0].lock().await.conn.is_some());
+
+        // 所有的 client 都 drop 之后后台任务自动结束
+        drop(client);
+        time::timeout(Duration::from_secs(1), checker).await??;
+        Ok(())
+    }
+
+    /// 依次连接 addrs 里的地址，最后一个地址之后一直连接最后一个
+    fn counting_connect(
+        addrs: Vec<SocketAddr>,
+        attempts: Arc<AtomicUsize>,
+    ) -> impl Fn() -> BoxFuture<'static, Result<ProstClientStream, KvError>> {
+        move || {
+            let n = attempts.fetch_add(1, Ordering::SeqCst);
+            let addr = addrs[n.min(addrs.len() - 1)];
+            async move {
+                let stream = TcpStream::connect(addr).await?;
+                ProstClientStream::new(stream).await
+            }
+            .boxed()
+        }
+    }
+
+    fn free_addr() -> Result<SocketAddr> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        Ok(listener.local_addr()?)
+    }
+
+    async fn start_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(listener, ServiceInner::new(MemTable::new()).into()));
+        Ok(addr)
+    }
+
+    /// 握手之后马上关闭连接的服务器
+    async fn start_broken_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                server_handshake(&mut stream, &HandshakeOptions::default()).await.unwrap();
+            }
+        });
+        Ok(addr)
+    }
+
+    /// 握手之后不再回应的服务器
+    async fn start_hung_server() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                server_handshake(&mut stream, &HandshakeOptions::default()).await.unwrap();
+                streams.push(stream);
+            }
+        });
+        Ok(addr)
+    }
+}
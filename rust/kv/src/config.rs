@@ -0,0 +1,350 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{Compression, KvError, DEFAULT_MAX_FRAME, DEFAULT_MAX_INFLIGHT};
+
+/// 服务器配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ServerConfig {
+    pub general: GeneralConfig,
+    pub storage: StorageConfig,
+    pub tls: ServerTlsConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// 配置了的话，在 metrics.addr 上提供 Prometheus 格式的统计数据
+    pub metrics: Option<MetricsConfig>,
+    /// 主从复制配置，不配置的话服务器不参与复制
+    pub replication: Option<ReplicationConfig>,
+}
+
+/// 客户端配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientConfig {
+    pub general: GeneralConfig,
+    pub tls: ClientTlsConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct GeneralConfig {
+    /// 服务器监听（或者客户端连接）的地址
+    pub addr: String,
+    /// 允许使用的压缩算法，按优先级排列，连接建立时和对端协商
+    #[serde(default = "Compression::supported")]
+    pub compressions: Vec<Compression>,
+    /// 本地能接收的最大的 frame，握手时告诉对端
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: u32,
+    /// 服务器的每个连接上，已经读进来、还没有返回结果的请求最多占用的字节数，客户端不使用
+    #[serde(default = "default_max_inflight")]
+    pub max_inflight: usize,
+    /// 服务器退出时最多等待已有的连接多少秒，超时的连接会被直接关掉，客户端不使用
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+}
+
+impl GeneralConfig {
+    /// 使用缺省的压缩算法
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            compressions: Compression::supported(),
+            max_frame_size: default_max_frame_size(),
+            max_inflight: default_max_inflight(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+        }
+    }
+}
+
+fn default_max_frame_size() -> u32 {
+    DEFAULT_MAX_FRAME as u32
+}
+
+fn default_max_inflight() -> usize {
+    DEFAULT_MAX_INFLIGHT
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    10
+}
+
+/// 存储后端，SledDb 需要提供数据库的路径，DurableMemTable 需要提供存放日志和 snapshot 的目录
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", content = "args")]
+pub enum StorageConfig {
+    MemTable,
+    SledDb(String),
+    DurableMemTable(String),
+}
+
+/// 服务器 TLS 配置，这里存放的都是文件路径
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ServerTlsConfig {
+    pub cert: String,
+    pub key: String,
+    /// 如果提供了 CA 证书，则要求客户端证书
+    pub ca: Option<String>,
+}
+
+/// 客户端 TLS 配置，这里存放的都是文件路径
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientTlsConfig {
+    pub domain: String,
+    /// 客户端证书和私钥
+    pub identity: Option<(String, String)>,
+    pub ca: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LogConfig {
+    /// 日志级别，支持 tracing EnvFilter 的语法，比如 "info" 或者 "kv=debug"
+    pub level: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MetricsConfig {
+    /// HTTP 服务监听的地址
+    pub addr: String,
+}
+
+/// 服务器在主从复制里的角色
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum ReplicationConfig {
+    /// 把成功执行的写命令同步给连上来的 replica
+    Primary,
+    /// 从 primary 同步数据，拒绝写命令
+    Replica(ReplicaConfig),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ReplicaConfig {
+    /// 连接 primary 的配置，写命令会被重定向到 primary.general.addr
+    pub primary: ClientConfig,
+    /// primary 开启了认证的话，用这个 token 登录
+    pub token: Option<String>,
+}
+
+/// 认证和授权配置，不配置的话所有的 table 都可以匿名访问
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AuthConfig {
+    /// token 到身份的映射，客户端可以用 Auth 命令登录
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+    /// 每个 table 的访问权限，"*" 对应没有单独配置的 table
+    #[serde(default)]
+    pub tables: HashMap<String, TableAcl>,
+}
+
+/// 一个 table 允许哪些身份读写，"*" 表示所有通过认证的身份
+///
+/// 能写的身份也能读
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct TableAcl {
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// 从 toml 文件加载服务器配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        load_toml(path.as_ref())
+    }
+}
+
+impl ClientConfig {
+    /// 从 toml 文件加载客户端配置
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        load_toml(path.as_ref())
+    }
+}
+
+fn load_toml<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, KvError> {
+    let name = path.display().to_string();
+    let content =
+        fs::read_to_string(path).map_err(|e| KvError::ConfigError(name.clone(), e.to_string()))?;
+    toml::from_str(&content).map_err(|e| KvError::ConfigError(name, e.to_string()))
+}
+
+/// 读取配置中引用的证书/私钥文件
+pub(crate) fn read_file(path: &str) -> Result<String, KvError> {
+    fs::read_to_string(path).map_err(|e| KvError::ConfigError(path.into(), e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn server_config_should_be_loaded() {
+        let result = ServerConfig::load("fixtures/server.conf");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn client_config_should_be_loaded() {
+        let result = ClientConfig::load("fixtures/client.conf");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn server_config_without_log_should_use_default_level() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [storage]
+            type = "SledDb"
+            args = "/tmp/kvserver"
+
+            [tls]
+            cert = "fixtures/server.cert"
+            key = "fixtures/server.key"
+            "#
+        )
+        .unwrap();
+
+        let config = ServerConfig::load(file.path()).unwrap();
+        assert_eq!(config.storage, StorageConfig::SledDb("/tmp/kvserver".into()));
+        assert_eq!(config.tls.ca, None);
+        assert_eq!(config.log, LogConfig::default());
+        assert_eq!(config.general.compressions, Compression::supported());
+        assert_eq!(config.general.max_frame_size, DEFAULT_MAX_FRAME as u32);
+        assert_eq!(config.general.max_inflight, DEFAULT_MAX_INFLIGHT);
+        assert_eq!(config.general.shutdown_timeout_secs, 10);
+        assert_eq!(config.auth, AuthConfig::default());
+        assert_eq!(config.metrics, None);
+        assert_eq!(config.replication, None);
+    }
+
+    #[test]
+    fn server_config_should_load_auth() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [storage]
+            type = "MemTable"
+
+            [tls]
+            cert = "fixtures/server.cert"
+            key = "fixtures/server.key"
+
+            [metrics]
+            addr = "127.0.0.1:9528"
+
+            [auth.tokens]
+            secret = "alice"
+
+            [auth.tables.users]
+            read = ["*"]
+            write = ["alice"]
+            "#
+        )
+        .unwrap();
+
+        let config = ServerConfig::load(file.path()).unwrap();
+        assert_eq!(config.auth.tokens["secret"], "alice");
+        let acl = &config.auth.tables["users"];
+        assert_eq!(acl.read, vec!["*"]);
+        assert_eq!(acl.write, vec!["alice"]);
+        assert_eq!(config.metrics.unwrap().addr, "127.0.0.1:9528");
+    }
+
+    #[test]
+    fn server_config_should_load_replication() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+            [general]
+            addr = "127.0.0.1:9528"
+
+            [storage]
+            type = "MemTable"
+
+            [tls]
+            cert = "fixtures/server.cert"
+            key = "fixtures/server.key"
+
+            [replication]
+            role = "replica"
+            token = "secret"
+
+            [replication.primary.general]
+            addr = "127.0.0.1:9527"
+
+            [replication.primary.tls]
+            domain = "kvserver.acme.inc"
+            ca = "fixtures/ca.cert"
+            "#
+        )
+        .unwrap();
+
+        let config = ServerConfig::load(file.path()).unwrap();
+        let replica = match config.replication {
+            Some(ReplicationConfig::Replica(replica)) => replica,
+            v => panic!("expect replica, got {:?}", v),
+        };
+        assert_eq!(replica.primary.general.addr, "127.0.0.1:9527");
+        assert_eq!(replica.primary.tls.ca.as_deref(), Some("fixtures/ca.cert"));
+        assert_eq!(replica.token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn client_config_should_load_compressions() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+            compressions = ["lz4", "none"]
+            max_frame_size = 4096
+
+            [tls]
+            domain = "kvserver.acme.inc"
+            "#
+        )
+        .unwrap();
+
+        let config = ClientConfig::load(file.path()).unwrap();
+        let compressions = vec![Compression::Lz4, Compression::None];
+        assert_eq!(config.general.compressions, compressions);
+        assert_eq!(config.general.max_frame_size, 4096);
+    }
+
+    #[test]
+    fn bad_config_should_return_config_error() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "[general]\naddr = 9527\n").unwrap();
+
+        let result = ServerConfig::load(file.path());
+        assert!(matches!(result, Err(KvError::ConfigError(_, _))));
+    }
+
+    #[test]
+    fn missing_config_should_return_config_error() {
+        let result = ClientConfig::load("fixtures/not_exist.conf");
+        assert!(matches!(result, Err(KvError::ConfigError(_, _))));
+    }
+}
@@ -1,21 +1,38 @@
 use crate::Value;
+use std::{convert::Infallible, time::Duration};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum KvError {
     #[error("Not found for table: {0}, key: {1}")]
     NotFound(String, String),
+    #[error("Transaction aborted, watched key is changed. table: {0}, key: {1}")]
+    WatchFailed(String, String),
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+    #[error("Permission denied: {0} cannot {1} table {2}")]
+    PermissionDenied(String, &'static str, String),
+    #[error("Read only replica, send writes to primary: {0}")]
+    Redirect(String),
+    #[error("Handshake failed: {0}")]
+    HandshakeError(String),
     #[error("Frame is larger than max size")]
     FrameError,
+    #[error("Malformed frame: {0}")]
+    MalformedFrame(String),
 
     #[error("Command is invalid: `{0}`")]
     InvalidCommand(String),
-    #[error("Cannot convert value {:0} to {1}")]
+    #[error("Cannot convert value {0:?} to {1}")]
     ConvertError(Value, &'static str),
-    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
+    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
-    #[error("Certificate parse error: error to load {0} {0}")]
+    #[error("Failed to serialize or deserialize value as {0}: {1}")]
+    SerdeError(&'static str, String),
+    #[error("Certificate parse error: error to load {0} {1}")]
     CertifcateParseError(&'static str, &'static str),
+    #[error("Failed to load config {0}: {1}")]
+    ConfigError(String, String),
 
     #[error("Failed to encode protobuf message")]
     EncodeError(#[from] prost::EncodeError),
@@ -28,6 +45,15 @@ pub enum KvError {
     #[error("TLS error")]
     TlsError(#[from] tokio_rustls::rustls::TLSError),
 
+    #[error("Request timed out after {0:?}")]
+    Timeout(Duration),
+
     #[error("Internal error: {0}")]
     Internal(String),
-}
\ No newline at end of file
+}
+/// Value 转换成 Value 不会出错，有了它 TryFrom<Value> 的错误都可以用 ? 转换成 KvError
+impl From<Infallible> for KvError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
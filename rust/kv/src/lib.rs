@@ -1,11 +1,245 @@
+mod client;
+mod config;
 mod error;
+mod metrics;
 mod pb;
 mod storage;
 mod service;
 mod network;
 
+pub use client::*;
+pub use config::*;
 pub use error::KvError;
+pub use metrics::*;
 pub use pb::abi::*;
+pub use pb::Serde;
 pub use storage::*;
 pub use service::*;
 pub use network::*;
+
+use futures::future::BoxFuture;
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+};
+use tracing::{info, warn};
+
+/// 后台清理过期 key 的间隔
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 通过配置创建 KV 服务器，一直运行下去
+pub async fn start_server_with_config(config: &ServerConfig) -> Result<(), KvError> {
+    start_server_with_shutdown(config, futures::future::pending()).await
+}
+
+/// 通过配置创建 KV 服务器，shutdown 完成时优雅地退出
+///
+/// 退出时不再接受新的连接，已有的连接执行完手上的命令、把结果发出去之后关闭，
+/// 最多等待配置的 shutdown_timeout_secs，超时的连接直接关掉，最后把存储里的数据刷到磁盘上
+pub async fn start_server_with_shutdown(
+    config: &ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), KvError> {
+    let cert = config::read_file(&config.tls.cert)?;
+    let key = config::read_file(&config.tls.key)?;
+    let ca = config.tls.ca.as_deref().map(config::read_file).transpose()?;
+    let acceptor = TlsServerAcceptor::new(&cert, &key, ca.as_deref())?;
+
+    match &config.storage {
+        StorageConfig::MemTable => {
+            start_tls_server(config, MemTable::new(), acceptor, shutdown).await
+        }
+        StorageConfig::SledDb(path) => {
+            // 告诉用户是哪个路径出了问题
+            let store = SledDb::open(path).map_err(|e| match e {
+                KvError::SledError(e) => KvError::ConfigError(path.clone(), e.to_string()),
+                e => e,
+            })?;
+            start_tls_server(config, store, acceptor, shutdown).await
+        }
+        StorageConfig::DurableMemTable(path) => {
+            let store = DurableMemTable::open(path)?;
+            start_tls_server(config, store, acceptor, shutdown).await
+        }
+    }
+}
+
+/// 通过配置创建 KV 客户端
+pub async fn start_client_with_config(
+    config: &ClientConfig,
+) -> Result<ProstClientStream, KvError> {
+    let connector = tls_connector(&config.tls)?;
+    let stream = TcpStream::connect(&config.general.addr).await?;
+    let stream = connector.connect(stream).await?;
+    ProstClientStream::with_options(stream, handshake_options(&config.general)).await
+}
+
+/// 通过配置创建带连接池的 KV 客户端，连接断开后会自动重连
+pub fn kv_client_with_config(
+    config: &ClientConfig,
+    options: PoolOptions,
+) -> Result<KvClient, KvError> {
+    Ok(KvClient::with_options(client_connector(config)?, options))
+}
+
+async fn start_tls_server<Store>(
+    config: &ServerConfig,
+    store: Store,
+    acceptor: TlsServerAcceptor,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let metrics = Metrics::new();
+    let mut inner = ServiceInner::new(store).auth(config.auth.clone());
+    let mut replica = None;
+    match &config.replication {
+        Some(ReplicationConfig::Primary) => inner = inner.primary(),
+        Some(ReplicationConfig::Replica(replica_config)) => {
+            inner = inner.replica_of(&replica_config.primary.general.addr);
+            replica = Some(primary_connector(replica_config)?);
+        }
+        None => {}
+    }
+    if let Some(metrics_config) = &config.metrics {
+        let listener = TcpListener::bind(&metrics_config.addr).await?;
+        tokio::spawn(start_metrics_server(listener, Arc::clone(&metrics)));
+        inner = metrics.register(inner);
+    }
+    let service: Service<Store> = inner.into();
+    let sweeper = service.start_expiry_sweeper(EXPIRY_SWEEP_INTERVAL);
+    let replica = replica.map(|connect| service.start_replica(connect));
+    let addr = &config.general.addr;
+    let listener = TcpListener::bind(addr).await?;
+    info!("Start listening on {}", addr);
+
+    // 通知所有的连接服务器要退出了
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        let tls = acceptor.clone();
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            // 顺便回收已经结束的连接
+            Some(_) = connections.join_next() => continue,
+            _ = &mut shutdown => break,
+        };
+        info!("Client {:?} connected", addr);
+        let svc = service.clone();
+        let options = handshake_options(&config.general);
+        let max_inflight = config.general.max_inflight;
+        let metrics = config.metrics.as_ref().map(|_| Arc::clone(&metrics));
+        let stop_rx = stop_rx.clone();
+        connections.spawn(async move {
+            // 连上来之后什么都不发的客户端，不能一直占着连接
+            let accept = tokio::time::timeout(DEFAULT_HANDSHAKE_TIMEOUT, tls.accept(stream));
+            let stream = match accept.await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("TLS handshake with {} failed: {:?}", addr, e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("TLS handshake with {} timed out", addr);
+                    return;
+                }
+            };
+            let identity = client_identity(&stream);
+            let mut server = ProstServerStream::new(stream, svc)
+                .with_handshake_options(options)
+                .with_max_inflight(max_inflight)
+                .with_identity(identity)
+                .with_shutdown(stop_rx)
+                .with_peer_addr(addr);
+            if let Some(metrics) = metrics {
+                server = server.with_metrics(metrics);
+            }
+            // 出错的时候 process 已经记录了日志
+            let _ = server.process().await;
+        });
+    }
+
+    info!("Shutting down, waiting for connections to finish");
+    drop(listener);
+    let _ = stop_tx.send(true);
+    let timeout = Duration::from_secs(config.general.shutdown_timeout_secs);
+    let finished = async { while connections.join_next().await.is_some() {} };
+    if tokio::time::timeout(timeout, finished).await.is_err() {
+        // 关掉剩下的连接，它们不再使用 store 之后才能 flush
+        warn!("Connections didn't finish in {:?}, close them", timeout);
+        connections.shutdown().await;
+    }
+
+    sweeper.abort();
+    let _ = sweeper.await;
+    if let Some(replica) = replica {
+        replica.abort();
+        let _ = replica.await;
+    }
+    service.flush().await?;
+    info!("Server stopped");
+    Ok(())
+}
+
+/// 按照配置建立连接的函数，连接断开之后用它重连
+fn client_connector(
+    config: &ClientConfig,
+) -> Result<impl Fn() -> BoxFuture<'static, Result<ProstClientStream, KvError>>, KvError> {
+    let connector = tls_connector(&config.tls)?;
+    let addr = config.general.addr.clone();
+    let handshake = handshake_options(&config.general);
+    Ok(move || {
+        let (connector, addr, handshake) = (connector.clone(), addr.clone(), handshake.clone());
+        let fut = async move {
+            let stream = TcpStream::connect(&addr).await?;
+            let stream = connector.connect(stream).await?;
+            ProstClientStream::with_options(stream, handshake).await
+        };
+        Box::pin(fut) as BoxFuture<'static, _>
+    })
+}
+
+/// replica 连接 primary 的函数，配置了 token 的话连上之后先登录
+fn primary_connector(
+    config: &ReplicaConfig,
+) -> Result<impl Fn() -> BoxFuture<'static, Result<ProstClientStream, KvError>>, KvError> {
+    let connect = client_connector(&config.primary)?;
+    let token = config.token.clone();
+    Ok(move || {
+        let (connect, token) = (connect(), token.clone());
+        let fut = async move {
+            let client = connect.await?;
+            if let Some(token) = token {
+                client.execute(CommandRequest::new_auth(token)).await?.into_result()?;
+            }
+            Ok(client)
+        };
+        Box::pin(fut) as BoxFuture<'static, _>
+    })
+}
+
+fn tls_connector(tls: &ClientTlsConfig) -> Result<TlsClientConnector, KvError> {
+    let identity = match &tls.identity {
+        Some((cert, key)) => Some((config::read_file(cert)?, config::read_file(key)?)),
+        None => None,
+    };
+    let ca = tls.ca.as_deref().map(config::read_file).transpose()?;
+    TlsClientConnector::new(
+        &tls.domain,
+        identity.as_ref().map(|(cert, key)| (cert.as_str(), key.as_str())),
+        ca.as_deref(),
+    )
+}
+
+/// 配置里和握手相关的选项
+fn handshake_options(config: &GeneralConfig) -> HandshakeOptions {
+    HandshakeOptions {
+        compressions: config.compressions.clone(),
+        max_frame_size: config.max_frame_size,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,265 @@
+mod server;
+
+pub use server::start_metrics_server;
+
+use dashmap::DashMap;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{CommandResponse, ServiceInner, Storage};
+
+/// 处理时间的直方图的桶（秒）
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// KV server 的统计数据，以 Prometheus 的文本格式输出
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // 每种命令的请求数和处理时间
+    requests: DashMap<&'static str, Histogram>,
+    // 每种状态码的 response 数
+    statuses: DashMap<u32, AtomicU64>,
+    // 当前的连接数
+    connections: AtomicI64,
+    // 网络上收发的字节数（压缩后）
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    // 收发的 frame 解压缩后的字节数
+    payload_in: AtomicU64,
+    payload_out: AtomicU64,
+}
+
+/// 一个命令的处理时间的分布
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    // 总的处理时间（微秒）
+    sum_us: AtomicU64,
+}
+
+/// 连接关闭（drop）的时候减少连接数
+pub struct ConnectionGuard(Arc<Metrics>);
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// 给 service 注册收集统计数据的 hook
+    pub fn register<Store>(self: &Arc<Self>, inner: ServiceInner<Store>) -> ServiceInner<Store>
+    where
+        Store: Storage,
+    {
+        let metrics = Arc::clone(self);
+        inner.fn_completed(move |name, res: &CommandResponse, elapsed| {
+            metrics.record_request(name, res.status, elapsed)
+        })
+    }
+
+    /// 记录一个请求的命令，状态码和处理时间
+    pub fn record_request(&self, name: &'static str, status: u32, elapsed: Duration) {
+        self.requests.entry(name).or_default().observe(elapsed);
+        self.statuses
+            .entry(status)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 记录收到的 frame，wire 是 frame 在网络上的大小，payload 是解压缩后的大小
+    pub fn record_frame_in(&self, wire: usize, payload: usize) {
+        self.bytes_in.fetch_add(wire as u64, Ordering::Relaxed);
+        self.payload_in.fetch_add(payload as u64, Ordering::Relaxed);
+    }
+
+    /// 记录发出的 frame，wire 是 frame 在网络上的大小，payload 是压缩前的大小
+    pub fn record_frame_out(&self, wire: usize, payload: usize) {
+        self.bytes_out.fetch_add(wire as u64, Ordering::Relaxed);
+        self.payload_out.fetch_add(payload as u64, Ordering::Relaxed);
+    }
+
+    /// 记录一个新的连接，返回的 guard drop 的时候连接数减一
+    pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(Arc::clone(self))
+    }
+
+    /// 以 Prometheus 的文本格式输出所有的统计数据
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let mut requests: Vec<_> = self.requests.iter().collect();
+        requests.sort_by_key(|entry| *entry.key());
+        let help = "Total number of requests by command.";
+        header(&mut out, "kv_requests_total", "counter", help);
+        for entry in &requests {
+            let count = entry.count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "kv_requests_total{{command=\"{}\"}} {}", entry.key(), count);
+        }
+
+        let name = "kv_request_duration_seconds";
+        header(&mut out, name, "histogram", "Time to the first response by command.");
+        for entry in &requests {
+            entry.render(&mut out, name, entry.key());
+        }
+
+        let mut statuses: Vec<_> = self
+            .statuses
+            .iter()
+            .map(|entry| (*entry.key(), entry.load(Ordering::Relaxed)))
+            .collect();
+        statuses.sort_unstable();
+        let help = "Total number of responses by status.";
+        header(&mut out, "kv_responses_total", "counter", help);
+        for (status, count) in statuses {
+            let _ = writeln!(out, "kv_responses_total{{status=\"{}\"}} {}", status, count);
+        }
+
+        let connections = self.connections.load(Ordering::Relaxed);
+        header(&mut out, "kv_connections_active", "gauge", "Number of active connections.");
+        let _ = writeln!(out, "kv_connections_active {}", connections);
+
+        let bytes = [
+            ("in", &self.bytes_in, &self.payload_in),
+            ("out", &self.bytes_out, &self.payload_out),
+        ];
+        let help = "Total bytes of frames on the wire.";
+        header(&mut out, "kv_bytes_total", "counter", help);
+        for (direction, wire, _) in &bytes {
+            let wire = wire.load(Ordering::Relaxed);
+            let _ = writeln!(out, "kv_bytes_total{{direction=\"{}\"}} {}", direction, wire);
+        }
+        let help = "Total bytes of frames before compression.";
+        header(&mut out, "kv_payload_bytes_total", "counter", help);
+        for (direction, _, payload) in &bytes {
+            let payload = payload.load(Ordering::Relaxed);
+            let line = format!("kv_payload_bytes_total{{direction=\"{}\"}}", direction);
+            let _ = writeln!(out, "{} {}", line, payload);
+        }
+        let help = "Payload bytes divided by bytes on the wire.";
+        header(&mut out, "kv_compression_ratio", "gauge", help);
+        for (direction, wire, payload) in &bytes {
+            let wire = wire.load(Ordering::Relaxed);
+            let payload = payload.load(Ordering::Relaxed);
+            // 还没有数据的时候算作没有压缩
+            let ratio = if wire == 0 { 1.0 } else { payload as f64 / wire as f64 };
+            let _ = writeln!(out, "kv_compression_ratio{{direction=\"{}\"}} {}", direction, ratio);
+        }
+
+        out
+    }
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        // 桶是累计的，这里只记录落在哪个桶，输出时再累加
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, command: &str) {
+        let mut total = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            total += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                name, command, le, total
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{command=\"{}\",le=\"+Inf\"}} {}", name, command, count);
+        let _ = writeln!(out, "{}_sum{{command=\"{}\"}} {}", name, command, sum);
+        let _ = writeln!(out, "{}_count{{command=\"{}\"}} {}", name, command, count);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, Service};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn metrics_should_record_requests_from_service_hooks() {
+        let metrics = Metrics::new();
+        let service: Service = metrics.register(ServiceInner::new(MemTable::new())).into();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute(cmd).next().await.unwrap();
+        service.execute(CommandRequest::new_hget("t1", "k1")).next().await.unwrap();
+        service.execute(CommandRequest::new_hget("t1", "k2")).next().await.unwrap();
+        // 流式的命令只统计一次
+        let _: Vec<_> = service.execute(CommandRequest::new_hgetall("t1")).collect().await;
+
+        let out = metrics.render();
+        assert!(out.contains("kv_requests_total{command=\"hget\"} 2\n"));
+        assert!(out.contains("kv_requests_total{command=\"hset\"} 1\n"));
+        assert!(out.contains("kv_requests_total{command=\"hgetall\"} 1\n"));
+        let bucket = "kv_request_duration_seconds_bucket{command=\"hget\",le=\"+Inf\"}";
+        assert!(out.contains(&format!("{} 2\n", bucket)));
+        assert!(out.contains("kv_request_duration_seconds_count{command=\"hget\"} 2\n"));
+        assert!(out.contains("kv_responses_total{status=\"200\"} 3\n"));
+        assert!(out.contains("kv_responses_total{status=\"404\"} 1\n"));
+    }
+
+    #[test]
+    fn histogram_buckets_should_be_cumulative() {
+        let metrics = Metrics::default();
+        metrics.record_request("hget", 200, Duration::from_micros(50));
+        metrics.record_request("hget", 200, Duration::from_millis(3));
+        metrics.record_request("hget", 200, Duration::from_secs(2));
+
+        let out = metrics.render();
+        let bucket = |le: &str| {
+            format!("kv_request_duration_seconds_bucket{{command=\"hget\",le=\"{}\"}}", le)
+        };
+        assert!(out.contains(&format!("{} 1\n", bucket("0.0001"))));
+        assert!(out.contains(&format!("{} 1\n", bucket("0.0025"))));
+        assert!(out.contains(&format!("{} 2\n", bucket("0.005"))));
+        assert!(out.contains(&format!("{} 2\n", bucket("1"))));
+        assert!(out.contains(&format!("{} 3\n", bucket("+Inf"))));
+        assert!(out.contains("kv_request_duration_seconds_sum{command=\"hget\"} 2.00305\n"));
+    }
+
+    #[test]
+    fn connections_and_bytes_should_be_recorded() {
+        let metrics = Metrics::new();
+        let guard1 = metrics.connection_opened();
+        let _guard2 = metrics.connection_opened();
+        drop(guard1);
+        metrics.record_frame_in(100, 100);
+        metrics.record_frame_out(100, 400);
+
+        let out = metrics.render();
+        assert!(out.contains("kv_connections_active 1\n"));
+        assert!(out.contains("kv_bytes_total{direction=\"out\"} 100\n"));
+        assert!(out.contains("kv_payload_bytes_total{direction=\"out\"} 400\n"));
+        assert!(out.contains("kv_compression_ratio{direction=\"in\"} 1\n"));
+        assert!(out.contains("kv_compression_ratio{direction=\"out\"} 4\n"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+use crate::{KvError, Metrics};
+
+/// 请求头最多读这么多字节，我们只关心第一行
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// Prometheus 文本格式的 content type
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// 启动一个简单的 HTTP 服务，在 GET /metrics 上输出统计数据
+pub async fn start_metrics_server(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+) -> Result<(), KvError> {
+    info!("Metrics are served on http://{}/metrics", listener.local_addr()?);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &metrics).await {
+                warn!("Failed to serve metrics to {:?}: {:?}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, metrics: &Metrics) -> Result<(), KvError> {
+    let mut buf = Vec::with_capacity(1024);
+    // 读到请求头结束为止，请求不会有 body
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => respond(&mut stream, "200 OK", &metrics.render()).await,
+        (Some("GET"), _) => respond(&mut stream, "404 Not Found", "").await,
+        _ => respond(&mut stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), KvError> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        CONTENT_TYPE,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn metrics_server_should_serve_text_format() -> anyhow::Result<()> {
+        let metrics = Metrics::new();
+        metrics.record_request("hget", 200, Duration::from_millis(1));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(start_metrics_server(listener, metrics));
+
+        let res = get(addr, "/metrics").await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(res.contains("kv_requests_total{command=\"hget\"} 1\n"));
+
+        let res = get(addr, "/other").await?;
+        assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        Ok(())
+    }
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> anyhow::Result<String> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        Ok(res)
+    }
+}
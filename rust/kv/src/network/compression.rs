@@ -0,0 +1,154 @@
+use bytes::{BufMut, BytesMut};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use std::io::{Read, Write};
+
+use crate::{Compression, KvError};
+
+/// 压缩算法占用 frame 长度的最高 2 bit
+pub(crate) const COMPRESSION_SHIFT: usize = 30;
+/// 压缩算法在 header 里的掩码
+pub(crate) const COMPRESSION_MASK: usize = 0b11 << COMPRESSION_SHIFT;
+
+impl Compression {
+    /// 本地支持的所有压缩算法，按优先级排列
+    pub fn supported() -> Vec<Compression> {
+        vec![
+            Compression::Zstd,
+            Compression::Lz4,
+            Compression::Gzip,
+            Compression::None,
+        ]
+    }
+
+    /// 从对端给出的（按优先级排列的）压缩算法中，选出第一个本地也允许的
+    ///
+    /// 不压缩总是可用的，所以协商不会失败
+    pub fn negotiate(remote: &[Compression], local: &[Compression]) -> Compression {
+        remote
+            .iter()
+            .find(|c| local.contains(c))
+            .copied()
+            .unwrap_or(Compression::None)
+    }
+
+    /// 把 data 压缩后追加到 buf 里
+    pub fn compress(self, data: &[u8], buf: &mut BytesMut) -> Result<(), KvError> {
+        match self {
+            Compression::None => buf.extend_from_slice(data),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(buf.writer(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?;
+            }
+            Compression::Zstd => zstd::stream::copy_encode(data, buf.writer(), 0)?,
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(buf.writer());
+                encoder.write_all(data)?;
+                encoder
+                    .finish()
+                    .map_err(|e| KvError::Internal(format!("lz4 error: {}", e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 解压缩 data，解压后超过 max_size 的数据返回 FrameError
+    ///
+    /// 最多只读出 max_size + 1 个字节，很小的压缩数据也不能让我们分配大量的内存
+    pub fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>, KvError> {
+        let mut buf = Vec::with_capacity(data.len().saturating_mul(2).min(max_size));
+        let limit = max_size as u64 + 1;
+        match self {
+            Compression::None => buf.extend_from_slice(data),
+            Compression::Gzip => {
+                GzDecoder::new(data).take(limit).read_to_end(&mut buf)?;
+            }
+            Compression::Zstd => {
+                zstd::stream::read::Decoder::new(data)?
+                    .take(limit)
+                    .read_to_end(&mut buf)?;
+            }
+            Compression::Lz4 => {
+                lz4_flex::frame::FrameDecoder::new(data)
+                    .take(limit)
+                    .read_to_end(&mut buf)?;
+            }
+        }
+        if buf.len() > max_size {
+            return Err(KvError::FrameError);
+        }
+        Ok(buf)
+    }
+
+    /// 压缩算法在 frame header 里的标记
+    ///
+    /// gzip 用最高位，和以前只有一个 COMPRESSION_BIT 的格式兼容
+    pub(crate) fn header_bits(self) -> usize {
+        let bits = match self {
+            Compression::None => 0b00,
+            Compression::Gzip => 0b10,
+            Compression::Zstd => 0b01,
+            Compression::Lz4 => 0b11,
+        };
+        bits << COMPRESSION_SHIFT
+    }
+
+    /// 从 frame header 里取出压缩算法
+    pub(crate) fn from_header(header: usize) -> Self {
+        match (header & COMPRESSION_MASK) >> COMPRESSION_SHIFT {
+            0b10 => Compression::Gzip,
+            0b01 => Compression::Zstd,
+            0b11 => Compression::Lz4,
+            _ => Compression::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_decompress_should_work() {
+        let data = b"hello world! ".repeat(100);
+        for c in Compression::supported() {
+            let mut buf = BytesMut::new();
+            c.compress(&data, &mut buf).unwrap();
+            if c != Compression::None {
+                assert!(buf.len() < data.len());
+            }
+            assert_eq!(c.decompress(&buf, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn decompress_should_reject_data_larger_than_max_size() {
+        // 16M 个 0 压缩之后只有很小的一点
+        let data = vec![0u8; 16 * 1024 * 1024];
+        for c in Compression::supported() {
+            let mut buf = BytesMut::new();
+            c.compress(&data, &mut buf).unwrap();
+            let result = c.decompress(&buf, 1024);
+            assert!(matches!(result, Err(KvError::FrameError)), "{:?}", c);
+        }
+    }
+
+    #[test]
+    fn header_bits_should_round_trip() {
+        for c in Compression::supported() {
+            let header = c.header_bits() | 1234;
+            assert_eq!(Compression::from_header(header), c);
+            assert_eq!(header & !COMPRESSION_MASK, 1234);
+        }
+    }
+
+    #[test]
+    fn negotiate_should_pick_first_supported() {
+        let local = vec![Compression::Lz4, Compression::None];
+        let remote = vec![Compression::Zstd, Compression::Lz4, Compression::Gzip];
+        assert_eq!(Compression::negotiate(&remote, &local), Compression::Lz4);
+
+        let remote = vec![Compression::Zstd];
+        assert_eq!(Compression::negotiate(&remote, &local), Compression::None);
+    }
+}
@@ -1,98 +1,120 @@
-use std::io::{Read, Write};
-
-use crate::{CommandRequest, CommandResponse, KvError};
+use crate::{
+    network::compression::COMPRESSION_MASK, CommandRequest, CommandResponse, Compression, Hello,
+    KvError,
+};
 use bytes::{Buf, BufMut, BytesMut};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
 /// 长度整个占用 4 个字节
 pub const LEN_LEN: usize = 4;
-/// 长度占 31 bit，所以最大的 frame 是 2G
-const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
+/// 长度占 30 bit，所以最大的 frame 是 1G
+pub(crate) const MAX_FRAME: usize = 1024 * 1024 * 1024;
+/// 缺省能接收的最大的 frame
+pub const DEFAULT_MAX_FRAME: usize = 16 * 1024 * 1024;
 /// 如果 payload 超过了 1436 字节，就做压缩
 const COMPRESSION_LIMIT: usize = 1436;
-/// 代表压缩的 bit （整个长度 4 字节的最高位）
-const COMPRESSION_BIT: usize = 1 << 31;
 
 /// 处理 Frame 的 encode/decode
 pub trait FrameCoder
 where
     Self: Message + Sized + Default,
 {
-    /// 把一个 Message encode 成一个 frame
+    /// 把一个 Message encode 成一个 frame，使用 gzip 压缩
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, Compression::Gzip)
+    }
+
+    /// 把一个 Message encode 成一个 frame，使用指定的压缩算法
+    fn encode_frame_with(
+        &self,
+        buf: &mut BytesMut,
+        compression: Compression,
+    ) -> Result<(), KvError> {
         let size = self.encoded_len();
 
         if size >= MAX_FRAME {
             return Err(KvError::FrameError);
         }
 
-        // 我们先写入长度，如果需要压缩，再重写压缩后的长度
-        buf.put_u32(size as _);
-
-        if size > COMPRESSION_LIMIT {
-            let mut buf1 = Vec::with_capacity(size);
-            self.encode(&mut buf1)?;
-
-            // BytesMut 支持逻辑上的 split （之后还能 unsplit）
-            // 所以我们先把长度这 4 字节拿走，清除
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
+        if compression == Compression::None || size <= COMPRESSION_LIMIT {
+            buf.put_u32(size as _);
+            self.encode(buf)?;
+            return Ok(());
+        }
 
-            // 处理 gzip 压缩，具体可以参考 flate2 文档
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&buf1[..])?;
+        let mut buf1 = Vec::with_capacity(size);
+        self.encode(&mut buf1)?;
 
-            // 压缩完成后，从 gzip encoder 中把 BytesMut 再拿回来
-            let payload = encoder.finish()?.into_inner();
-            debug!("Encode a frame: size {}({})", size, payload.len());
+        // 先占住长度的位置，压缩完成后再写入压缩后的长度
+        // buf 里可能已经有别的 frame，所以从当前的末尾开始
+        let start = buf.len();
+        buf.put_u32(0);
+        compression.compress(&buf1, buf)?;
 
-            // 写入压缩后的长度
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
+        let len = buf.len() - start - LEN_LEN;
+        debug!("Encode a frame: size {}({}), {:?}", size, len, compression);
+        if len >= MAX_FRAME {
+            buf.truncate(start);
+            return Err(KvError::FrameError);
+        }
 
-            // 把 BytesMut 再合并回来
-            buf.unsplit(payload);
+        let header = (len | compression.header_bits()) as u32;
+        buf[start..start + LEN_LEN].copy_from_slice(&header.to_be_bytes());
+        Ok(())
+    }
 
-            Ok(())
-        } else {
-            self.encode(buf)?;
-            Ok(())
-        }
+    /// 把一个完整的 frame decode 成一个 Message，接受不压缩和 gzip 压缩的 frame
+    fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
+        Self::decode_frame_with(buf, Compression::Gzip, DEFAULT_MAX_FRAME)
     }
 
     /// 把一个完整的 frame decode 成一个 Message
-    fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
-        // 先取 4 字节，从中拿出长度和 compression bit
+    ///
+    /// 只接受不压缩的 frame 和用 compression（协商好的压缩算法）压缩的 frame，
+    /// 解压之后超过 max_size 的 frame 返回 FrameError
+    fn decode_frame_with(
+        buf: &mut BytesMut,
+        compression: Compression,
+        max_size: usize,
+    ) -> Result<Self, KvError> {
+        // 先取 4 字节，从中拿出长度和压缩算法
         let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
-
-        if compressed {
-            // 解压缩
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut buf1)?;
-            buf.advance(len);
+        let (len, frame_compression) = decode_header(header);
+        debug!("Got a frame: msg len {}, {:?}", len, frame_compression);
 
-            // decode 成相应的消息
-            Ok(Self::decode(&buf1[..buf1.len()])?)
-        } else {
+        if frame_compression == Compression::None {
             let msg = Self::decode(&buf[..len])?;
             buf.advance(len);
-            Ok(msg)
+            return Ok(msg);
         }
+
+        // 跳过整个 frame，出错时 buf 里后面的 frame 还可以继续 decode
+        let data = buf.split_to(len);
+        if frame_compression != compression {
+            return Err(KvError::FrameError);
+        }
+        // 解压缩，然后 decode 成相应的消息
+        let buf1 = frame_compression.decompress(&data, max_size)?;
+        Ok(Self::decode(&buf1[..])?)
     }
 }
 
-/// 从 stream 中读取一个完整的 frame
-pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
+/// 从 stream 中读取一个完整的 frame，超过 max_frame_size 的 frame 在分配内存之前就会被拒绝
+pub async fn read_frame<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    max_frame_size: usize,
+) -> Result<(), KvError>
 where
     S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
     let (len, _compressed) = decode_header(header);
+    if len > max_frame_size {
+        return Err(KvError::FrameError);
+    }
     // 如果没有那么大的内存，就分配至少一个 frame 的内存，保证它可用
     buf.reserve(LEN_LEN + len);
     buf.put_u32(header as _);
@@ -106,11 +128,11 @@ where
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
+impl FrameCoder for Hello {}
 
-fn decode_header(header: usize) -> (usize, bool) {
-    let len = header & !COMPRESSION_BIT;
-    let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
-    (len, compressed)
+pub(crate) fn decode_header(header: usize) -> (usize, Compression) {
+    let len = header & !COMPRESSION_MASK;
+    (len, Compression::from_header(header))
 }
 
 #[cfg(test)]
@@ -119,6 +141,7 @@ mod tests {
     use crate::Value;
     use bytes::Bytes;
     use crate::utils::DummyStream;
+    use std::convert::TryInto;
 
     #[test]
     fn command_request_encode_decode_should_work() {
@@ -128,7 +151,7 @@ mod tests {
         cmd.encode_frame(&mut buf).unwrap();
 
         // 最高位没设置
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
 
         let cmd1 = CommandRequest::decode_frame(&mut buf).unwrap();
         assert_eq!(cmd, cmd1);
@@ -172,17 +195,87 @@ mod tests {
         }
     }
 
+    #[test]
+    fn frame_should_be_compressed_with_given_algorithm() {
+        let value: Value = Bytes::from(vec![0u8; COMPRESSION_LIMIT + 1]).into();
+        let res: CommandResponse = value.into();
+        for c in Compression::supported() {
+            let mut buf = BytesMut::new();
+            res.encode_frame_with(&mut buf, c).unwrap();
+
+            let header = u32::from_be_bytes(buf[..LEN_LEN].try_into().unwrap());
+            assert_eq!(decode_header(header as usize).1, c);
+            let res1 = CommandResponse::decode_frame_with(&mut buf, c, DEFAULT_MAX_FRAME).unwrap();
+            assert_eq!(res1, res);
+        }
+    }
+
+    #[test]
+    fn frame_with_unnegotiated_compression_should_be_rejected() {
+        let value: Value = Bytes::from(vec![0u8; COMPRESSION_LIMIT + 1]).into();
+        let res: CommandResponse = value.into();
+        let mut buf = BytesMut::new();
+        res.encode_frame_with(&mut buf, Compression::Zstd).unwrap();
+        res.encode_frame_with(&mut buf, Compression::Lz4).unwrap();
+
+        let result = CommandResponse::decode_frame_with(&mut buf, Compression::Lz4, 64 * 1024);
+        assert!(matches!(result, Err(KvError::FrameError)));
+        // 被拒绝的 frame 被跳过了，后面的 frame 可以正常 decode
+        let res1 = CommandResponse::decode_frame_with(&mut buf, Compression::Lz4, 64 * 1024);
+        assert_eq!(res1.unwrap(), res);
+
+        // 解压之后超过限制的 frame 也会被拒绝
+        res.encode_frame_with(&mut buf, Compression::Lz4).unwrap();
+        let result = CommandResponse::decode_frame_with(&mut buf, Compression::Lz4, 1024);
+        assert!(matches!(result, Err(KvError::FrameError)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn multiple_frames_should_be_encoded_into_one_buffer() {
+        let value: Value = Bytes::from(vec![0u8; COMPRESSION_LIMIT + 1]).into();
+        let res1: CommandResponse = value.into();
+        let res2: CommandResponse = vec![Value::from("hello")].into();
+
+        let mut buf = BytesMut::new();
+        res2.encode_frame_with(&mut buf, Compression::Zstd).unwrap();
+        res1.encode_frame_with(&mut buf, Compression::Zstd).unwrap();
+        res2.encode_frame_with(&mut buf, Compression::Zstd).unwrap();
+
+        let zstd = Compression::Zstd;
+        let decode = |buf: &mut BytesMut| {
+            CommandResponse::decode_frame_with(buf, zstd, DEFAULT_MAX_FRAME).unwrap()
+        };
+        assert_eq!(decode(&mut buf), res2);
+        assert_eq!(decode(&mut buf), res1);
+        assert_eq!(decode(&mut buf), res2);
+        assert!(buf.is_empty());
+    }
+
     #[tokio::test]
     async fn read_frame_should_work() {
         let mut buf = BytesMut::new();
         let cmd = CommandRequest::new_hdel("t1", "k1");
         cmd.encode_frame(&mut buf).unwrap();
-        let mut stream = DummyStream { buf };
+        let mut stream = DummyStream::new(buf);
 
         let mut data = BytesMut::new();
-        read_frame(&mut stream, &mut data).await.unwrap();
+        read_frame(&mut stream, &mut data, DEFAULT_MAX_FRAME).await.unwrap();
 
         let cmd1 = CommandRequest::decode_frame(&mut data).unwrap();
         assert_eq!(cmd, cmd1);
     }
+
+    #[tokio::test]
+    async fn read_frame_should_reject_oversized_header_before_allocating() {
+        // 只有一个声称有 512M 的 header
+        let mut buf = BytesMut::new();
+        buf.put_u32(512 * 1024 * 1024);
+        let mut stream = DummyStream::new(buf);
+
+        let mut data = BytesMut::new();
+        let result = read_frame(&mut stream, &mut data, 1024).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+        assert_eq!(data.capacity(), 0);
+    }
 }
\ No newline at end of file
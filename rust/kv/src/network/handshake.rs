@@ -0,0 +1,288 @@
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+
+use crate::{
+    read_frame, Compression, Feature, FrameCoder, Hello, KvError, DEFAULT_MAX_FRAME,
+};
+
+/// 当前的协议版本，abi.proto 或者 frame 的格式有不兼容的修改时需要加一
+pub const PROTOCOL_VERSION: u32 = 1;
+/// 能够兼容的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// 握手消息都很小，超过这个大小的肯定不是合法的握手消息
+const MAX_HELLO_SIZE: usize = 64 * 1024;
+
+/// 握手时本地的设置
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeOptions {
+    /// 允许使用的压缩算法，按优先级排列
+    pub compressions: Vec<Compression>,
+    /// 本地能接收的最大的 frame
+    pub max_frame_size: u32,
+    /// 本地支持的功能
+    pub features: Vec<Feature>,
+    /// 对端必须支持的功能，不支持的话握手失败
+    pub required_features: Vec<Feature>,
+}
+
+/// 握手协商的结果
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negotiated {
+    /// 对端的协议版本
+    pub version: u32,
+    /// 发送时使用的压缩算法
+    pub compression: Compression,
+    /// 对端能接收的最大的 frame
+    pub max_frame_size: u32,
+    /// 双方都支持的功能
+    pub features: Vec<Feature>,
+}
+
+impl Default for HandshakeOptions {
+    fn default() -> Self {
+        Self {
+            compressions: Compression::supported(),
+            max_frame_size: DEFAULT_MAX_FRAME as u32,
+            features: Feature::supported(),
+            required_features: Vec::new(),
+        }
+    }
+}
+
+impl Feature {
+    /// 本地支持的所有功能
+    pub fn supported() -> Vec<Feature> {
+        vec![
+            Feature::Pubsub,
+            Feature::StreamingScan,
+            Feature::Transaction,
+            Feature::Ttl,
+        ]
+    }
+}
+
+impl Negotiated {
+    /// 对端是否支持某个功能
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+/// 客户端握手：发送本地的设置，根据服务器的回应得到协商的结果
+pub(crate) async fn client_handshake<S>(
+    stream: &mut S,
+    options: &HandshakeOptions,
+) -> Result<Negotiated, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    write_hello(stream, &options.hello(&options.compressions)).await?;
+
+    let hello = read_hello(stream).await?;
+    if !hello.error.is_empty() {
+        let msg = format!("rejected by server: {}", hello.error);
+        return Err(KvError::HandshakeError(msg));
+    }
+    // 服务器只会返回它选中的压缩算法
+    let compression = hello
+        .compressions()
+        .next()
+        .ok_or_else(|| KvError::HandshakeError("server didn't choose any compression".into()))?;
+
+    let negotiated = options.negotiate(&hello, compression)?;
+    debug!("Client handshake is done: {:?}", negotiated);
+    Ok(negotiated)
+}
+
+/// 服务器握手：检查客户端是否兼容，从客户端支持的压缩算法里选择一个，告诉客户端
+pub(crate) async fn server_handshake<S>(
+    stream: &mut S,
+    options: &HandshakeOptions,
+) -> Result<Negotiated, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let hello = read_hello(stream).await?;
+    let remote: Vec<_> = hello.compressions().collect();
+    let compression = Compression::negotiate(&remote, &options.compressions);
+
+    match options.negotiate(&hello, compression) {
+        Ok(negotiated) => {
+            write_hello(stream, &options.hello(&[compression])).await?;
+            debug!("Server handshake is done: {:?}", negotiated);
+            Ok(negotiated)
+        }
+        Err(e) => {
+            // 告诉客户端为什么被拒绝
+            warn!("Reject client: {}", e);
+            write_hello(stream, &Hello::rejected(PROTOCOL_VERSION, e.to_string())).await?;
+            Err(e)
+        }
+    }
+}
+
+impl HandshakeOptions {
+    fn hello(&self, compressions: &[Compression]) -> Hello {
+        Hello::new(PROTOCOL_VERSION, compressions, self.max_frame_size, &self.features)
+    }
+
+    /// 根据对端的 Hello 检查是否兼容，并得到协商的结果
+    fn negotiate(&self, hello: &Hello, compression: Compression) -> Result<Negotiated, KvError> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.version) {
+            return Err(KvError::HandshakeError(format!(
+                "incompatible protocol version {}, expect {} to {}",
+                hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+
+        let remote: Vec<_> = hello.features().collect();
+        let missing: Vec<_> = self
+            .required_features
+            .iter()
+            .filter(|f| !remote.contains(f))
+            .collect();
+        if !missing.is_empty() {
+            let msg = format!("peer doesn't support required features {:?}", missing);
+            return Err(KvError::HandshakeError(msg));
+        }
+
+        if hello.max_frame_size == 0 {
+            return Err(KvError::HandshakeError("invalid max frame size 0".into()));
+        }
+
+        Ok(Negotiated {
+            version: hello.version,
+            compression,
+            max_frame_size: hello.max_frame_size,
+            features: remote.into_iter().filter(|f| self.features.contains(f)).collect(),
+        })
+    }
+}
+
+async fn write_hello<S>(stream: &mut S, hello: &Hello) -> Result<(), KvError>
+where
+    S: AsyncWrite + Unpin,
+{
+    // 握手的时候还没有协商好压缩算法，所以不压缩
+    let mut buf = BytesMut::new();
+    hello.encode_frame_with(&mut buf, Compression::None)?;
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_hello<S>(stream: &mut S) -> Result<Hello, KvError>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    let mut buf = BytesMut::new();
+    read_frame(stream, &mut buf, MAX_HELLO_SIZE).await?;
+    // 握手消息是不压缩的
+    Hello::decode_frame_with(&mut buf, Compression::None, MAX_HELLO_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_should_negotiate_compression() {
+        let server = HandshakeOptions {
+            compressions: vec![Compression::Lz4, Compression::Gzip, Compression::None],
+            ..Default::default()
+        };
+        let client = HandshakeOptions {
+            compressions: vec![Compression::Zstd, Compression::Gzip, Compression::Lz4],
+            ..Default::default()
+        };
+        let (client, server) = handshake(client, server).await;
+
+        assert_eq!(client.unwrap().compression, Compression::Gzip);
+        assert_eq!(server.unwrap().compression, Compression::Gzip);
+    }
+
+    #[tokio::test]
+    async fn handshake_without_common_compression_should_fall_back_to_none() {
+        let server = HandshakeOptions {
+            compressions: vec![Compression::Lz4],
+            ..Default::default()
+        };
+        let client = HandshakeOptions {
+            compressions: vec![Compression::Zstd],
+            ..Default::default()
+        };
+        let (client, server) = handshake(client, server).await;
+
+        assert_eq!(client.unwrap().compression, Compression::None);
+        assert_eq!(server.unwrap().compression, Compression::None);
+    }
+
+    #[tokio::test]
+    async fn handshake_should_exchange_max_frame_size_and_features() {
+        let server = HandshakeOptions {
+            max_frame_size: 1024,
+            features: vec![Feature::Pubsub, Feature::Ttl],
+            ..Default::default()
+        };
+        let (client, server) = handshake(HandshakeOptions::default(), server).await;
+
+        let client = client.unwrap();
+        assert_eq!(client.version, PROTOCOL_VERSION);
+        assert_eq!(client.max_frame_size, 1024);
+        assert_eq!(client.features, vec![Feature::Pubsub, Feature::Ttl]);
+        assert!(client.supports(Feature::Ttl));
+        assert!(!client.supports(Feature::Transaction));
+
+        let server = server.unwrap();
+        assert_eq!(server.max_frame_size, DEFAULT_MAX_FRAME as u32);
+        assert_eq!(server.features, vec![Feature::Pubsub, Feature::Ttl]);
+    }
+
+    #[tokio::test]
+    async fn handshake_with_missing_required_feature_should_fail() {
+        let server = HandshakeOptions {
+            features: vec![Feature::Pubsub],
+            ..Default::default()
+        };
+        let client = HandshakeOptions {
+            required_features: vec![Feature::Transaction],
+            ..Default::default()
+        };
+        let (client, server) = handshake(client, server).await;
+
+        assert!(matches!(client, Err(KvError::HandshakeError(_))));
+        // 服务器并不知道客户端的要求，它这边是成功的
+        assert!(server.is_ok());
+    }
+
+    #[tokio::test]
+    async fn server_should_reject_incompatible_version() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            server_handshake(&mut server, &HandshakeOptions::default()).await
+        });
+
+        let options = HandshakeOptions::default();
+        let mut hello = options.hello(&options.compressions);
+        hello.version = PROTOCOL_VERSION + 1;
+        write_hello(&mut client, &hello).await.unwrap();
+
+        // 客户端能收到被拒绝的原因
+        let hello = read_hello(&mut client).await.unwrap();
+        assert!(hello.error.contains("incompatible protocol version"));
+        let result = server.await.unwrap();
+        assert!(matches!(result, Err(KvError::HandshakeError(_))));
+    }
+
+    async fn handshake(
+        client: HandshakeOptions,
+        server: HandshakeOptions,
+    ) -> (Result<Negotiated, KvError>, Result<Negotiated, KvError>) {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(1024);
+        let server =
+            tokio::spawn(async move { server_handshake(&mut server_stream, &server).await });
+        let client = client_handshake(&mut client_stream, &client).await;
+        (client, server.await.unwrap())
+    }
+}
@@ -1,57 +1,294 @@
+mod compression;
 mod frame;
+pub(crate) mod handshake;
 mod tls;
 mod stream;
+mod stream_result;
 
-use bytes::BytesMut;
-use futures::{SinkExt, StreamExt};
-pub use frame::{read_frame, FrameCoder};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{future, SinkExt, StreamExt};
+pub use frame::{read_frame, FrameCoder, DEFAULT_MAX_FRAME};
+pub use handshake::{HandshakeOptions, Negotiated, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use http::StatusCode;
+use prost::Message;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tracing::info;
-pub use tls::{TlsServerAcceptor, TlsClientConnector};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{info, warn};
+pub use tls::{client_identity, TlsServerAcceptor, TlsClientConnector};
+pub use stream_result::{KvpairStream, StreamResult};
 
-use crate::{CommandRequest, CommandResponse, KvError, Service};
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, Compression, KvError,
+    MemTable, Metrics, Service, Storage,
+};
+use crate::network::handshake::{client_handshake, server_handshake};
 use crate::network::stream::ProstStream;
 
+/// 服务器端等待发送的 response 的队列长度
+const RESPONSE_CAPACITY: usize = 128;
+/// 客户端等待发送的 request 的队列长度
+const REQUEST_CAPACITY: usize = 128;
+/// 缺省每个连接上最多有这么多字节的请求在执行
+pub const DEFAULT_MAX_INFLIGHT: usize = 32 * 1024 * 1024;
+/// 连续收到这么多无法解析的 frame 之后，认为对端已经不可救药，关闭连接
+const MAX_MALFORMED_FRAMES: usize = 16;
+/// 缺省连接建立之后要在这么长时间内完成握手
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 下一个连接的编号
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
 /// 处理服务器端的某个 accept 下来的 socket 的读写
 // 旧的接口
 // pub struct ProstServerStream<S> {
 //     inner: S,
 //     service: Service,
 // }
-pub struct ProstServerStream<S> {
+pub struct ProstServerStream<S, Store = MemTable> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
-    service: Service,
+    service: Service<Store>,
+    // 连接的编号，订阅只能由建立它的连接取消
+    id: u64,
+    // 握手时本地的设置
+    options: HandshakeOptions,
+    // 客户端的身份，来自客户端证书或者 Auth 命令
+    identity: Option<String>,
+    // 统计连接数和收发的字节数
+    metrics: Option<Arc<Metrics>>,
+    // 服务器退出时变成 true，连接不再读取新的命令
+    shutdown: Option<watch::Receiver<bool>>,
+    // 对端的地址
+    peer: Option<SocketAddr>,
+    // 已经读进来、还没有返回结果的请求最多占用的字节数
+    max_inflight: usize,
+    // 连接建立之后，超过这么长时间还没有完成握手就关闭连接
+    handshake_timeout: Duration,
 }
 
 /// 处理客户端 socket 的读写
+///
+/// 所有的 clone 共享同一个连接，每个请求带上 id，
+/// 后台的 task 根据 response 的 id 把它交给对应的调用者
 // 旧的接口
 // pub struct ProstClientStream<S> {
 //     inner: S,
 // }
-pub struct ProstClientStream<S> {
-    inner: ProstStream<S, CommandResponse, CommandRequest>,
+#[derive(Clone)]
+pub struct ProstClientStream {
+    sender: mpsc::Sender<PendingRequest>,
+    negotiated: Negotiated,
+}
+
+/// 等待发送的请求，以及接收 response 的 channel
+struct PendingRequest {
+    cmd: CommandRequest,
+    tx: mpsc::UnboundedSender<Result<CommandResponse, KvError>>,
+    streaming: bool,
 }
 
-impl<S> ProstServerStream<S>
+impl<S, Store> ProstServerStream<S, Store>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage + Send + Sync + 'static,
 {
-    pub fn new(stream: S, service: Service) -> Self {
+    pub fn new(stream: S, service: Service<Store>) -> Self {
         Self {
             inner: ProstStream::new(stream),
             service,
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            options: HandshakeOptions::default(),
+            identity: None,
+            metrics: None,
+            shutdown: None,
+            peer: None,
+            max_inflight: DEFAULT_MAX_INFLIGHT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
         }
     }
 
+    /// 设置已经读进来、还没有返回结果的请求最多占用的字节数
+    ///
+    /// 超过之后暂停读取新的请求，让客户端感受到背压，而不是无限制地缓存请求
+    pub fn with_max_inflight(mut self, max_inflight: usize) -> Self {
+        self.max_inflight = max_inflight;
+        self
+    }
+
+    /// 服务器退出时通过 shutdown 通知连接：不再读取新的命令，正在执行的命令的结果发完后关闭连接
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// 统计连接数和收发的字节数，请求的统计需要用 Metrics::register 注册到 service 上
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// 设置客户端的身份，比如从客户端证书里得到的身份
+    pub fn with_identity(mut self, identity: Option<String>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// 设置允许使用的压缩算法，握手时从客户端支持的算法里选择
+    pub fn with_compressions(mut self, compressions: Vec<Compression>) -> Self {
+        self.options.compressions = compressions;
+        self
+    }
+
+    /// 设置握手时的全部选项
+    pub fn with_handshake_options(mut self, options: HandshakeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// 设置握手的超时时间，连上来之后什么都不发的客户端不会一直占着连接
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// 设置对端的地址，记录日志时使用
+    pub fn with_peer_addr(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
     pub async fn process(mut self) -> Result<(), KvError> {
+        let peer = self
+            .peer
+            .map_or_else(|| "unknown peer".to_string(), |addr| addr.to_string());
+        let result = self.serve(&peer).await;
+        match &result {
+            Ok(()) => info!("Client {} disconnected", peer),
+            Err(e) => warn!("Connection with {} is closed on error: {:?}", peer, e),
+        }
+        result
+    }
+
+    async fn serve(&mut self, peer: &str) -> Result<(), KvError> {
+        // 连接结束（包括出错返回）时 guard 被 drop，连接数减一
+        let _guard = self.metrics.as_ref().map(|metrics| metrics.connection_opened());
+        if let Some(metrics) = &self.metrics {
+            self.inner.set_metrics(Arc::clone(metrics));
+        }
+        let handshake = server_handshake(self.inner.get_mut(), &self.options);
+        let negotiated = tokio::time::timeout(self.handshake_timeout, handshake)
+            .await
+            .map_err(|_| KvError::Timeout(self.handshake_timeout))??;
+        self.inner.set_compression(negotiated.compression);
+        self.inner.set_max_frame_size(negotiated.max_frame_size as usize);
+        self.inner.set_max_recv_frame_size(self.options.max_frame_size as usize);
+
         let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
-            info!("Got a new command: {:?}", cmd);
-            let res = self.service.execute(cmd);
-            stream.send(res).await.unwrap();
+        // 每个命令在单独的 task 里执行，结果通过 channel 汇总到这里统一写回
+        // 命令的第一个 response 带上这个命令占用的字节数，写回的时候释放
+        let (tx, mut rx) = mpsc::channel::<(CommandResponse, usize)>(RESPONSE_CAPACITY);
+        // 已经读进来、还没有返回结果的请求占用的字节数
+        let mut inflight = 0;
+        // 连接的 task 被取消时，drop 掉的 JoinSet 会取消所有还在执行的命令
+        let mut tasks = JoinSet::new();
+        let mut subscriptions = Vec::new();
+        let mut shutdown = self.shutdown.take();
+        // 连续收到的无法解析的 frame 的数量
+        let mut malformed = 0;
+        // 读连接出错时，先把已经在执行的命令的结果发完，再返回这个错误
+        let mut read_error = None;
+
+        loop {
+            tokio::select! {
+                _ = wait_shutdown(&mut shutdown) => {
+                    info!("Server is shutting down, stop reading commands from {}", peer);
+                    break;
+                }
+                // 超过额度之后不再读取，直到有命令返回了结果
+                cmd = stream.next(), if inflight < self.max_inflight => match cmd {
+                    Some(Ok(cmd)) => {
+                        info!("Got a new command: {:?}", cmd);
+                        malformed = 0;
+                        let id = cmd.id;
+                        let mut size = cmd.encoded_len();
+                        inflight += size;
+                        let is_endless = matches!(
+                            cmd.request_data,
+                            Some(RequestData::Subscribe(_) | RequestData::Replicate(_))
+                        );
+                        // 登录成功之后，这个连接后续的命令都以新的身份执行
+                        if let Some(RequestData::Auth(param)) = &cmd.request_data {
+                            if let Ok(identity) = self.service.authenticate(&param.token) {
+                                info!("Client {} authenticated as {}", peer, identity);
+                                self.identity = Some(identity);
+                            }
+                        }
+                        let (identity, connection) = (self.identity.clone(), self.id);
+                        let service = self.service.clone();
+                        let tx = tx.clone();
+                        let handle = tasks.spawn(async move {
+                            let mut res =
+                                service.execute_on(cmd, identity.as_deref(), Some(connection));
+                            while let Some(data) = res.next().await {
+                                let mut data = data.as_ref().clone();
+                                data.id = id;
+                                // 有了第一个 response 之后，命令就不再占用额度，订阅也是这样
+                                let size = std::mem::take(&mut size);
+                                if tx.send((data, size)).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                        if is_endless {
+                            subscriptions.push(handle);
+                        }
+                    }
+                    // frame 是完整的，只是内容有问题，回复一个 400 之后可以继续读后面的 frame
+                    Some(Err(KvError::MalformedFrame(msg))) => {
+                        warn!("Got a malformed frame from {}: {}", peer, msg);
+                        // 解不出请求，也就不知道它的 id，只能用 0
+                        let res: CommandResponse = KvError::MalformedFrame(msg.clone()).into();
+                        send_response(stream, &self.service, &res).await?;
+                        malformed += 1;
+                        if malformed >= MAX_MALFORMED_FRAMES {
+                            read_error = Some(KvError::MalformedFrame(msg));
+                            break;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        read_error = Some(e);
+                        break;
+                    }
+                    None => break,
+                },
+                Some((res, size)) = rx.recv() => {
+                    inflight -= size;
+                    send_response(stream, &self.service, &res).await?;
+                }
+                // 回收已经结束的命令
+                Some(_) = tasks.join_next() => {}
+            }
         }
-        // info!("Client {:?} disconnected", self.addr);
-        Ok(())
+
+        // 不再读取请求了：订阅和复制不会再结束，直接取消；其它命令的结果发完再退出
+        subscriptions.iter().for_each(|handle| handle.abort());
+        drop(tx);
+        while let Some((res, _)) = rx.recv().await {
+            send_response(stream, &self.service, &res).await?;
+        }
+        // 对 TLS 连接来说，这里会发送 close_notify，让客户端知道连接是正常关闭的
+        let _ = stream.get_mut().shutdown().await;
+        read_error.map_or(Ok(()), Err)
     }
 
     // // 旧的接口方法， 删除
@@ -83,24 +320,144 @@ where
     // }
 }
 
-impl<S> ProstClientStream<S>
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send,
-{
-    pub fn new(stream: S) -> Self {
-        Self { inner: ProstStream::new(stream) }
+impl ProstClientStream {
+    /// 和服务器握手，建立客户端，允许使用所有支持的压缩算法
+    pub async fn new<S>(stream: S) -> Result<Self, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::with_options(stream, HandshakeOptions::default()).await
     }
 
-    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
-        let stream = &mut self.inner;
-        stream.send(cmd).await?;
+    /// 和服务器握手，建立客户端，compressions 是按优先级排列的压缩算法
+    pub async fn with_compressions<S>(
+        stream: S,
+        compressions: &[Compression],
+    ) -> Result<Self, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let options = HandshakeOptions {
+            compressions: compressions.to_vec(),
+            ..Default::default()
+        };
+        Self::with_options(stream, options).await
+    }
+
+    /// 和服务器握手，建立客户端，服务器的协议版本不兼容或者缺少必需的功能时返回错误
+    pub async fn with_options<S>(stream: S, options: HandshakeOptions) -> Result<Self, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut stream = ProstStream::new(stream);
+        let negotiated = client_handshake(stream.get_mut(), &options).await?;
+        stream.set_compression(negotiated.compression);
+        stream.set_max_frame_size(negotiated.max_frame_size as usize);
+        stream.set_max_recv_frame_size(options.max_frame_size as usize);
 
-        match stream.next().await {
+        let (sender, requests) = mpsc::channel(REQUEST_CAPACITY);
+        tokio::spawn(client_loop(stream, requests));
+        Ok(Self { sender, negotiated })
+    }
+
+    /// 连接是否已经断开，断开之后所有的命令都会失败
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// 两个 client 是否共用同一个连接
+    pub(crate) fn is_same(&self, other: &Self) -> bool {
+        self.sender.same_channel(&other.sender)
+    }
+
+    /// 握手协商的结果，可以用来判断服务器是否支持某个功能
+    pub fn negotiated(&self) -> &Negotiated {
+        &self.negotiated
+    }
+
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        // 遍历类的命令会分成多个 response 返回，这里把它们合并成一个
+        if cmd.is_scan() {
+            return self.execute_merged(cmd).await;
+        }
+
+        let mut rx = self.send(cmd, false).await?;
+
+        match rx.recv().await {
             Some(v) => v,
             None => Err(KvError::Internal("Didn't get any response".into()))
         }
     }
 
+    /// 执行流式命令（比如 subscribe），得到一个 response 的 stream
+    pub async fn execute_streaming(&self, cmd: CommandRequest) -> Result<StreamResult, KvError> {
+        let rx = self.send(cmd, true).await?;
+        StreamResult::new(UnboundedReceiverStream::new(rx)).await
+    }
+
+    /// 执行遍历类的命令（比如 hgetall），得到一个 kv pair 的 stream
+    ///
+    /// 命令本身出错（比如没有权限）时服务器只会返回一个错误和结束标记，直接返回这个错误；
+    /// 之后出错或者连接在结束标记之前断开，stream 的最后一项是错误
+    pub async fn execute_scan(&self, cmd: CommandRequest) -> Result<KvpairStream, KvError> {
+        let rx = self.send(cmd, true).await?;
+        let mut stream = UnboundedReceiverStream::new(rx);
+        let first = match stream.next().await {
+            Some(res) => res?.into_result()?,
+            None => return Err(KvError::Internal("Didn't get any response".into())),
+        };
+
+        let responses = futures::stream::once(future::ready(Ok(first))).chain(stream);
+        let pairs = futures::stream::unfold(Some(responses), |responses| async move {
+            let mut responses = responses?;
+            let (items, responses) = match responses.next().await {
+                Some(Ok(res)) if res.stream_end => return None,
+                Some(Ok(res)) => match res.into_result() {
+                    Ok(res) => (res.pairs.into_iter().map(Ok).collect(), Some(responses)),
+                    Err(e) => (vec![Err(e)], None),
+                },
+                Some(Err(e)) => (vec![Err(e)], None),
+                None => {
+                    let e = KvError::Internal("Stream closed before it ends".into());
+                    (vec![Err(e)], None)
+                }
+            };
+            Some((futures::stream::iter(items), responses))
+        })
+        .flatten();
+        Ok(Box::pin(pairs))
+    }
+
+    async fn execute_merged(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let mut rx = self.send(cmd, true).await?;
+        let mut merged = CommandResponse::ok();
+        while let Some(res) = rx.recv().await {
+            let res = res?;
+            if res.stream_end {
+                return Ok(merged);
+            }
+            if res.status != StatusCode::OK.as_u16() as u32 {
+                return Ok(res);
+            }
+            merged.pairs.extend(res.pairs);
+        }
+        Err(KvError::Internal("Stream closed before it ends".into()))
+    }
+
+    async fn send(
+        &self,
+        cmd: CommandRequest,
+        streaming: bool,
+    ) -> Result<mpsc::UnboundedReceiver<Result<CommandResponse, KvError>>, KvError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let req = PendingRequest { cmd, tx, streaming };
+        self.sender
+            .send(req)
+            .await
+            .map_err(|_| KvError::Internal("Connection is closed".into()))?;
+        Ok(rx)
+    }
+
     // // 旧的接口方法，删除
     //
     // pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
@@ -124,14 +481,121 @@ where
     // }
 }
 
+/// 把 response 写到连接上，对端收不下的 response 换成一个错误
+async fn send_response<S, Store>(
+    stream: &mut ProstStream<S, CommandRequest, CommandResponse>,
+    service: &Service<Store>,
+    res: &CommandResponse,
+) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    match stream.send(res).await {
+        // 超过对端限制的 frame 没有写出去，连接还可以继续用
+        Err(KvError::FrameError) => {
+            let mut err: CommandResponse = KvError::FrameError.into();
+            err.id = res.id;
+            stream.send(&err).await?;
+        }
+        result => result?,
+    }
+    service.after_send();
+    Ok(())
+}
+
+/// 等待服务器退出的通知，没有设置 shutdown 的话永远不会完成
+async fn wait_shutdown(shutdown: &mut Option<watch::Receiver<bool>>) {
+    match shutdown {
+        // sender 被 drop 掉也当作退出
+        Some(rx) => {
+            let _ = rx.wait_for(|stop| *stop).await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// 客户端后台的 task：给请求分配 id 并发送，再把收到的 response 按 id 分发
+async fn client_loop<S>(
+    mut stream: ProstStream<S, CommandResponse, CommandRequest>,
+    mut requests: mpsc::Receiver<PendingRequest>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut pending = HashMap::new();
+    let mut next_id: u32 = 1;
+    let mut closed = false;
+
+    // 所有的 client 都被 drop 之后，还要等正在进行的请求（比如订阅）结束
+    while !closed || !pending.is_empty() {
+        tokio::select! {
+            req = requests.recv(), if !closed => match req {
+                Some(PendingRequest { mut cmd, tx, streaming }) => {
+                    // id 0 留给不带 id 的请求
+                    let id = next_id;
+                    next_id = next_id.checked_add(1).unwrap_or(1);
+                    cmd.id = id;
+                    match stream.send(&cmd).await {
+                        Ok(()) => {}
+                        // 超过服务器限制的请求没有发出去，连接还可以继续用
+                        Err(KvError::FrameError) => {
+                            let _ = tx.send(Err(KvError::FrameError));
+                            continue;
+                        }
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                            break;
+                        }
+                    }
+                    pending.insert(id, (tx, streaming));
+                }
+                None => closed = true,
+            },
+            res = stream.next() => match res {
+                Some(Ok(res)) => {
+                    let id = res.id;
+                    // 客户端不会使用 id 0，这是服务器解不出某个请求时的回复
+                    if id == 0 {
+                        warn!("Server failed to decode a request: {}", res.message);
+                    }
+                    if let Some((tx, streaming)) = pending.get(&id) {
+                        // 非流式的请求只有一个 response；调用者不再接收时也清理掉
+                        let done = !*streaming || res.stream_end;
+                        if tx.send(Ok(res)).is_err() || done {
+                            pending.remove(&id);
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    warn!("Failed to read response: {:?}", e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
-    use bytes::Bytes;
-    use std::net::SocketAddr;
+    use bytes::{BufMut, Bytes, BytesMut};
+    use futures::TryStreamExt;
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tempfile::tempdir;
     use tokio::net::{TcpListener, TcpStream};
 
-    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use crate::{
+        assert_res_ok, utils::DummyStream, AuthConfig, Feature, Hello, Kvpair, MemTable,
+        ServiceInner, SledDb, TableAcl, Value,
+    };
 
     use super::*;
 
@@ -140,7 +604,7 @@ mod tests {
         let addr = start_server().await?;
 
         let stream = TcpStream::connect(addr).await?;
-        let mut client = ProstClientStream::new(stream);
+        let client = ProstClientStream::new(stream).await?;
 
         // 发送 HSET，等待回应
 
@@ -148,14 +612,14 @@ mod tests {
         let res = client.execute(cmd).await.unwrap();
 
         // 第一次 HSET 服务器应该返回 None
-        assert_res_ok(res, &[Value::default()], &[]);
+        assert_res_ok(&res, &[Value::default()], &[]);
 
         // 再发一个 HSET
         let cmd = CommandRequest::new_hget("t1", "k1");
         let res = client.execute(cmd).await?;
 
         // 服务器应该返回上一次的结果
-        assert_res_ok(res, &["v1".into()], &[]);
+        assert_res_ok(&res, &["v1".into()], &[]);
 
         Ok(())
     }
@@ -165,31 +629,642 @@ mod tests {
         let addr = start_server().await?;
 
         let stream = TcpStream::connect(addr).await?;
-        let mut client = ProstClientStream::new(stream);
+        let client = ProstClientStream::new(stream).await?;
 
         let v: Value = Bytes::from(vec![0u8; 16384]).into();
         let cmd = CommandRequest::new_hset("t2", "k2", v.clone());
         let res = client.execute(cmd).await?;
 
-        assert_res_ok(res, &[Value::default()], &[]);
+        assert_res_ok(&res, &[Value::default()], &[]);
 
         let cmd = CommandRequest::new_hget("t2", "k2");
         let res = client.execute(cmd).await?;
 
-        assert_res_ok(res, &[v], &[]);
+        assert_res_ok(&res, &[v], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_should_use_negotiated_compression() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let (stream, _) = listener.accept().await.unwrap();
+            let compressions = vec![Compression::Lz4, Compression::None];
+            let server = ProstServerStream::new(stream, service).with_compressions(compressions);
+            server.process().await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let compressions = [Compression::Zstd, Compression::Lz4];
+        let client = ProstClientStream::with_compressions(stream, &compressions).await?;
+
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        client.execute(CommandRequest::new_hset("t1", "k1", v.clone())).await?;
+        let res = client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+        assert_res_ok(&res, &[v], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_respect_server_max_frame_size() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let (stream, _) = listener.accept().await.unwrap();
+            let options = HandshakeOptions {
+                compressions: vec![Compression::None],
+                max_frame_size: 1024,
+                ..Default::default()
+            };
+            let server = ProstServerStream::new(stream, service).with_handshake_options(options);
+            server.process().await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+        assert_eq!(client.negotiated().max_frame_size, 1024);
+        assert_eq!(client.negotiated().version, PROTOCOL_VERSION);
+
+        // 太大的请求在客户端就被拒绝，连接不受影响
+        let v: Value = Bytes::from(vec![0u8; 4096]).into();
+        let result = client.execute(CommandRequest::new_hset("t1", "k1", v)).await;
+        assert!(matches!(result, Err(KvError::FrameError)));
+
+        let res = client.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_reject_server_without_required_features() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            let (stream, _) = listener.accept().await.unwrap();
+            let options = HandshakeOptions {
+                features: vec![Feature::Pubsub],
+                ..Default::default()
+            };
+            let server = ProstServerStream::new(stream, service).with_handshake_options(options);
+            let _ = server.process().await;
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let options = HandshakeOptions {
+            required_features: vec![Feature::Transaction],
+            ..Default::default()
+        };
+        let result = ProstClientStream::with_options(stream, options).await;
+        assert!(matches!(result, Err(KvError::HandshakeError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_auth_with_token() -> anyhow::Result<()> {
+        let mut config = AuthConfig::default();
+        config.tokens.insert("secret".into(), "alice".into());
+        let acl = TableAcl {
+            read: vec![],
+            write: vec!["alice".into()],
+        };
+        config.tables.insert("users".into(), acl);
+        let addr = start_server_with(ServiceInner::new(MemTable::new()).auth(config).into()).await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+        let cmd = CommandRequest::new_hset("users", "k1", "v1".into());
+        let res = client.execute(cmd.clone()).await?;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED.as_u16() as u32);
+
+        let res = client.execute(CommandRequest::new_auth("bad")).await?;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED.as_u16() as u32);
+        let res = client.execute(CommandRequest::new_auth("secret")).await?;
+        assert_res_ok(&res, &["alice".into()], &[]);
+
+        // 登录之后的命令以 alice 的身份执行
+        let res = client.execute(cmd).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_fire_all_hooks() -> anyhow::Result<()> {
+        let counters: Arc<[AtomicUsize; 4]> = Arc::new(Default::default());
+        let (c0, c1) = (counters.clone(), counters.clone());
+        let (c2, c3) = (counters.clone(), counters.clone());
+        let (sent_tx, mut sent_rx) = mpsc::unbounded_channel();
+        let service = ServiceInner::new(MemTable::new())
+            .fn_received(move |_| {
+                c0[0].fetch_add(1, Ordering::SeqCst);
+            })
+            .fn_executed(move |_| {
+                c1[1].fetch_add(1, Ordering::SeqCst);
+            })
+            .fn_before_send(move |_| {
+                c2[2].fetch_add(1, Ordering::SeqCst);
+            })
+            .fn_after_send(move || {
+                c3[3].fetch_add(1, Ordering::SeqCst);
+                let _ = sent_tx.send(());
+            });
+        let addr = start_server_with(service.into()).await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+        client.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+        client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+
+        // after_send 在写完之后才调用，可能比客户端收到 response 稍晚一点
+        for _ in 0..2 {
+            sent_rx.recv().await;
+        }
+        let counts: Vec<_> = counters.iter().map(|c| c.load(Ordering::SeqCst)).collect();
+        assert_eq!(counts, vec![2, 2, 2, 2]);
 
         Ok(())
     }
 
+    #[tokio::test]
+    async fn server_should_record_connection_metrics() -> anyhow::Result<()> {
+        let metrics = Metrics::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let m = metrics.clone();
+        let server = tokio::spawn(async move {
+            let service: Service = m.register(ServiceInner::new(MemTable::new())).into();
+            let (stream, _) = listener.accept().await.unwrap();
+            let server = ProstServerStream::new(stream, service).with_metrics(m);
+            server.process().await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+        let v: Value = Bytes::from(vec![0u8; 16384]).into();
+        client.execute(CommandRequest::new_hset("t1", "k1", v)).await?;
+        client.execute(CommandRequest::new_hget("t1", "k1")).await?;
+
+        let out = metrics.render();
+        assert!(out.contains("kv_connections_active 1\n"));
+        assert!(out.contains("kv_requests_total{command=\"hget\"} 1\n"));
+        // 大的 value 会被压缩
+        let ratio = out
+            .lines()
+            .find_map(|l| l.strip_prefix("kv_compression_ratio{direction=\"in\"} "))
+            .unwrap();
+        assert!(ratio.parse::<f64>()? > 10.0);
+
+        // 连接关闭之后连接数减一
+        drop(client);
+        server.await??;
+        assert!(metrics.render().contains("kv_connections_active 0\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_finish_inflight_commands_on_shutdown() -> anyhow::Result<()> {
+        // 每个命令都要执行一段时间，开始执行时通知一下
+        let (started_tx, mut started_rx) = mpsc::unbounded_channel();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_intercept(move |_| {
+                let _ = started_tx.send(());
+                Box::pin(async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    None
+                })
+            })
+            .into();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            ProstServerStream::new(stream, service).with_shutdown(stop_rx).process().await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let pending = tokio::spawn({
+            let client = client.clone();
+            async move { client.execute(cmd).await }
+        });
+
+        // 命令执行到一半时服务器要退出，命令的结果还是会发回来
+        started_rx.recv().await;
+        stop_tx.send(true)?;
+        let res = pending.await??;
+        assert_res_ok(&res, &[Value::default()], &[]);
+        tokio::time::timeout(Duration::from_secs(1), server).await???;
+
+        // 之后连接已经关闭了
+        let result = client.execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_reply_bad_request_to_malformed_frames() -> anyhow::Result<()> {
+        let mut input = client_hello()?;
+        // protobuf 解不出来的 frame
+        input.put_u32(3);
+        input.put_slice(&[0xff, 0xff, 0xff]);
+        // 标记成 gzip 压缩，但内容不是 gzip 的数据
+        input.put_u32((3 | Compression::Gzip.header_bits()) as u32);
+        input.put_slice(b"abc");
+        // 后面的命令不受影响
+        let mut cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        cmd.id = 1;
+        cmd.encode_frame(&mut input)?;
+
+        let mut stream = DummyStream::with_output(input);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        ProstServerStream::new(&mut stream, service).process().await?;
+
+        let responses = server_responses(stream)?;
+        let statuses: Vec<_> = responses.iter().map(|res| (res.id, res.status)).collect();
+        assert_eq!(statuses, vec![(0, 400), (0, 400), (1, 200)]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_close_after_too_many_malformed_frames() -> anyhow::Result<()> {
+        let mut input = client_hello()?;
+        for _ in 0..MAX_MALFORMED_FRAMES {
+            input.put_u32(1);
+            input.put_u8(0xff);
+        }
+        CommandRequest::new_hget("t1", "k1").encode_frame(&mut input)?;
+
+        let mut stream = DummyStream::with_output(input);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let result = ProstServerStream::new(&mut stream, service).process().await;
+        assert!(matches!(result, Err(KvError::MalformedFrame(_))));
+
+        // 最后一个命令没有被执行
+        let responses = server_responses(stream)?;
+        assert_eq!(responses.len(), MAX_MALFORMED_FRAMES);
+        assert!(responses.iter().all(|res| res.status == 400));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_send_pending_responses_before_closing_on_read_error() -> Result<()> {
+        let mut input = client_hello()?;
+        let mut cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        cmd.id = 1;
+        cmd.encode_frame(&mut input)?;
+        // 对端在发送一个 frame 的中途断开了
+        input.put_u32(10);
+        input.put_slice(b"abc");
+
+        let mut stream = DummyStream::with_output(input);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let result = ProstServerStream::new(&mut stream, service).process().await;
+        assert!(matches!(result, Err(KvError::IoError(_))));
+
+        let responses = server_responses(stream)?;
+        assert_eq!(responses.len(), 1);
+        assert_res_ok(&responses[0], &[Value::default()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn server_should_close_connection_without_handshake() -> Result<()> {
+        // 客户端连上来之后什么都不发
+        let (_client, server) = tokio::io::duplex(1024);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let result = ProstServerStream::new(server, service).process().await;
+        assert!(matches!(result, Err(KvError::Timeout(t)) if t == DEFAULT_HANDSHAKE_TIMEOUT));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_should_not_panic_when_client_is_gone() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut input = client_hello()?;
+        CommandRequest::new_hget("t1", "k1").encode_frame(&mut input)?;
+        client.write_all(&input).await?;
+        // 客户端发完请求就断开，服务器写 response 时出错
+        drop(client);
+
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let result = ProstServerStream::new(server, service).process().await;
+        assert!(matches!(result, Err(KvError::IoError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn server_should_stop_reading_when_inflight_budget_is_used_up() -> anyhow::Result<()> {
+        let received = Arc::new(AtomicUsize::new(0));
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let (r, g) = (received.clone(), gate.clone());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_received(move |_| {
+                r.fetch_add(1, Ordering::SeqCst);
+            })
+            // 命令一直执行不完，直到 gate 打开
+            .fn_intercept(move |_| {
+                let gate = g.clone();
+                Box::pin(async move {
+                    let _permit = gate.acquire().await;
+                    None
+                })
+            })
+            .into();
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            // 一个命令就会用完额度
+            let server = ProstServerStream::new(server_io, service).with_max_inflight(1);
+            server.process().await.unwrap();
+        });
+
+        let client = ProstClientStream::new(client_io).await?;
+        let handles: Vec<_> = (0..3)
+            .map(|i| {
+                let client = client.clone();
+                let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+                tokio::spawn(async move { client.execute(cmd).await })
+            })
+            .collect();
+
+        // 第一个命令没有结果之前，服务器不会读后面的命令
+        // 时间是暂停的，所有的 task 都空闲下来之后 sleep 才会返回
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        gate.add_permits(1);
+        for handle in handles {
+            assert_res_ok(&handle.await??, &[Value::default()], &[]);
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_with_sled_should_work() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let service: Service<SledDb> = ServiceInner::new(SledDb::new(dir.path())).into();
+        let addr = start_server_with(service).await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await?;
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute(cmd).await?;
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_pub_sub_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        // 订阅和其它命令共享同一个连接
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+        let id = sub.id;
+
+        let data = vec!["hello".into(), 42.into()];
+        let res = client.execute(CommandRequest::new_publish("lobby", data.clone())).await?;
+        assert_res_ok(&res, &[], &[]);
+
+        let res = sub.next().await.unwrap()?;
+        assert_res_ok(&res, &data, &[]);
+
+        // 取消订阅后，订阅的 stream 结束
+        let res = client.execute(CommandRequest::new_unsubscribe("lobby", id)).await?;
+        assert_res_ok(&res, &[], &[]);
+        assert!(sub.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_not_unsubscribe_other_connections() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let client = ProstClientStream::new(TcpStream::connect(addr).await?).await?;
+        let other = ProstClientStream::new(TcpStream::connect(addr).await?).await?;
+        let mut sub = client
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await?;
+
+        // 别的连接猜到了 id 也取消不了
+        let res = other.execute(CommandRequest::new_unsubscribe("lobby", sub.id)).await?;
+        assert_eq!(res.status, StatusCode::NOT_FOUND.as_u16() as u32);
+
+        let data = vec!["hello".into()];
+        other.execute(CommandRequest::new_publish("lobby", data.clone())).await?;
+        assert_res_ok(&sub.next().await.unwrap()?, &data, &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_hgetall_should_stream_pairs() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+
+        // 数据多到需要分成多个 response 返回
+        let pairs: Vec<_> = (0..1000i64)
+            .map(|i| Kvpair::new(format!("k{}", i), i.into()))
+            .collect();
+        client.execute(CommandRequest::new_hmset("t1", pairs.clone())).await?;
+
+        let mut result: Vec<_> = client
+            .execute_scan(CommandRequest::new_hgetall("t1"))
+            .await?
+            .try_collect()
+            .await?;
+        result.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut expected = pairs.clone();
+        expected.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(result, expected);
+
+        // execute 会把多个 response 合并成一个
+        let res = client.execute(CommandRequest::new_hgetall("t1")).await?;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.pairs.len(), pairs.len());
+
+        let cmd = CommandRequest::new_hrange("t1", "k99", "", "");
+        let result: Vec<_> = client.execute_scan(cmd).await?.try_collect().await?;
+        assert_eq!(result.len(), 11);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_scan_should_return_structured_errors() -> anyhow::Result<()> {
+        let mut config = AuthConfig::default();
+        config.tokens.insert("secret".into(), "bob".into());
+        let acl = TableAcl {
+            read: vec!["alice".into()],
+            write: vec![],
+        };
+        config.tables.insert("users".into(), acl);
+        let addr = start_server_with(ServiceInner::new(MemTable::new()).auth(config).into()).await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+        let result = client.execute_scan(CommandRequest::new_hgetall("users")).await;
+        assert!(matches!(result, Err(KvError::Unauthenticated(_))));
+
+        client.execute(CommandRequest::new_auth("secret")).await?.into_result()?;
+        let result = client.execute_scan(CommandRequest::new_hgetall("users")).await;
+        assert!(matches!(result, Err(KvError::PermissionDenied(..))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_scan_should_fail_when_connection_is_cut() -> anyhow::Result<()> {
+        let (client_io, mut server_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let options = HandshakeOptions::default();
+            let negotiated = server_handshake(&mut server_io, &options).await?;
+            let mut stream = ProstStream::<_, CommandRequest, CommandResponse>::new(server_io);
+            stream.set_compression(negotiated.compression);
+            // 只发了一部分结果，没有结束标记就断开了
+            let cmd = stream.next().await.unwrap()?;
+            let res = CommandResponse {
+                id: cmd.id,
+                status: 200,
+                pairs: vec![Kvpair::new("k1", "v1".into())],
+                ..Default::default()
+            };
+            stream.send(&res).await
+        });
+
+        let client = ProstClientStream::new(client_io).await?;
+        let result: Vec<_> = client
+            .execute_scan(CommandRequest::new_hgetall("t1"))
+            .await?
+            .collect()
+            .await;
+        assert_eq!(result.len(), 2);
+        assert!(matches!(&result[0], Ok(pair) if pair.key == "k1"));
+        assert!(result[1].is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_multiplex_concurrent_requests() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+
+        // 多个 task 共享一个连接，同时发送请求，每个 task 都应该拿到自己的结果
+        let handles: Vec<_> = (0..32i64)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let key = format!("k{}", i);
+                    let cmd = CommandRequest::new_hset("t1", &key, i.into());
+                    let res = client.execute(cmd).await?;
+                    assert_res_ok(&res, &[Value::default()], &[]);
+
+                    let res = client.execute(CommandRequest::new_hget("t1", &key)).await?;
+                    assert_res_ok(&res, &[i.into()], &[]);
+                    Ok::<_, KvError>(())
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.await??;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_should_fail_when_connection_closed() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            // 第一个连接直接关闭，第二个连接握手之后再关闭
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().await.unwrap();
+            server_handshake(&mut stream, &HandshakeOptions::default()).await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        assert!(ProstClientStream::new(stream).await.is_err());
+
+        let stream = TcpStream::connect(addr).await?;
+        let client = ProstClientStream::new(stream).await?;
+        let result = client.execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    /// 客户端的握手消息，不使用压缩
+    fn client_hello() -> Result<BytesMut> {
+        let features = Feature::supported();
+        let hello = Hello::new(PROTOCOL_VERSION, &[Compression::None], 1024 * 1024, &features);
+        let mut buf = BytesMut::new();
+        hello.encode_frame_with(&mut buf, Compression::None)?;
+        Ok(buf)
+    }
+
+    /// 服务器写出的握手消息之后的所有 response
+    fn server_responses(stream: DummyStream) -> Result<Vec<CommandResponse>> {
+        let mut output = stream.output.unwrap();
+        let hello = Hello::decode_frame(&mut output)?;
+        assert!(hello.error.is_empty());
+        let mut responses = Vec::new();
+        while !output.is_empty() {
+            responses.push(CommandResponse::decode_frame(&mut output)?);
+        }
+        Ok(responses)
+    }
+
     async fn start_server() -> Result<SocketAddr> {
+        start_server_with(ServiceInner::new(MemTable::new()).into()).await
+    }
+
+    async fn start_server_with<Store>(service: Service<Store>) -> Result<SocketAddr>
+    where
+        Store: Storage + Send + Sync + 'static,
+    {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
 
         tokio::spawn(async move {
             loop {
                 let (stream, _) = listener.accept().await.unwrap();
-                let service: Service = ServiceInner::new(MemTable::new()).into();
-                let server = ProstServerStream::new(stream, service);
+                let server = ProstServerStream::new(stream, service.clone());
                 tokio::spawn(server.process());
             }
         });
@@ -205,9 +1280,37 @@ pub mod utils {
     use std::task::{Context, Poll};
     use bytes::{BufMut, BytesMut};
     use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::net::TcpListener;
+
+    use crate::{ProstServerStream, Service};
+
+    /// 在 listener 上接受连接，每个连接用 service 处理
+    pub async fn serve(listener: TcpListener, service: Service) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(ProstServerStream::new(stream, service.clone()).process());
+        }
+    }
 
     pub struct DummyStream {
         pub buf: BytesMut,
+        // 设置了的话，写入的数据放在这里，不会再被读出来，可以用来模拟对端
+        pub output: Option<BytesMut>,
+    }
+
+    impl DummyStream {
+        /// 写入的数据追加到 buf 里，之后可以读出来
+        pub fn new(buf: BytesMut) -> Self {
+            Self { buf, output: None }
+        }
+
+        /// 从 input 里读，写入的数据放到 output 里
+        pub fn with_output(input: BytesMut) -> Self {
+            Self {
+                buf: input,
+                output: Some(BytesMut::new()),
+            }
+        }
     }
 
     impl AsyncRead for DummyStream {
@@ -216,8 +1319,8 @@ pub mod utils {
             _cx: &mut std::task::Context<'_>,
             buf: &mut tokio::io::ReadBuf<'_>,
         ) -> std::task::Poll<std::io::Result<()>> {
-            // 看看 ReadBuf 需要多大的数据
-            let len = buf.capacity();
+            // 看看 ReadBuf 需要多大的数据，最多给出我们手上有的数据
+            let len = buf.remaining().min(self.buf.len());
 
             // split 出这么大的数据
             let data = self.get_mut().buf.split_to(len);
@@ -231,16 +1334,17 @@ pub mod utils {
     }
 
     impl AsyncWrite for DummyStream {
-        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
-            self.get_mut().buf.put_slice(buf);
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+            let this = self.get_mut();
+            this.output.as_mut().unwrap_or(&mut this.buf).put_slice(buf);
             Poll::Ready(Ok(buf.len()))
         }
 
-        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
             Poll::Ready(Ok(()))
         }
 
-        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
             Poll::Ready(Ok(()))
         }
     }
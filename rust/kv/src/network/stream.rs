@@ -1,13 +1,23 @@
 use bytes::BytesMut;
-use futures::{FutureExt, ready, Sink, Stream};
+use futures::{ready, Sink, Stream};
 use std::{
+    convert::TryInto,
+    io,
     marker::PhantomData,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
 
-use crate::{read_frame, FrameCoder, KvError};
+use crate::{
+    network::frame::{decode_header, LEN_LEN, MAX_FRAME},
+    Compression, FrameCoder, KvError, Metrics, DEFAULT_MAX_FRAME,
+};
+
+/// 读缓存每次至少扩充的大小
+const INITIAL_CAPACITY: usize = 4 * 1024;
 
 /// 处理 KV server prost frame 的 stream
 pub struct ProstStream<S, In, Out> {
@@ -19,6 +29,14 @@ pub struct ProstStream<S, In, Out> {
     written: usize,
     // 读缓存
     rbuf: BytesMut,
+    // 发送时使用的压缩算法
+    compression: Compression,
+    // 对端能接收的最大的 frame
+    max_frame_size: usize,
+    // 本地能接收的最大的 frame
+    max_recv_frame_size: usize,
+    // 统计收发的字节数
+    metrics: Option<Arc<Metrics>>,
 
     // 类型占位符
     _in: PhantomData<In>,
@@ -36,10 +54,39 @@ where
             written: 0,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
-            _in: PhantomData::default(),
-            _out: PhantomData::default()
+            compression: Compression::Gzip,
+            max_frame_size: MAX_FRAME,
+            max_recv_frame_size: DEFAULT_MAX_FRAME,
+            metrics: None,
+            _in: PhantomData,
+            _out: PhantomData,
         }
     }
+
+    /// 设置协商好的压缩算法，发送时使用它压缩，接收时只接受不压缩或者用它压缩的 frame
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// 设置对端能接收的最大的 frame，超过的 frame 在发送时就会报错
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size.min(MAX_FRAME);
+    }
+
+    /// 设置本地能接收的最大的 frame，对端声称更大的 frame 在分配内存之前就会被拒绝
+    pub fn set_max_recv_frame_size(&mut self, max_frame_size: usize) {
+        self.max_recv_frame_size = max_frame_size.min(MAX_FRAME);
+    }
+
+    /// 设置统计数据，之后收发的每个 frame 都会记录大小
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// 获取底层的 stream，只能在还没有通过 ProstStream 读写之前使用（比如握手）
+    pub(crate) fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
 }
 
 // 一般来说，如果我们的 Stream 是 Unpin，最好实现一下
@@ -55,28 +102,54 @@ where
     type Item = Result<In, KvError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // 上一次调用结束后 rbuf 应该为空
-        assert!(self.rbuf.is_empty());
-
-        // 从 rbuf 中分离出 rest （摆脱对 self 的引用）
-        let mut rest = self.rbuf.split_off(0);
-
-        // 使用 read_frame 来获取数据
-        let fut = read_frame(&mut self.stream, &mut rest);
-        // Box::pin(fut) 会生成 Pin<Box>，可以调用底层的 poll_xxx() 方法
-        // ready! 会在 Pending 时直接 Return Pending，而在 Ready 时，返回 Ready 的值。
-        ready!(Box::pin(fut).poll_unpin(cx))?;
+        // 读到一半的数据保存在 rbuf 里，这样 future 被 drop 之后（比如 select!）也不会丢数据
+        loop {
+            // 如果 rbuf 里已经有一个完整的 frame，直接 decode
+            if self.rbuf.len() >= LEN_LEN {
+                let header = u32::from_be_bytes(self.rbuf[..LEN_LEN].try_into().unwrap());
+                let (len, _) = decode_header(header as usize);
+                // 不能为超过限制的 frame 分配内存，它后面的数据也没法再读了
+                if len > self.max_recv_frame_size {
+                    return Poll::Ready(Some(Err(KvError::FrameError)));
+                }
+                if self.rbuf.len() >= LEN_LEN + len {
+                    let mut frame = self.rbuf.split_to(LEN_LEN + len);
+                    // frame 的边界没有问题，解不出来的话后面的 frame 还可以继续读
+                    // 只接受协商好的压缩算法，解压后的大小同样受 max_recv_frame_size 的限制
+                    let (compression, max_size) = (self.compression, self.max_recv_frame_size);
+                    // frame 是完整的，解压缩或者 protobuf 出错都不影响后面的 frame
+                    let msg = In::decode_frame_with(&mut frame, compression, max_size)
+                        .map_err(|e| KvError::MalformedFrame(e.to_string()));
+                    if let (Some(metrics), Ok(msg)) = (&self.metrics, &msg) {
+                        metrics.record_frame_in(LEN_LEN + len, LEN_LEN + msg.encoded_len());
+                    }
+                    return Poll::Ready(Some(msg));
+                }
 
-        // 拿到一个 frame 的数据，把 buffer 合并回去
-        self.rbuf.unsplit(rest);
+                // 保证有足够的空间放下整个 frame
+                let additional = LEN_LEN + len - self.rbuf.len();
+                self.rbuf.reserve(additional);
+            } else {
+                self.rbuf.reserve(INITIAL_CAPACITY);
+            }
 
-        // 调用 decode_frame 获取解包后的数据
-        Poll::Ready(Some(In::decode_frame(&mut self.rbuf)))
+            // 否则从 stream 里继续读
+            let this = self.as_mut().get_mut();
+            let n = ready!(poll_read_buf(Pin::new(&mut this.stream), cx, &mut this.rbuf))?;
+            if n == 0 {
+                // 对端关闭了连接，如果还有没读完的 frame，说明数据不完整
+                if this.rbuf.is_empty() {
+                    return Poll::Ready(None);
+                }
+                let err = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Poll::Ready(Some(Err(err.into())));
+            }
+        }
     }
 }
 
 /// 当调用 send() 时，会把 Out 发出去
-impl<S, In, Out> Sink<Out> for ProstStream<S, In, Out>
+impl<S, In, Out> Sink<&Out> for ProstStream<S, In, Out>
 where
     S: AsyncRead + AsyncWrite + Unpin,
     In: Unpin + Send,
@@ -86,13 +159,24 @@ where
     type Error = KvError;
 
     // 做背压，可以根据负载来决定是否返回 Poll::Ready
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+    fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
-        item.encode_frame(&mut this.wbuf)?;
+        let start = this.wbuf.len();
+        item.encode_frame_with(&mut this.wbuf, this.compression)?;
+
+        // 对端收不下的 frame 不能发出去，把它从写缓存里去掉
+        if this.wbuf.len() - start - LEN_LEN > this.max_frame_size {
+            this.wbuf.truncate(start);
+            return Err(KvError::FrameError);
+        }
+
+        if let Some(metrics) = &this.metrics {
+            metrics.record_frame_out(this.wbuf.len() - start, LEN_LEN + item.encoded_len());
+        }
 
         Ok(())
     }
@@ -131,15 +215,16 @@ mod tests {
     use super::*;
     use crate::{utils::DummyStream, CommandRequest};
     use anyhow::Result;
+    use bytes::Bytes;
 
     #[allow(clippy::all)]
     #[tokio::test]
     async fn prost_stream_should_work() -> Result<()> {
         let buf = BytesMut::new();
-        let stream = DummyStream { buf };
+        let stream = DummyStream::new(buf);
         let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
         let cmd = CommandRequest::new_hdel("t1", "k1");
-        stream.send(cmd.clone()).await?;
+        stream.send(&cmd).await?;
         if let Some(Ok(s)) = stream.next().await {
             assert_eq!(s, cmd);
         } else {
@@ -148,4 +233,63 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn prost_stream_should_handle_partial_frames() -> Result<()> {
+        let cmd1 = CommandRequest::new_hset("t1", "k1", Bytes::from(vec![1u8; 4096]).into());
+        let cmd2 = CommandRequest::new_hdel("t1", "k1");
+        let mut buf = BytesMut::new();
+        cmd1.encode_frame(&mut buf)?;
+        cmd2.encode_frame(&mut buf)?;
+
+        // 每次只能读到几个字节，frame 需要多次读取才能拼完整
+        let (client, mut server) = tokio::io::duplex(7);
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            server.write_all(&buf).await.unwrap();
+        });
+
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(client);
+        assert_eq!(stream.next().await.unwrap()?, cmd1);
+        assert_eq!(stream.next().await.unwrap()?, cmd2);
+        assert!(stream.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_reject_frame_larger_than_peer_limit() -> Result<()> {
+        let stream = DummyStream::new(BytesMut::new());
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        stream.set_compression(Compression::None);
+        stream.set_max_frame_size(64);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", Bytes::from(vec![1u8; 128]).into());
+        assert!(matches!(stream.send(&cmd).await, Err(KvError::FrameError)));
+
+        // 被拒绝的 frame 不会留在写缓存里，后面的 frame 可以正常收发
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        stream.send(&cmd).await?;
+        assert_eq!(stream.next().await.unwrap()?, cmd);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_reject_frame_larger_than_local_limit() -> Result<()> {
+        use bytes::BufMut;
+
+        // 对端声称要发一个 512M 的 frame
+        let mut buf = BytesMut::new();
+        buf.put_u32(512 * 1024 * 1024);
+        buf.put_slice(&[0u8; 128]);
+        let stream = DummyStream::new(buf);
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(stream);
+        stream.set_max_recv_frame_size(1024);
+
+        assert!(matches!(stream.next().await, Some(Err(KvError::FrameError))));
+        assert!(stream.rbuf.capacity() < 1024 * 1024);
+
+        Ok(())
+    }
 }
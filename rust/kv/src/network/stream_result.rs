@@ -0,0 +1,62 @@
+use futures::{Stream, StreamExt};
+use std::{
+    convert::TryInto,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{CommandResponse, KvError, Kvpair};
+
+/// 遍历类命令（比如 hgetall）的返回结果
+///
+/// 中途出错或者连接在 stream_end 之前断开时，最后一项是错误，调用者据此知道结果不完整
+pub type KvpairStream = Pin<Box<dyn Stream<Item = Result<Kvpair, KvError>> + Send>>;
+
+/// 流式命令（比如 subscribe）的返回结果
+///
+/// 第一个 Response 里是 subscription id，之后是推送的数据，
+/// 收到 stream_end 标记或者连接断开时 stream 结束
+pub struct StreamResult {
+    pub id: u32,
+    inner: Pin<Box<dyn Stream<Item = Result<CommandResponse, KvError>> + Send>>,
+}
+
+impl StreamResult {
+    pub async fn new<T>(mut stream: T) -> Result<Self, KvError>
+    where
+        T: Stream<Item = Result<CommandResponse, KvError>> + Send + Unpin + 'static,
+    {
+        let id = match stream.next().await {
+            Some(Ok(CommandResponse {
+                status: 200,
+                mut values,
+                ..
+            })) if !values.is_empty() => {
+                let id: i64 = values.swap_remove(0).try_into()?;
+                id as u32
+            }
+            Some(Ok(res)) => return Err(KvError::Internal(format!("Invalid stream: {:?}", res))),
+            Some(Err(e)) => return Err(e),
+            None => return Err(KvError::Internal("Didn't get any response".into())),
+        };
+
+        // 遇到结束标记就停下来，结束标记本身不需要交给调用者
+        let inner = stream.take_while(|res| {
+            let end = matches!(res, Ok(CommandResponse { stream_end: true, .. }));
+            futures::future::ready(!end)
+        });
+
+        Ok(StreamResult {
+            id,
+            inner: Box::pin(inner),
+        })
+    }
+}
+
+impl Stream for StreamResult {
+    type Item = Result<CommandResponse, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
@@ -4,6 +4,7 @@ use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
 use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
+use tokio_rustls::rustls::Session;
 use tokio_rustls::webpki::DNSNameRef;
 use tokio_rustls::TlsConnector;
 use tokio_rustls::{
@@ -121,6 +122,14 @@ impl TlsServerAcceptor {
     }
 }
 
+/// 从客户端证书的 subject 里取出 CN 作为客户端的身份，没有客户端证书时返回 None
+pub fn client_identity<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+    let certs = stream.get_ref().1.get_peer_certificates()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&certs.first()?.0).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(|s| s.to_string())
+}
+
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
     let mut cert = Cursor::new(cert);
     pemfile::certs(&mut cert).map_err(|_| KvError::CertifcateParseError("server", "cert"))
@@ -201,6 +210,17 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn client_identity_should_come_from_cert_subject() -> Result<()> {
+        let identity = connect_and_get_identity(Some(CA_CERT), Some((CLIENT_CERT, CLIENT_KEY)));
+        assert_eq!(identity.await?, Some("awesome-device-id".into()));
+
+        // 不要求客户端证书的时候，没有身份
+        assert_eq!(connect_and_get_identity(None, None).await?, None);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn tls_with_bad_domain_should_not_work() -> Result<()> {
         let addr = start_server(None).await?;
@@ -230,6 +250,27 @@ mod tests {
 
         Ok(addr)
     }
-}
 
+    async fn connect_and_get_identity(
+        ca: Option<&str>,
+        identity: Option<(&str, &str)>,
+    ) -> Result<Option<String>> {
+        let acceptor = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, ca)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.write_all(b"ok").await.unwrap();
+            client_identity(&stream)
+        });
 
+        let connector = TlsClientConnector::new("kvserver.acme.inc", identity, Some(CA_CERT))?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).await?;
+
+        Ok(server.await?)
+    }
+}
@@ -2,7 +2,10 @@
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9")]
+    /// 请求 id，同一个连接上可以同时有多个请求，服务器用它来标记对应的响应
+    #[prost(uint32, tag="100")]
+    pub id: u32,
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
@@ -28,6 +31,34 @@ pub mod command_request {
         Hexist(super::Hexist),
         #[prost(message, tag="9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag="10")]
+        Subscribe(super::Subscribe),
+        #[prost(message, tag="11")]
+        Unsubscribe(super::Unsubscribe),
+        #[prost(message, tag="12")]
+        Publish(super::Publish),
+        #[prost(message, tag="13")]
+        Expire(super::Expire),
+        #[prost(message, tag="14")]
+        Ttl(super::Ttl),
+        #[prost(message, tag="15")]
+        Persist(super::Persist),
+        #[prost(message, tag="16")]
+        Hrange(super::Hrange),
+        #[prost(message, tag="17")]
+        Hscan(super::Hscan),
+        #[prost(message, tag="18")]
+        Transaction(super::Transaction),
+        #[prost(message, tag="19")]
+        Hincrby(super::Hincrby),
+        #[prost(message, tag="20")]
+        Hincrbyfloat(super::Hincrbyfloat),
+        #[prost(message, tag="21")]
+        Hcas(super::Hcas),
+        #[prost(message, tag="22")]
+        Auth(super::Auth),
+        #[prost(message, tag="23")]
+        Replicate(super::Replicate),
     }
 }
 /// 服务器的响应
@@ -46,6 +77,30 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 流式的响应（比如 subscribe）结束时，服务器会发送一个 stream_end 为 true 的响应
+    #[prost(bool, tag="5")]
+    pub stream_end: bool,
+    /// 对应请求的 id；服务器解不出请求（malformed frame）时不知道 id，回复的 400 用 0
+    #[prost(uint32, tag="6")]
+    pub id: u32,
+    /// Transaction 里每个命令各自的响应
+    #[prost(message, repeated, tag="7")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// 复制流里要在 replica 上执行的写命令
+    #[prost(message, repeated, tag="8")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+    /// 出错时结构化的错误信息，客户端用它还原 KvError，不需要解析 message
+    #[prost(message, optional, tag="9")]
+    pub error: ::core::option::Option<ErrorDetail>,
+}
+/// 错误的类型和参数，参数的含义由类型决定，比如 NOT_FOUND 的参数是 table 和 key
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ErrorDetail {
+    #[prost(enumeration="ErrorKind", tag="1")]
+    pub kind: i32,
+    #[prost(string, repeated, tag="2")]
+    pub args: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 从 table 中获取一个 key，返回 value
 #[derive(PartialOrd)]
@@ -57,12 +112,27 @@ pub struct Hget {
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中获取所有的 Kvpair
+/// 结果会分成多个 response 返回，最后一个是 stream_end 为 true 的结束标记
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
 }
+/// 从 table 中获取 key 以 prefix 开头，并且在 [start, end) 范围内的 Kvpair
+/// start 或者 end 为空表示不限制。和 Hgetall 一样，结果以多个 response 返回
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub prefix: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub start: ::prost::alloc::string::String,
+    #[prost(string, tag="4")]
+    pub end: ::prost::alloc::string::String,
+}
 /// 从 table 中获取一组 key，返回它们的 value
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -76,7 +146,7 @@ pub struct Hmget {
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5, 6")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -94,8 +164,19 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag="5")]
         Bool(bool),
+        #[prost(message, tag="6")]
+        Encoded(super::Encoded),
     }
 }
+/// 用 serde 序列化的值，content_type 说明用的是哪种格式
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Encoded {
+    #[prost(enumeration="ContentType", tag="1")]
+    pub content_type: i32,
+    #[prost(bytes="bytes", tag="2")]
+    pub data: ::prost::bytes::Bytes,
+}
 /// 返回的 kvpair
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -105,6 +186,21 @@ pub struct Kvpair {
     #[prost(message, optional, tag="2")]
     pub value: ::core::option::Option<Value>,
 }
+/// 按 key 的顺序分页遍历 table，返回 key 以 prefix 开头，并且大于 start_after 的最多 limit 个 Kvpair
+/// start_after 为空表示从头开始，limit 为 0 表示使用缺省值
+/// values 里返回 cursor，作为下一次请求的 start_after；遍历完之后 cursor 为空字符串
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub prefix: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub start_after: ::prost::alloc::string::String,
+    #[prost(uint32, tag="4")]
+    pub limit: u32,
+}
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
 #[derive(PartialOrd)]
@@ -114,6 +210,13 @@ pub struct Hset {
     pub table: ::prost::alloc::string::String,
     #[prost(message, optional, tag="2")]
     pub pair: ::core::option::Option<Kvpair>,
+    /// 过期时间（毫秒），0 表示不过期
+    #[prost(uint64, tag="3")]
+    pub ttl_ms: u64,
+    /// 过期的时间点（unix 时间戳，毫秒），不为 0 时忽略 ttl_ms
+    /// primary 用它把 key 的过期时间同步给 replica，replica 上的过期时间不会因为同步的延迟而推后
+    #[prost(uint64, tag="4")]
+    pub expire_at: u64,
 }
 /// 往 table 里存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
@@ -124,6 +227,9 @@ pub struct Hmset {
     pub table: ::prost::alloc::string::String,
     #[prost(message, repeated, tag="2")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 过期时间（毫秒），0 表示不过期
+    #[prost(uint64, tag="3")]
+    pub ttl_ms: u64,
 }
 /// 从 table 中删除一个 key，返回它之前的值
 #[derive(PartialOrd)]
@@ -161,3 +267,201 @@ pub struct Hmexist {
     #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// subscribe 到某个主题，任何发布到这个主题的数据都会被收到
+/// 成功后，第一个返回的消息里包含 subscription id
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscribe {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+}
+/// 取消对某个主题的订阅
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unsubscribe {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag="2")]
+    pub id: u32,
+}
+/// 发布数据到某个主题
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Publish {
+    #[prost(string, tag="1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub data: ::prost::alloc::vec::Vec<Value>,
+}
+/// 设置 key 的过期时间（毫秒），返回 key 是否存在
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Expire {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub ttl_ms: u64,
+}
+/// 查看 key 剩余的存活时间（毫秒），
+/// key 不存在返回 -2，没有设置过期时间返回 -1
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ttl {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 去掉 key 的过期时间，返回之前是否设置了过期时间
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Persist {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 把 key 的整数 value 加上 delta，key 不存在时从 0 开始，返回新的 value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub delta: i64,
+}
+/// 把 key 的浮点数 value 加上 delta，key 不存在时从 0 开始，返回新的 value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrbyfloat {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag="3")]
+    pub delta: f64,
+}
+/// 如果 key 当前的 value 等于 expected，就把它设置成 new，expected 为空表示 key 必须不存在
+/// 返回两个 value：是否设置成功，以及 key 当前的 value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcas {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(message, optional, tag="4")]
+    pub new: ::core::option::Option<Value>,
+}
+/// 原子地执行一组命令，要么全部成功，要么全部不执行
+/// 目前支持 Hget/Hmget/Hset/Hmset/Hdel/Hmdel/Hexist/Hmexist，不支持设置过期时间
+/// 执行前会检查所有的 watch，任何一个不满足，事务就放弃执行
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Transaction {
+    #[prost(message, repeated, tag="1")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+    #[prost(message, repeated, tag="2")]
+    pub watches: ::prost::alloc::vec::Vec<Watch>,
+}
+/// 乐观锁：事务执行时 key 的值必须等于 value，value 为空表示 key 必须不存在
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 用 token 登录，之后这个连接上的命令都以 token 对应的身份执行
+/// 成功时返回一个 value：登录后的身份
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag="1")]
+    pub token: ::prost::alloc::string::String,
+}
+/// replica 向 primary 订阅数据，只有 primary 支持
+/// 第一个 response 里是 replica 的编号，之后是若干个状态码为 206 的 response，
+/// 里面是当前数据的 snapshot，然后是一个不带命令的 response 表示全量同步结束，
+/// 再之后每个 response 里是 primary 上成功执行的写命令
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {
+}
+/// 连接建立时，客户端和服务器交换的第一个消息
+/// 客户端按优先级列出它支持的压缩算法，服务器从中选择一个自己也支持的返回
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hello {
+    #[prost(enumeration="Compression", repeated, tag="1")]
+    pub compressions: ::prost::alloc::vec::Vec<i32>,
+    /// 协议版本
+    #[prost(uint32, tag="2")]
+    pub version: u32,
+    /// 能接收的最大的 frame，对端发送的 frame 不能超过这个大小
+    #[prost(uint32, tag="3")]
+    pub max_frame_size: u32,
+    /// 支持的功能
+    #[prost(enumeration="Feature", repeated, tag="4")]
+    pub features: ::prost::alloc::vec::Vec<i32>,
+    /// 服务器拒绝握手时，在这里说明原因
+    #[prost(string, tag="5")]
+    pub error: ::prost::alloc::string::String,
+}
+/// 客户端可以还原的错误类型，其它的错误都是 INTERNAL，参数是错误信息
+#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = "snake_case")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ErrorKind {
+    Internal = 0,
+    NotFound = 1,
+    WatchFailed = 2,
+    Unauthenticated = 3,
+    PermissionDenied = 4,
+    InvalidCommand = 5,
+    MalformedFrame = 6,
+    Redirect = 7,
+}
+/// 序列化的格式
+#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = "lowercase")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ContentType {
+    Json = 0,
+    Bincode = 1,
+    Cbor = 2,
+}
+/// 压缩算法
+#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = "lowercase")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Compression {
+    None = 0,
+    Gzip = 1,
+    Zstd = 2,
+    Lz4 = 3,
+}
+/// 协议中可选的功能
+#[derive(serde::Serialize, serde::Deserialize)] #[serde(rename_all = "snake_case")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Feature {
+    Unknown = 0,
+    /// pub/sub
+    Pubsub = 1,
+    /// Hgetall/Hrange 以多个 response 返回
+    StreamingScan = 2,
+    /// Transaction 和 Watch
+    Transaction = 3,
+    /// key 的过期时间
+    Ttl = 4,
+}
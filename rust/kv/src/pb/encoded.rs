@@ -0,0 +1,274 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+
+use super::abi::{value, CommandRequest, ContentType, Encoded, Kvpair, Value};
+use crate::KvError;
+
+/// 用 serde 存取的值，可以作为类型化接口的结果类型，比如 `client.hget::<Serde<User>>(..)`
+///
+/// 反序列化时使用 Value 里记录的格式，所以不管写入时用的是哪种格式都能读出来
+#[derive(Clone, Debug, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl ContentType {
+    /// 格式的名字，用于显示和错误信息
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentType::Json => "json",
+            ContentType::Bincode => "bincode",
+            ContentType::Cbor => "cbor",
+        }
+    }
+
+    fn serialize<T: Serialize + ?Sized>(self, v: &T) -> Result<Vec<u8>, KvError> {
+        let error = |e: String| KvError::SerdeError(self.name(), e);
+        match self {
+            ContentType::Json => serde_json::to_vec(v).map_err(|e| error(e.to_string())),
+            ContentType::Bincode => {
+                bincode_options().serialize(v).map_err(|e| error(e.to_string()))
+            }
+            ContentType::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::ser::into_writer(v, &mut buf).map_err(|e| error(e.to_string()))?;
+                Ok(buf)
+            }
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, KvError> {
+        let error = |e: String| KvError::SerdeError(self.name(), e);
+        match self {
+            ContentType::Json => serde_json::from_slice(data).map_err(|e| error(e.to_string())),
+            // 数据里的长度不可信，最多只能读出 data 那么多的字节，不会因此分配大量内存
+            ContentType::Bincode => bincode_options()
+                .with_limit(data.len() as u64)
+                .deserialize(data)
+                .map_err(|e| error(e.to_string())),
+            ContentType::Cbor => ciborium::de::from_reader(data).map_err(|e| error(e.to_string())),
+        }
+    }
+}
+
+/// 和 bincode::serialize 相同的编码方式（定长整数，允许多余的字节），和已经存下来的数据兼容
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+impl Value {
+    /// 用 content_type 指定的格式把 v 序列化成 Value
+    pub fn from_serialize<T>(v: &T, content_type: ContentType) -> Result<Self, KvError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let encoded = Encoded {
+            content_type: content_type as i32,
+            data: content_type.serialize(v)?.into(),
+        };
+        Ok(Self {
+            value: Some(value::Value::Encoded(encoded)),
+        })
+    }
+
+    /// 按照 Value 里记录的格式反序列化，不是序列化的值时返回 ConvertError
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, KvError> {
+        match &self.value {
+            Some(value::Value::Encoded(encoded)) => {
+                let content_type = ContentType::from_i32(encoded.content_type).ok_or_else(|| {
+                    let msg = format!("unknown content type {}", encoded.content_type);
+                    KvError::SerdeError("unknown", msg)
+                })?;
+                content_type.deserialize(&encoded.data)
+            }
+            _ => Err(KvError::ConvertError(self.clone(), "Encoded")),
+        }
+    }
+}
+
+impl<T: DeserializeOwned> TryFrom<Value> for Serde<T> {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        v.deserialize().map(Serde)
+    }
+}
+
+impl CommandRequest {
+    /// 创建 HSET 命令，value 用 content_type 指定的格式序列化
+    pub fn new_hset_as<T>(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: &T,
+        content_type: ContentType,
+    ) -> Result<Self, KvError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let value = Value::from_serialize(value, content_type)?;
+        Ok(Self::new_hset(table, key, value))
+    }
+
+    /// 创建带过期时间的 HSET 命令，value 用 content_type 指定的格式序列化
+    pub fn new_hset_with_ttl_as<T>(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: &T,
+        content_type: ContentType,
+        ttl: Duration,
+    ) -> Result<Self, KvError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let value = Value::from_serialize(value, content_type)?;
+        Ok(Self::new_hset_with_ttl(table, key, value, ttl))
+    }
+
+    /// 创建 HMSET 命令，所有的 value 都用 content_type 指定的格式序列化
+    pub fn new_hmset_as<'a, K, T>(
+        table: impl Into<String>,
+        pairs: impl IntoIterator<Item = (K, &'a T)>,
+        content_type: ContentType,
+    ) -> Result<Self, KvError>
+    where
+        K: Into<String>,
+        T: Serialize + ?Sized + 'a,
+    {
+        let pairs = pairs
+            .into_iter()
+            .map(|(k, v)| Ok(Kvpair::new(k, Value::from_serialize(v, content_type)?)))
+            .collect::<Result<Vec<_>, KvError>>()?;
+        Ok(Self::new_hmset(table, pairs))
+    }
+
+    /// 创建 HCAS 命令，expected 和 new 都用 content_type 指定的格式序列化
+    ///
+    /// 比较的是序列化之后的结果，所以写入时要用同样的格式
+    pub fn new_hcas_as<T>(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Option<&T>,
+        new: &T,
+        content_type: ContentType,
+    ) -> Result<Self, KvError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let expected = expected
+            .map(|v| Value::from_serialize(v, content_type))
+            .transpose()?;
+        let new = Value::from_serialize(new, content_type)?;
+        Ok(Self::new_hcas(table, key, expected, new))
+    }
+
+    /// 创建 PUBLISH 命令，每条消息都用 content_type 指定的格式序列化
+    pub fn new_publish_as<T>(
+        topic: impl Into<String>,
+        data: &[T],
+        content_type: ContentType,
+    ) -> Result<Self, KvError>
+    where
+        T: Serialize,
+    {
+        let data = data
+            .iter()
+            .map(|v| Value::from_serialize(v, content_type))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new_publish(topic, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{command_request::RequestData, CommandService, MemTable, Storage};
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct User {
+        name: String,
+        age: u8,
+        tags: Vec<String>,
+        extra: BTreeMap<String, f64>,
+    }
+
+    fn user() -> User {
+        User {
+            name: "Tyr".into(),
+            age: 42,
+            tags: vec!["admin".into(), "dev".into()],
+            extra: [("score".to_string(), 9.5)].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn values_should_roundtrip_with_every_content_type() {
+        for content_type in [ContentType::Json, ContentType::Bincode, ContentType::Cbor] {
+            let v = Value::from_serialize(&user(), content_type).unwrap();
+            match &v.value {
+                Some(value::Value::Encoded(e)) => assert_eq!(e.content_type, content_type as i32),
+                _ => panic!("expect encoded value"),
+            }
+            assert_eq!(v.deserialize::<User>().unwrap(), user());
+            let Serde(u): Serde<User> = v.try_into().unwrap();
+            assert_eq!(u, user());
+        }
+
+        let v = Value::from_serialize(&user(), ContentType::Json).unwrap();
+        match v.value {
+            Some(value::Value::Encoded(e)) => {
+                let json: serde_json::Value = serde_json::from_slice(&e.data).unwrap();
+                assert_eq!(json["name"], "Tyr");
+            }
+            _ => panic!("expect encoded value"),
+        }
+    }
+
+    #[test]
+    fn deserialize_should_fail_for_wrong_values() {
+        let res = Value::from("hello").deserialize::<User>();
+        assert!(matches!(res, Err(KvError::ConvertError(_, "Encoded"))));
+
+        let v = Value::from_serialize(&42, ContentType::Cbor).unwrap();
+        assert!(matches!(v.deserialize::<User>(), Err(KvError::SerdeError("cbor", _))));
+
+        // 声称有 u64::MAX 个元素的 Vec 不会让我们分配内存
+        let encoded = Encoded {
+            content_type: ContentType::Bincode as i32,
+            data: u64::MAX.to_le_bytes().to_vec().into(),
+        };
+        let v = Value {
+            value: Some(value::Value::Encoded(encoded)),
+        };
+        assert!(matches!(v.deserialize::<Vec<u64>>(), Err(KvError::SerdeError("bincode", _))));
+    }
+
+    #[test]
+    fn bincode_should_stay_compatible_with_default_encoding() {
+        let data = bincode::serialize(&user()).unwrap();
+        assert_eq!(ContentType::Bincode.serialize(&user()).unwrap(), data);
+        assert_eq!(ContentType::Bincode.deserialize::<User>(&data).unwrap(), user());
+    }
+
+    #[test]
+    fn encoded_values_should_survive_storage() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hset_as("t1", "u1", &user(), ContentType::Bincode).unwrap();
+        match cmd.request_data {
+            Some(RequestData::Hset(hset)) => hset.execute(&store),
+            _ => panic!("expect hset"),
+        };
+        let v = store.get("t1", "u1").unwrap().unwrap();
+        assert_eq!(v.deserialize::<User>().unwrap(), user());
+
+        let pairs = [("u2", &user()), ("u3", &user())];
+        let cmd = CommandRequest::new_hmset_as("t1", pairs, ContentType::Json).unwrap();
+        match cmd.request_data {
+            Some(RequestData::Hmset(hmset)) => assert_eq!(hmset.pairs.len(), 2),
+            _ => panic!("expect hmset"),
+        };
+    }
+}
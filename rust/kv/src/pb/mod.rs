@@ -1,6 +1,9 @@
 pub mod abi;
+mod encoded;
 
-use std::convert::TryFrom;
+pub use encoded::Serde;
+
+use std::{convert::TryFrom, time::Duration};
 
 use abi::{command_request::RequestData, *};
 use bytes::Bytes;
@@ -16,17 +19,56 @@ impl CommandRequest {
             request_data: Some(RequestData::Hset(Hset {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
+                ..Default::default()
             })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建带过期时间的 HSET 命令
+    pub fn new_hset_with_ttl(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        ttl: Duration,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+                ttl_ms: ttl.as_millis() as _,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建在 expire_at（unix 时间戳，毫秒）过期的 HSET 命令，expire_at 为 None 表示不过期
+    pub fn new_hset_with_expire_at(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        expire_at: Option<u64>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+                expire_at: expire_at.unwrap_or_default(),
+                ..Default::default()
+            })),
+            ..Default::default()
         }
     }
 
     /// 创建 HGET 命令
     pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
-        Self { 
+        Self {
             request_data: Some(RequestData::Hget(Hget {
                 table: table.into(),
                 key: key.into(),
-            })) 
+            })),
+            ..Default::default()
         }
     }
 
@@ -36,6 +78,43 @@ impl CommandRequest {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into()
             })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 HRANGE 命令，start 或者 end 为空表示不限制
+    pub fn new_hrange(
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+        start: impl Into<String>,
+        end: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hrange(Hrange {
+                table: table.into(),
+                prefix: prefix.into(),
+                start: start.into(),
+                end: end.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 HSCAN 命令，start_after 为空表示从头开始
+    pub fn new_hscan(
+        table: impl Into<String>,
+        prefix: impl Into<String>,
+        start_after: impl Into<String>,
+        limit: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hscan(Hscan {
+                table: table.into(),
+                prefix: prefix.into(),
+                start_after: start_after.into(),
+                limit,
+            })),
+            ..Default::default()
         }
     }
 
@@ -45,6 +124,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -53,17 +133,32 @@ impl CommandRequest {
             request_data: Some(RequestData::Hmset(Hmset {
                 table: table.into(),
                 pairs,
+                ..Default::default()
             })),
+            ..Default::default()
         }
     }
 
 
+    /// 创建带过期时间的 HMSET 命令
+    pub fn new_hmset_with_ttl(table: impl Into<String>, pairs: Vec<Kvpair>, ttl: Duration) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+                ttl_ms: ttl.as_millis() as _,
+            })),
+            ..Default::default()
+        }
+    }
+
     pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
         Self {
             request_data: Some(RequestData::Hdel(Hdel {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -73,6 +168,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -82,6 +178,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -91,6 +188,242 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 EXPIRE 命令
+    pub fn new_expire(table: impl Into<String>, key: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            request_data: Some(RequestData::Expire(Expire {
+                table: table.into(),
+                key: key.into(),
+                ttl_ms: ttl.as_millis() as _,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 TTL 命令
+    pub fn new_ttl(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Ttl(Ttl {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 PERSIST 命令
+    pub fn new_persist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Persist(Persist {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 SUBSCRIBE 命令
+    pub fn new_subscribe(topic: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 UNSUBSCRIBE 命令
+    pub fn new_unsubscribe(topic: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
+                topic: topic.into(),
+                id,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 PUBLISH 命令
+    pub fn new_publish(topic: impl Into<String>, data: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                topic: topic.into(),
+                data,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 HINCRBY 命令
+    pub fn new_hincrby(table: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrby(Hincrby {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 HINCRBYFLOAT 命令
+    pub fn new_hincrbyfloat(table: impl Into<String>, key: impl Into<String>, delta: f64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrbyfloat(Hincrbyfloat {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 HCAS 命令，expected 为 None 表示 key 必须不存在
+    pub fn new_hcas(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Option<Value>,
+        new: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcas(Hcas {
+                table: table.into(),
+                key: key.into(),
+                expected,
+                new: Some(new),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 TRANSACTION 命令
+    pub fn new_transaction(commands: Vec<CommandRequest>, watches: Vec<Watch>) -> Self {
+        Self {
+            request_data: Some(RequestData::Transaction(Transaction { commands, watches })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 AUTH 命令
+    pub fn new_auth(token: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                token: token.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 REPLICATE 命令
+    pub fn new_replicate() -> Self {
+        Self {
+            request_data: Some(RequestData::Replicate(Replicate {})),
+            ..Default::default()
+        }
+    }
+}
+
+impl CommandRequest {
+    /// 是否是 pub/sub 的命令，这些命令由 broadcaster 处理，不经过 store
+    pub fn is_pubsub(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Subscribe(_))
+                | Some(RequestData::Unsubscribe(_))
+                | Some(RequestData::Publish(_))
+        )
+    }
+
+    /// 是否是遍历 table 的命令，这些命令会返回多个 Response，最后是 stream_end
+    pub fn is_scan(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Hgetall(_)) | Some(RequestData::Hrange(_))
+        )
+    }
+
+    /// 是否是只读的命令，这些命令执行多次的结果和执行一次一样，连接出错时可以放心地重试
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Hget(_))
+                | Some(RequestData::Hmget(_))
+                | Some(RequestData::Hgetall(_))
+                | Some(RequestData::Hexist(_))
+                | Some(RequestData::Hmexist(_))
+                | Some(RequestData::Ttl(_))
+                | Some(RequestData::Hrange(_))
+                | Some(RequestData::Hscan(_))
+        )
+    }
+
+    /// 是否是修改数据的命令，replica 上不能执行这些命令
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Hset(_))
+                | Some(RequestData::Hmset(_))
+                | Some(RequestData::Hdel(_))
+                | Some(RequestData::Hmdel(_))
+                | Some(RequestData::Expire(_))
+                | Some(RequestData::Persist(_))
+                | Some(RequestData::Transaction(_))
+                | Some(RequestData::Hincrby(_))
+                | Some(RequestData::Hincrbyfloat(_))
+                | Some(RequestData::Hcas(_))
+        )
+    }
+
+    /// 命令的名字，用于日志和统计
+    pub fn name(&self) -> &'static str {
+        match &self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::Expire(_)) => "expire",
+            Some(RequestData::Ttl(_)) => "ttl",
+            Some(RequestData::Persist(_)) => "persist",
+            Some(RequestData::Hrange(_)) => "hrange",
+            Some(RequestData::Hscan(_)) => "hscan",
+            Some(RequestData::Transaction(_)) => "transaction",
+            Some(RequestData::Hincrby(_)) => "hincrby",
+            Some(RequestData::Hincrbyfloat(_)) => "hincrbyfloat",
+            Some(RequestData::Hcas(_)) => "hcas",
+            Some(RequestData::Auth(_)) => "auth",
+            Some(RequestData::Replicate(_)) => "replicate",
+            None => "unknown",
+        }
+    }
+}
+
+impl CommandResponse {
+    /// 不带任何数据的成功响应
+    pub fn ok() -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            ..Default::default()
+        }
+    }
+
+    /// 流式响应结束的标记
+    pub fn stream_end() -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            stream_end: true,
+            ..Default::default()
         }
     }
 }
@@ -105,6 +438,44 @@ impl Kvpair {
     }
 }
 
+impl Hello {
+    /// 创建握手消息，compressions 按优先级排列
+    pub fn new(
+        version: u32,
+        compressions: &[Compression],
+        max_frame_size: u32,
+        features: &[Feature],
+    ) -> Self {
+        Self {
+            version,
+            compressions: compressions.iter().map(|c| *c as i32).collect(),
+            max_frame_size,
+            features: features.iter().map(|f| *f as i32).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// 创建拒绝握手的消息
+    pub fn rejected(version: u32, error: impl Into<String>) -> Self {
+        Self {
+            version,
+            error: error.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Watch {
+    /// 创建一个 watch，value 为 None 表示 key 必须不存在
+    pub fn new(table: impl Into<String>, key: impl Into<String>, value: Option<Value>) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            value,
+        }
+    }
+}
+
 /// 从 String 转换成 Value
 impl From<String> for Value {
     fn from(s: String) -> Self {
@@ -157,20 +528,85 @@ impl From<Vec<Kvpair>> for CommandResponse {
 /// 从 KvError 转换成 CommandResponse
 impl From<KvError> for CommandResponse {
     fn from(e: KvError) -> Self {
-        let mut result = Self {
-            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
-            message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+        let message = e.to_string();
+        let (status, kind, args) = match e {
+            KvError::NotFound(table, key) => {
+                (StatusCode::NOT_FOUND, ErrorKind::NotFound, vec![table, key])
+            }
+            KvError::InvalidCommand(msg) => {
+                (StatusCode::BAD_REQUEST, ErrorKind::InvalidCommand, vec![msg])
+            }
+            KvError::MalformedFrame(msg) => {
+                (StatusCode::BAD_REQUEST, ErrorKind::MalformedFrame, vec![msg])
+            }
+            KvError::WatchFailed(table, key) => {
+                (StatusCode::CONFLICT, ErrorKind::WatchFailed, vec![table, key])
+            }
+            KvError::Unauthenticated(msg) => {
+                (StatusCode::UNAUTHORIZED, ErrorKind::Unauthenticated, vec![msg])
+            }
+            KvError::PermissionDenied(identity, access, table) => {
+                let args = vec![identity, access.into(), table];
+                (StatusCode::FORBIDDEN, ErrorKind::PermissionDenied, args)
+            }
+            KvError::Redirect(addr) => {
+                (StatusCode::TEMPORARY_REDIRECT, ErrorKind::Redirect, vec![addr])
+            }
+            KvError::Internal(msg) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, ErrorKind::Internal, vec![msg])
+            }
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, ErrorKind::Internal, vec![message.clone()]),
         };
 
-        match e {
-            KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
-            _ => {}
+        Self {
+            status: status.as_u16() as _,
+            message,
+            error: Some(ErrorDetail {
+                kind: kind as i32,
+                args,
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+impl CommandResponse {
+    /// 把非 2xx 的 response 转换回服务器端的 KvError，和上面的 From<KvError> 相反
+    ///
+    /// 没有结构化错误信息的 response 算作 Internal，参数是 message
+    pub fn into_result(self) -> Result<Self, KvError> {
+        if (200..300).contains(&self.status) {
+            return Ok(self);
         }
+        let error = self.error.and_then(|detail| {
+            let kind = ErrorKind::from_i32(detail.kind)?;
+            detail.into_error(kind)
+        });
+        Err(error.unwrap_or(KvError::Internal(self.message)))
+    }
+}
 
-        result
+impl ErrorDetail {
+    /// 参数的个数不对时返回 None
+    fn into_error(self, kind: ErrorKind) -> Option<KvError> {
+        let mut args = self.args.into_iter();
+        let mut next = || args.next();
+        let error = match kind {
+            ErrorKind::Internal => KvError::Internal(next()?),
+            ErrorKind::NotFound => KvError::NotFound(next()?, next()?),
+            ErrorKind::WatchFailed => KvError::WatchFailed(next()?, next()?),
+            ErrorKind::Unauthenticated => KvError::Unauthenticated(next()?),
+            ErrorKind::PermissionDenied => {
+                let identity = next()?;
+                let access = next()?;
+                let access = ["read", "write"].into_iter().find(|a| *a == access)?;
+                KvError::PermissionDenied(identity, access, next()?)
+            }
+            ErrorKind::InvalidCommand => KvError::InvalidCommand(next()?),
+            ErrorKind::MalformedFrame => KvError::MalformedFrame(next()?),
+            ErrorKind::Redirect => KvError::Redirect(next()?),
+        };
+        Some(error)
     }
 }
 
@@ -236,6 +672,17 @@ impl TryFrom<Value> for f64 {
     }
 }
 
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v, "String")),
+        }
+    }
+}
+
 impl TryFrom<Value> for Bytes {
     type Error = KvError;
 
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use crate::{command_request::RequestData, AuthConfig, CommandRequest, KvError, TableAcl};
+
+/// ACL 里表示所有通过认证的身份，或者所有没有单独配置的 table
+const WILDCARD: &str = "*";
+
+/// 对 table 的访问类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// 根据 AuthConfig 做认证和授权
+///
+/// 没有配置 ACL 的 table 不做限制；pub/sub 的 topic 不是 table，也不做限制
+#[derive(Clone, Debug, Default)]
+pub struct Authorizer {
+    tokens: HashMap<String, String>,
+    tables: HashMap<String, TableAcl>,
+}
+
+impl Access {
+    fn as_str(self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+        }
+    }
+}
+
+impl Authorizer {
+    /// 用 token 换取身份
+    pub fn authenticate(&self, token: &str) -> Result<String, KvError> {
+        self.tokens
+            .get(token)
+            .cloned()
+            .ok_or_else(|| KvError::Unauthenticated("invalid token".into()))
+    }
+
+    /// 检查 identity 是否能执行 cmd，identity 为 None 表示匿名
+    pub fn authorize(&self, cmd: &CommandRequest, identity: Option<&str>) -> Result<(), KvError> {
+        // replica 会拿到所有 table 的数据，需要能读每一个配置了 ACL 的 table
+        if let Some(RequestData::Replicate(_)) = &cmd.request_data {
+            for table in self.tables.keys() {
+                self.check(table, Access::Read, identity)?;
+            }
+            return Ok(());
+        }
+        for (table, access) in accesses(cmd) {
+            self.check(table, access, identity)?;
+        }
+        Ok(())
+    }
+
+    fn check(&self, table: &str, access: Access, identity: Option<&str>) -> Result<(), KvError> {
+        let acl = match self.tables.get(table).or_else(|| self.tables.get(WILDCARD)) {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+
+        let identity = identity.ok_or_else(|| {
+            KvError::Unauthenticated(format!("table {} requires authentication", table))
+        })?;
+
+        // 能写的身份也能读
+        let allowed = match access {
+            Access::Read => acl.read.iter().chain(acl.write.iter()).collect::<Vec<_>>(),
+            Access::Write => acl.write.iter().collect(),
+        };
+        if allowed.iter().any(|id| *id == WILDCARD || *id == identity) {
+            Ok(())
+        } else {
+            Err(KvError::PermissionDenied(identity.into(), access.as_str(), table.into()))
+        }
+    }
+}
+
+impl From<AuthConfig> for Authorizer {
+    fn from(config: AuthConfig) -> Self {
+        Self {
+            tokens: config.tokens,
+            tables: config.tables,
+        }
+    }
+}
+
+/// 命令访问了哪些 table，以及访问的类型
+fn accesses(cmd: &CommandRequest) -> Vec<(&str, Access)> {
+    let request_data = match &cmd.request_data {
+        Some(data) => data,
+        None => return Vec::new(),
+    };
+
+    let access = match request_data {
+        RequestData::Hget(v) => (v.table.as_str(), Access::Read),
+        RequestData::Hgetall(v) => (v.table.as_str(), Access::Read),
+        RequestData::Hmget(v) => (v.table.as_str(), Access::Read),
+        RequestData::Hexist(v) => (v.table.as_str(), Access::Read),
+        RequestData::Hmexist(v) => (v.table.as_str(), Access::Read),
+        RequestData::Ttl(v) => (v.table.as_str(), Access::Read),
+        RequestData::Hrange(v) => (v.table.as_str(), Access::Read),
+        RequestData::Hscan(v) => (v.table.as_str(), Access::Read),
+        RequestData::Hset(v) => (v.table.as_str(), Access::Write),
+        RequestData::Hmset(v) => (v.table.as_str(), Access::Write),
+        RequestData::Hdel(v) => (v.table.as_str(), Access::Write),
+        RequestData::Hmdel(v) => (v.table.as_str(), Access::Write),
+        RequestData::Expire(v) => (v.table.as_str(), Access::Write),
+        RequestData::Persist(v) => (v.table.as_str(), Access::Write),
+        RequestData::Hincrby(v) => (v.table.as_str(), Access::Write),
+        RequestData::Hincrbyfloat(v) => (v.table.as_str(), Access::Write),
+        RequestData::Hcas(v) => (v.table.as_str(), Access::Write),
+        // 事务需要它包含的所有命令的权限，watch 需要读权限
+        RequestData::Transaction(v) => {
+            let mut result: Vec<_> = v.commands.iter().flat_map(accesses).collect();
+            result.extend(v.watches.iter().map(|w| (w.table.as_str(), Access::Read)));
+            return result;
+        }
+        RequestData::Subscribe(_)
+        | RequestData::Unsubscribe(_)
+        | RequestData::Publish(_)
+        | RequestData::Auth(_)
+        | RequestData::Replicate(_) => return Vec::new(),
+    };
+    vec![access]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Watch;
+
+    fn authorizer() -> Authorizer {
+        let mut config = AuthConfig::default();
+        config.tokens.insert("secret".into(), "alice".into());
+        let acl = TableAcl {
+            read: vec![WILDCARD.into()],
+            write: vec!["alice".into()],
+        };
+        config.tables.insert("users".into(), acl);
+        config.tables.insert("admin".into(), TableAcl::default());
+        config.into()
+    }
+
+    #[test]
+    fn authenticate_should_map_token_to_identity() {
+        let auth = authorizer();
+        assert_eq!(auth.authenticate("secret").unwrap(), "alice");
+        assert!(matches!(auth.authenticate("bad"), Err(KvError::Unauthenticated(_))));
+    }
+
+    #[test]
+    fn authorize_should_check_table_acl() {
+        let auth = authorizer();
+        let read = CommandRequest::new_hget("users", "k1");
+        let write = CommandRequest::new_hset("users", "k1", "v1".into());
+
+        // 匿名访问有 ACL 的 table
+        let result = auth.authorize(&read, None);
+        assert!(matches!(result, Err(KvError::Unauthenticated(_))));
+
+        // 任何通过认证的身份都可以读，只有 alice 可以写
+        assert!(auth.authorize(&read, Some("bob")).is_ok());
+        let result = auth.authorize(&write, Some("bob"));
+        assert!(matches!(result, Err(KvError::PermissionDenied(..))));
+        assert!(auth.authorize(&write, Some("alice")).is_ok());
+
+        // 没有任何人有权限的 table
+        let result = auth.authorize(&CommandRequest::new_hget("admin", "k1"), Some("alice"));
+        assert!(matches!(result, Err(KvError::PermissionDenied(..))));
+
+        // 没有配置 ACL 的 table 不做限制
+        assert!(auth.authorize(&CommandRequest::new_hget("t1", "k1"), None).is_ok());
+    }
+
+    #[test]
+    fn wildcard_table_should_apply_to_unlisted_tables() {
+        let mut config = AuthConfig::default();
+        let acl = TableAcl {
+            read: vec!["bob".into()],
+            write: vec![],
+        };
+        config.tables.insert(WILDCARD.into(), acl);
+        let auth: Authorizer = config.into();
+
+        assert!(auth.authorize(&CommandRequest::new_hget("t1", "k1"), Some("bob")).is_ok());
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        assert!(matches!(auth.authorize(&cmd, Some("bob")), Err(KvError::PermissionDenied(..))));
+    }
+
+    #[test]
+    fn transaction_should_require_access_to_all_tables() {
+        let auth = authorizer();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hget("users", "k1"),
+        ];
+        let cmd = CommandRequest::new_transaction(cmds, vec![]);
+        assert!(auth.authorize(&cmd, Some("bob")).is_ok());
+
+        let watches = vec![Watch::new("admin", "k1", None)];
+        let cmd = CommandRequest::new_transaction(vec![], watches);
+        assert!(matches!(auth.authorize(&cmd, Some("bob")), Err(KvError::PermissionDenied(..))));
+    }
+
+    #[test]
+    fn replicate_should_require_read_access_to_all_tables() {
+        let cmd = CommandRequest::new_replicate();
+        assert!(Authorizer::default().authorize(&cmd, None).is_ok());
+
+        let mut config = AuthConfig::default();
+        let acl = TableAcl {
+            read: vec!["replica".into()],
+            write: vec!["alice".into()],
+        };
+        config.tables.insert("users".into(), acl);
+        config.tables.insert(WILDCARD.into(), TableAcl {
+            read: vec![WILDCARD.into()],
+            write: vec![],
+        });
+        let auth: Authorizer = config.into();
+        assert!(matches!(auth.authorize(&cmd, None), Err(KvError::Unauthenticated(_))));
+        assert!(matches!(auth.authorize(&cmd, Some("bob")), Err(KvError::PermissionDenied(..))));
+        assert!(auth.authorize(&cmd, Some("replica")).is_ok());
+        assert!(auth.authorize(&cmd, Some("alice")).is_ok());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::*;
+use crate::{command_request::RequestData, storage::now_ms, *};
 
 impl CommandService for Hget {
     fn execute(self, store: &impl Storage) -> CommandResponse {
@@ -10,10 +10,44 @@ impl CommandService for Hget {
     }
 }
 
-impl CommandService for Hgetall {
+impl CommandService for Hmget {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.get_all(&self.table) {
-            Ok(v) => v.into(),
+        // 不存在的 key 返回 Value::default()，保证 values 和 keys 一一对应
+        self.keys
+            .iter()
+            .map(|key| match store.get(&self.table, key) {
+                Ok(Some(v)) => Ok(v),
+                Ok(None) => Ok(Value::default()),
+                Err(e) => Err(e),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(|e| e.into(), |v| v.into())
+    }
+}
+
+/// Hscan 没有指定 limit 时，每页返回的 kv pair 数量
+const DEFAULT_SCAN_LIMIT: usize = 100;
+
+impl CommandService for Hscan {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let limit = match self.limit {
+            0 => DEFAULT_SCAN_LIMIT,
+            n => n as usize,
+        };
+        let start_after = Some(self.start_after.as_str()).filter(|s| !s.is_empty());
+        // 多取一个，用来判断后面还有没有数据
+        match store.scan(&self.table, &self.prefix, start_after, limit + 1) {
+            Ok(mut pairs) => {
+                let cursor = if pairs.len() > limit {
+                    pairs.truncate(limit);
+                    pairs.last().map(|pair| pair.key.clone()).unwrap_or_default()
+                } else {
+                    String::new()
+                };
+                let mut res: CommandResponse = pairs.into();
+                res.values.push(cursor.into());
+                res
+            }
             Err(e) => e.into(),
         }
     }
@@ -21,8 +55,12 @@ impl CommandService for Hgetall {
 
 impl CommandService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
+        let expire_at = match self.expire_at() {
+            Ok(expire_at) => expire_at,
+            Err(e) => return e.into(),
+        };
         match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+            Some(v) => match set_pair(store, &self.table, v, expire_at) {
                 Ok(Some(v)) => v.into(),
                 Ok(None) => Value::default().into(),
                 Err(e) => e.into(),
@@ -32,87 +70,863 @@ impl CommandService for Hset {
     }
 }
 
+impl CommandService for Hmset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        // 返回每个 key 之前的值，之前不存在的 key 返回 Value::default()
+        let expire_at = match expire_after(self.ttl_ms) {
+            Ok(expire_at) => expire_at,
+            Err(e) => return e.into(),
+        };
+        let table = self.table;
+        self.pairs
+            .into_iter()
+            .map(|pair| set_pair(store, &table, pair, expire_at).map(|v| v.unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(|e| e.into(), |v| v.into())
+    }
+}
+
+impl CommandService for Hdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.del(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| store.del(&self.table, key).map(|v| v.unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(|e| e.into(), |v| v.into())
+    }
+}
+
+impl CommandService for Hexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| store.contains(&self.table, key).map(Value::from))
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(|e| e.into(), |v| v.into())
+    }
+}
+
+impl CommandService for Expire {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let expire_at = match deadline(self.ttl_ms) {
+            Ok(expire_at) => expire_at,
+            Err(e) => return e.into(),
+        };
+        match store.expire(&self.table, &self.key, Some(expire_at)) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Ttl {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let ttl = || -> Result<i64, KvError> {
+            // 和 redis 一样：key 不存在返回 -2，没有过期时间返回 -1
+            if !store.contains(&self.table, &self.key)? {
+                return Ok(-2);
+            }
+            Ok(match store.expire_at(&self.table, &self.key)? {
+                Some(at) => at.saturating_sub(now_ms()) as i64,
+                None => -1,
+            })
+        };
+        match ttl() {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Persist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let persist = || -> Result<bool, KvError> {
+            if store.expire_at(&self.table, &self.key)?.is_none() {
+                return Ok(false);
+            }
+            store.expire(&self.table, &self.key, None)
+        };
+        match persist() {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrby {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr(&self.table, &self.key, self.delta) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrbyfloat {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr_float(&self.table, &self.key, self.delta) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hcas {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let new = self.new.unwrap_or_default();
+        match store.cas(&self.table, &self.key, self.expected.as_ref(), new) {
+            Ok((swapped, current)) => vec![swapped.into(), current.unwrap_or_default()].into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Transaction {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let plans = self.commands.into_iter().map(into_txn_ops).collect::<Result<Vec<_>, _>>();
+        let plans = match plans {
+            Ok(v) => v,
+            Err(e) => return e.into(),
+        };
+        let (ops, responders): (Vec<_>, Vec<_>) = plans.into_iter().unzip();
+        let counts: Vec<_> = ops.iter().map(Vec::len).collect();
+        let ops: Vec<_> = ops.into_iter().flatten().collect();
+
+        match store.transaction(&self.watches, &ops) {
+            Ok(values) => {
+                // 按每个命令的操作数量，把结果分回给对应的命令
+                let mut values = values.into_iter();
+                let responses = counts
+                    .into_iter()
+                    .zip(responders)
+                    .map(|(n, responder)| responder(values.by_ref().take(n).collect()))
+                    .collect();
+                CommandResponse {
+                    responses,
+                    ..CommandResponse::ok()
+                }
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// 根据事务里操作的结果，生成命令的 Response
+type TxnResponder = Box<dyn FnOnce(Vec<Option<Value>>) -> CommandResponse>;
+
+/// 把事务里的命令转换成存储的操作
+fn into_txn_ops(cmd: CommandRequest) -> Result<(Vec<TxnOp>, TxnResponder), KvError> {
+    let get = |table: &String, key| TxnOp::Get { table: table.clone(), key };
+    let del = |table: &String, key| TxnOp::Del { table: table.clone(), key };
+    let set = |table: &String, pair: Kvpair| TxnOp::Set {
+        table: table.clone(),
+        key: pair.key,
+        value: pair.value.unwrap_or_default(),
+    };
+
+    let plan: (Vec<TxnOp>, TxnResponder) = match cmd.request_data {
+        Some(RequestData::Hget(Hget { table, key })) => {
+            let ops = vec![get(&table, key.clone())];
+            let responder = move |mut v: Vec<Option<Value>>| match v.pop().flatten() {
+                Some(v) => v.into(),
+                None => KvError::NotFound(table, key).into(),
+            };
+            (ops, Box::new(responder))
+        }
+        Some(RequestData::Hmget(Hmget { table, keys })) => {
+            let ops = keys.into_iter().map(|key| get(&table, key)).collect();
+            (ops, Box::new(all_or_default))
+        }
+        Some(RequestData::Hexist(Hexist { table, key })) => {
+            (vec![get(&table, key)], Box::new(all_exist))
+        }
+        Some(RequestData::Hmexist(Hmexist { table, keys })) => {
+            let ops = keys.into_iter().map(|key| get(&table, key)).collect();
+            (ops, Box::new(all_exist))
+        }
+        Some(RequestData::Hset(Hset { ttl_ms, expire_at, .. })) if ttl_ms > 0 || expire_at > 0 => {
+            return Err(KvError::InvalidCommand("TTL is not supported in transaction".into()));
+        }
+        Some(RequestData::Hmset(Hmset { ttl_ms, .. })) if ttl_ms > 0 => {
+            return Err(KvError::InvalidCommand("TTL is not supported in transaction".into()));
+        }
+        Some(RequestData::Hset(Hset { table, pair, .. })) => {
+            let ops = pair.into_iter().map(|pair| set(&table, pair)).collect();
+            (ops, Box::new(first_or_default))
+        }
+        Some(RequestData::Hmset(Hmset { table, pairs, .. })) => {
+            let ops = pairs.into_iter().map(|pair| set(&table, pair)).collect();
+            (ops, Box::new(all_or_default))
+        }
+        Some(RequestData::Hdel(Hdel { table, key })) => {
+            (vec![del(&table, key)], Box::new(first_or_default))
+        }
+        Some(RequestData::Hmdel(Hmdel { table, keys })) => {
+            let ops = keys.into_iter().map(|key| del(&table, key)).collect();
+            (ops, Box::new(all_or_default))
+        }
+        data => {
+            let msg = format!("Command is not supported in transaction: {:?}", data);
+            return Err(KvError::InvalidCommand(msg));
+        }
+    };
+    Ok(plan)
+}
+
+fn first_or_default(values: Vec<Option<Value>>) -> CommandResponse {
+    values.into_iter().next().flatten().unwrap_or_default().into()
+}
+
+fn all_or_default(values: Vec<Option<Value>>) -> CommandResponse {
+    let values: Vec<_> = values.into_iter().map(Option::unwrap_or_default).collect();
+    values.into()
+}
+
+fn all_exist(values: Vec<Option<Value>>) -> CommandResponse {
+    let values: Vec<_> = values.iter().map(|v| Value::from(v.is_some())).collect();
+    values.into()
+}
+
+impl Hset {
+    /// 过期的时间点：优先使用 expire_at，其次是 ttl_ms，都为 0 表示不过期
+    fn expire_at(&self) -> Result<Option<u64>, KvError> {
+        match self.expire_at {
+            0 => expire_after(self.ttl_ms),
+            at => Ok(Some(at)),
+        }
+    }
+}
+
+/// ttl_ms 之后的时间点，ttl_ms 为 0 表示不过期
+fn expire_after(ttl_ms: u64) -> Result<Option<u64>, KvError> {
+    match ttl_ms {
+        0 => Ok(None),
+        ttl_ms => deadline(ttl_ms).map(Some),
+    }
+}
+
+/// ttl_ms 之后的时间点，客户端给的 ttl_ms 太大、时间点溢出时返回错误
+fn deadline(ttl_ms: u64) -> Result<u64, KvError> {
+    now_ms()
+        .checked_add(ttl_ms)
+        .ok_or_else(|| KvError::InvalidCommand(format!("ttl {}ms is too large", ttl_ms)))
+}
+
+/// 写入一个 kv pair，同时设置过期时间
+fn set_pair(
+    store: &impl Storage,
+    table: &str,
+    pair: Kvpair,
+    expire_at: Option<u64>,
+) -> Result<Option<Value>, KvError> {
+    store.set_with_expire(table, pair.key, pair.value.unwrap_or_default(), expire_at)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::command_request::RequestData;
+    use futures::{executor::block_on, StreamExt};
+    use std::{convert::TryInto, thread, time::Duration};
+    use tempfile::{tempdir, TempDir};
+
+    /// 每个测试都分别在 MemTable 和 SledDb 上跑一遍
+    fn with_stores(f: impl Fn(&dyn Fn(CommandRequest) -> CommandResponse)) {
+        let store = MemTable::new();
+        f(&|cmd| run_cmd(cmd, &store));
+
+        let (_dir, store) = sled_store();
+        f(&|cmd| run_cmd(cmd, &store));
+    }
+
+    /// 遍历类的命令会返回多个 Response，这里把它们合并成一个，方便比较
+    fn run_cmd(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+        if !cmd.is_scan() {
+            return dispatch(cmd, store);
+        }
+        let responses: Vec<_> = block_on(dispatch_scan(cmd, store).collect());
+        let pairs = responses.iter().flat_map(|res| res.pairs.clone()).collect::<Vec<_>>();
+        pairs.into()
+    }
+
+    fn sled_store() -> (TempDir, SledDb) {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        (dir, store)
+    }
 
     #[test]
     fn hset_should_work() {
-        let store = MemTable::new();
-        let cmd = CommandRequest::new_hset("t1", "hello", "world".into());
-        let res = dispatch(cmd.clone(), &store);
-        assert_res_ok(res, &[Value::default()], &[]);
+        with_stores(|run| {
+            let cmd = CommandRequest::new_hset("t1", "hello", "world".into());
+            let res = run(cmd.clone());
+            assert_res_ok(&res, &[Value::default()], &[]);
 
-        let res = dispatch(cmd, &store);
-        assert_res_ok(res, &["world".into()], &[]);
+            let res = run(cmd);
+            assert_res_ok(&res, &["world".into()], &[]);
+        });
     }
 
     #[test]
     fn hget_should_work() {
-        let store = MemTable::new();
-        let cmd = CommandRequest::new_hset("score", "u1", 10.into());
-        dispatch(cmd, &store);
-        let cmd = CommandRequest::new_hget("score", "u1");
-        let res = dispatch(cmd, &store);
-        assert_res_ok(res, &[10.into()], &[]);
+        with_stores(|run| {
+            run(CommandRequest::new_hset("score", "u1", 10.into()));
+            let res = run(CommandRequest::new_hget("score", "u1"));
+            assert_res_ok(&res, &[10.into()], &[]);
+        });
     }
 
     #[test]
     fn hget_with_non_exist_key_should_return_404() {
-        let store = MemTable::new();
-        let cmd = CommandRequest::new_hget("score", "u1");
-        let res = dispatch(cmd, &store);
-        assert_res_error(res, 404, "Not found");
+        with_stores(|run| {
+            let res = run(CommandRequest::new_hget("score", "u1"));
+            assert_res_error(&res, 404, "Not found");
+        });
     }
 
     #[test]
     fn hgetall_should_work() {
+        with_stores(|run| {
+            let cmds = vec![
+                CommandRequest::new_hset("score", "u1", 10.into()),
+                CommandRequest::new_hset("score", "u2", 8.into()),
+                CommandRequest::new_hset("score", "u3", 11.into()),
+                CommandRequest::new_hset("score", "u1", 6.into()),
+            ];
+            for cmd in cmds {
+                run(cmd);
+            }
+
+            let res = run(CommandRequest::new_hgetall("score"));
+            let pairs = &[
+                Kvpair::new("u1", 6.into()),
+                Kvpair::new("u2", 8.into()),
+                Kvpair::new("u3", 11.into()),
+            ];
+            assert_res_ok(&res, &[], pairs);
+        });
+    }
+
+    #[test]
+    fn hmget_should_work() {
+        with_stores(|run| {
+            set_key_pairs(run, "user", vec![("u1", "Tyr"), ("u2", "Lindsey"), ("u3", "Rosie")]);
+
+            let cmd = CommandRequest::new_hmget("user", vec!["u1".into(), "u4".into(), "u3".into()]);
+            let res = run(cmd);
+            let values = &["Tyr".into(), Value::default(), "Rosie".into()];
+            assert_res_ok(&res, values, &[]);
+        });
+    }
+
+    #[test]
+    fn hmset_should_work() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("u1", "world")]);
+
+            let pairs = vec![
+                Kvpair::new("u1", 10.1.into()),
+                Kvpair::new("u2", 8.1.into()),
+            ];
+            let res = run(CommandRequest::new_hmset("t1", pairs));
+            assert_res_ok(&res, &["world".into(), Value::default()], &[]);
+
+            let res = run(CommandRequest::new_hget("t1", "u2"));
+            assert_res_ok(&res, &[8.1.into()], &[]);
+        });
+    }
+
+    #[test]
+    fn hdel_should_work() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("u1", "v1")]);
+
+            let res = run(CommandRequest::new_hdel("t1", "u2"));
+            assert_res_ok(&res, &[Value::default()], &[]);
+
+            let res = run(CommandRequest::new_hdel("t1", "u1"));
+            assert_res_ok(&res, &["v1".into()], &[]);
+
+            let res = run(CommandRequest::new_hget("t1", "u1"));
+            assert_res_error(&res, 404, "Not found");
+        });
+    }
+
+    #[test]
+    fn hmdel_should_work() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("u1", "v1"), ("u2", "v2")]);
+
+            let cmd = CommandRequest::new_hmdel("t1", vec!["u1".into(), "u3".into()]);
+            let res = run(cmd);
+            assert_res_ok(&res, &["v1".into(), Value::default()], &[]);
+
+            let res = run(CommandRequest::new_hgetall("t1"));
+            assert_res_ok(&res, &[], &[Kvpair::new("u2", "v2".into())]);
+        });
+    }
+
+    #[test]
+    fn hexist_should_work() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("u1", "v1")]);
+
+            let res = run(CommandRequest::new_hexist("t1", "u2"));
+            assert_res_ok(&res, &[false.into()], &[]);
+
+            let res = run(CommandRequest::new_hexist("t1", "u1"));
+            assert_res_ok(&res, &[true.into()], &[]);
+        });
+    }
+
+    #[test]
+    fn hmexist_should_work() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("u1", "v1"), ("u2", "v2")]);
+
+            let cmd = CommandRequest::new_hmexist("t1", vec!["u1".into(), "u3".into(), "u2".into()]);
+            let res = run(cmd);
+            assert_res_ok(&res, &[true.into(), false.into(), true.into()], &[]);
+        });
+    }
+
+    #[test]
+    fn hscan_should_page_through_table() {
+        with_stores(|run| {
+            let keys = ["a1", "b5", "b1", "b3", "b2", "b4", "c1"];
+            set_key_pairs(run, "t1", keys.iter().map(|k| (*k, *k)).collect());
+
+            // 按 key 的顺序返回，并带上下一页的 cursor
+            let res = run(CommandRequest::new_hscan("t1", "b", "", 2));
+            let pairs = &[Kvpair::new("b1", "b1".into()), Kvpair::new("b2", "b2".into())];
+            assert_res_ok(&res, &["b2".into()], pairs);
+
+            let res = run(CommandRequest::new_hscan("t1", "b", "b2", 2));
+            let pairs = &[Kvpair::new("b3", "b3".into()), Kvpair::new("b4", "b4".into())];
+            assert_res_ok(&res, &["b4".into()], pairs);
+
+            // 最后一页的 cursor 为空
+            let res = run(CommandRequest::new_hscan("t1", "b", "b4", 2));
+            assert_res_ok(&res, &["".into()], &[Kvpair::new("b5", "b5".into())]);
+        });
+    }
+
+    #[test]
+    fn hscan_should_skip_expired_keys() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("k1", "v1"), ("k3", "v3")]);
+            let ttl = Duration::from_millis(50);
+            run(CommandRequest::new_hset_with_ttl("t1", "k2", "v2".into(), ttl));
+            thread::sleep(ttl * 2);
+
+            let res = run(CommandRequest::new_hscan("t1", "", "", 0));
+            let pairs = &[Kvpair::new("k1", "v1".into()), Kvpair::new("k3", "v3".into())];
+            assert_res_ok(&res, &["".into()], pairs);
+        });
+    }
+
+    #[test]
+    fn hincrby_should_work() {
+        with_stores(|run| {
+            let res = run(CommandRequest::new_hincrby("t1", "k1", 10));
+            assert_res_ok(&res, &[10.into()], &[]);
+
+            let res = run(CommandRequest::new_hincrby("t1", "k1", -3));
+            assert_res_ok(&res, &[7.into()], &[]);
+
+            let res = run(CommandRequest::new_hget("t1", "k1"));
+            assert_res_ok(&res, &[7.into()], &[]);
+        });
+    }
+
+    #[test]
+    fn hincrbyfloat_should_work() {
+        with_stores(|run| {
+            let res = run(CommandRequest::new_hincrbyfloat("t1", "k1", 1.5));
+            assert_res_ok(&res, &[1.5.into()], &[]);
+
+            let res = run(CommandRequest::new_hincrbyfloat("t1", "k1", 0.25));
+            assert_res_ok(&res, &[1.75.into()], &[]);
+        });
+    }
+
+    #[test]
+    fn hincrby_with_wrong_type_should_fail() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("k1", "v1")]);
+            set_key_pairs(run, "t1", vec![("k2", 1.5)]);
+
+            let res = run(CommandRequest::new_hincrby("t1", "k1", 1));
+            assert_res_error(&res, 500, "Cannot convert value");
+            let res = run(CommandRequest::new_hincrby("t1", "k2", 1));
+            assert_res_error(&res, 500, "Cannot convert value");
+            let res = run(CommandRequest::new_hincrbyfloat("t1", "k1", 1.0));
+            assert_res_error(&res, 500, "Cannot convert value");
+
+            // 失败时不会修改原来的值
+            let res = run(CommandRequest::new_hget("t1", "k1"));
+            assert_res_ok(&res, &["v1".into()], &[]);
+        });
+    }
+
+    #[test]
+    fn hincrby_should_keep_ttl() {
+        with_stores(|run| {
+            let ttl = Duration::from_secs(10);
+            run(CommandRequest::new_hset_with_ttl("t1", "k1", 1.into(), ttl));
+            run(CommandRequest::new_hincrby("t1", "k1", 1));
+
+            let ttl: i64 = run(CommandRequest::new_ttl("t1", "k1")).values[0]
+                .clone()
+                .try_into()
+                .unwrap();
+            assert!(ttl > 0);
+        });
+    }
+
+    #[test]
+    fn hincrby_on_expired_key_should_start_over() {
+        let store = MemTable::new();
+        hincrby_on_expired_key(&store);
+
+        let (_dir, store) = sled_store();
+        hincrby_on_expired_key(&store);
+    }
+
+    fn hincrby_on_expired_key(store: &impl Storage) {
+        store.set("t1", "k1".into(), 5.into()).unwrap();
+        store.expire("t1", "k1", Some(now_ms() - 1)).unwrap();
+
+        let res = dispatch(CommandRequest::new_hincrby("t1", "k1", 1), store);
+        assert_res_ok(&res, &[1.into()], &[]);
+        // 过期的 key 当作不存在，新写入的 value 不带之前的过期时间
+        assert_eq!(store.expire_at("t1", "k1").unwrap(), None);
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), store);
+        assert_res_ok(&res, &[1.into()], &[]);
+    }
+
+    #[test]
+    fn hcas_should_work() {
+        with_stores(|run| {
+            // expected 为 None 时，只有 key 不存在才会设置成功
+            let res = run(CommandRequest::new_hcas("t1", "k1", None, "v1".into()));
+            assert_res_ok(&res, &[true.into(), "v1".into()], &[]);
+            let res = run(CommandRequest::new_hcas("t1", "k1", None, "v2".into()));
+            assert_res_ok(&res, &[false.into(), "v1".into()], &[]);
+
+            let res = run(CommandRequest::new_hcas("t1", "k1", Some("v0".into()), "v2".into()));
+            assert_res_ok(&res, &[false.into(), "v1".into()], &[]);
+            let res = run(CommandRequest::new_hcas("t1", "k1", Some("v1".into()), "v2".into()));
+            assert_res_ok(&res, &[true.into(), "v2".into()], &[]);
+
+            let res = run(CommandRequest::new_hget("t1", "k1"));
+            assert_res_ok(&res, &["v2".into()], &[]);
+        });
+    }
+
+    #[test]
+    fn concurrent_hincrby_should_not_lose_updates() {
         let store = MemTable::new();
-        let cmds = vec![
-            CommandRequest::new_hset("score", "u1", 10.into()),
-            CommandRequest::new_hset("score", "u2", 8.into()),
-            CommandRequest::new_hset("score", "u3", 11.into()),
-            CommandRequest::new_hset("score", "u1", 6.into()),
-        ];
-        for cmd in cmds {
-            dispatch(cmd, &store);
-        }
-
-        let cmd = CommandRequest::new_hgetall("score");
-        let res = dispatch(cmd, &store);
-        let pairs = &[
-            Kvpair::new("u1", 6.into()),
-            Kvpair::new("u2", 8.into()),
-            Kvpair::new("u3", 11.into()),
-        ];
-        assert_res_ok(res, &[], pairs);
-    }
-
-    // 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
-    fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
-        match cmd.request_data.unwrap() {
-            RequestData::Hget(v) => v.execute(store),
-            RequestData::Hgetall(v) => v.execute(store),
-            RequestData::Hset(v) => v.execute(store),
-            _ => todo!(),
-        }
-    }
-
-    // 测试成功返回的结果
-    fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-        res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        assert_eq!(res.status, 200);
-        assert_eq!(res.message, "");
-        assert_eq!(res.values, values);
-        assert_eq!(res.pairs, pairs);
-    }
-
-    // 测试失败返回的结果
-    fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-        assert_eq!(res.status, code);
-        assert!(res.message.contains(msg));
-        assert_eq!(res.values, &[]);
-        assert_eq!(res.pairs, &[]);
-    }
-}
\ No newline at end of file
+        concurrent_hincrby(&store);
+
+        let (_dir, store) = sled_store();
+        concurrent_hincrby(&store);
+    }
+
+    fn concurrent_hincrby(store: &(impl Storage + Sync)) {
+        let (threads, times) = (4i64, 100);
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    for _ in 0..times {
+                        let res = dispatch(CommandRequest::new_hincrby("t1", "counter", 1), store);
+                        assert_eq!(res.status, 200);
+                    }
+                });
+            }
+        });
+
+        let res = dispatch(CommandRequest::new_hget("t1", "counter"), store);
+        assert_res_ok(&res, &[(threads * times).into()], &[]);
+    }
+
+    #[test]
+    fn transaction_should_work() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("k1", "v1"), ("k2", "v2")]);
+
+            let cmds = vec![
+                CommandRequest::new_hset("t1", "k1", "v1-new".into()),
+                CommandRequest::new_hdel("t1", "k2"),
+                CommandRequest::new_hset("t2", "k3", "v3".into()),
+                CommandRequest::new_hget("t1", "k1"),
+                CommandRequest::new_hmexist("t1", vec!["k1".into(), "k2".into()]),
+            ];
+            let watches = vec![
+                Watch::new("t1", "k1", Some("v1".into())),
+                Watch::new("t2", "k3", None),
+            ];
+            let res = run(CommandRequest::new_transaction(cmds, watches));
+            assert_eq!(res.status, 200);
+
+            // 每个命令都有自己的响应，后面的命令能看到前面命令的修改
+            let responses = &res.responses;
+            assert_eq!(responses.len(), 5);
+            assert_res_ok(&responses[0], &["v1".into()], &[]);
+            assert_res_ok(&responses[1], &["v2".into()], &[]);
+            assert_res_ok(&responses[2], &[Value::default()], &[]);
+            assert_res_ok(&responses[3], &["v1-new".into()], &[]);
+            assert_res_ok(&responses[4], &[true.into(), false.into()], &[]);
+
+            let res = run(CommandRequest::new_hgetall("t1"));
+            assert_res_ok(&res, &[], &[Kvpair::new("k1", "v1-new".into())]);
+        });
+    }
+
+    #[test]
+    fn transaction_with_failed_watch_should_change_nothing() {
+        with_stores(|run| {
+            set_key_pairs(run, "t1", vec![("k1", "v1")]);
+
+            let cmds = vec![
+                CommandRequest::new_hset("t1", "k2", "v2".into()),
+                CommandRequest::new_hdel("t1", "k1"),
+            ];
+            let watches = vec![Watch::new("t1", "k1", Some("changed".into()))];
+            let res = run(CommandRequest::new_transaction(cmds, watches));
+            assert_res_error(&res, 409, "watched key is changed");
+
+            let res = run(CommandRequest::new_hgetall("t1"));
+            assert_res_ok(&res, &[], &[Kvpair::new("k1", "v1".into())]);
+        });
+    }
+
+    #[test]
+    fn transaction_with_unsupported_command_should_change_nothing() {
+        with_stores(|run| {
+            let cmds = vec![
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                CommandRequest::new_publish("lobby", vec![]),
+            ];
+            let res = run(CommandRequest::new_transaction(cmds, vec![]));
+            assert_res_error(&res, 400, "not supported in transaction");
+
+            let ttl = Duration::from_secs(1);
+            let cmds = vec![CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), ttl)];
+            let res = run(CommandRequest::new_transaction(cmds, vec![]));
+            assert_res_error(&res, 400, "TTL is not supported");
+
+            let res = run(CommandRequest::new_hexist("t1", "k1"));
+            assert_res_ok(&res, &[false.into()], &[]);
+        });
+    }
+
+    #[test]
+    fn concurrent_transactions_should_not_lose_updates() {
+        let store = MemTable::new();
+        concurrent_increments(&store);
+
+        let (_dir, store) = sled_store();
+        concurrent_increments(&store);
+    }
+
+    #[test]
+    fn concurrent_readers_should_see_whole_transactions() {
+        transactions_with_readers(&MemTable::new());
+
+        let dir = tempdir().unwrap();
+        transactions_with_readers(&DurableMemTable::open(dir.path()).unwrap());
+    }
+
+    /// 一个线程用事务同时修改两个 key，其它线程读到的两个 value 应该总是一样的
+    fn transactions_with_readers(store: &(impl Storage + Sync)) {
+        let done = std::sync::atomic::AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                        let mut res = run_cmd(CommandRequest::new_hgetall("t1"), store);
+                        res.pairs.sort_by(|a, b| a.key.cmp(&b.key));
+                        let values: Vec<_> = res.pairs.into_iter().map(|p| p.value).collect();
+                        assert!(values.is_empty() || values == vec![values[0].clone(); 2]);
+                    }
+                });
+            }
+
+            for i in 0..1000i64 {
+                let cmds = vec![
+                    CommandRequest::new_hset("t1", "k1", i.into()),
+                    CommandRequest::new_hset("t1", "k2", i.into()),
+                ];
+                let res = dispatch(CommandRequest::new_transaction(cmds, vec![]), store);
+                assert_eq!(res.status, 200);
+            }
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+        });
+    }
+
+    /// 多个线程用 watch 做乐观锁，同时给一个计数器加一，最后的结果不应该丢失任何一次修改
+    fn concurrent_increments(store: &(impl Storage + Sync)) {
+        let (threads, times) = (4i64, 50);
+        store.set("t1", "counter".into(), 0.into()).unwrap();
+
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    for _ in 0..times {
+                        loop {
+                            let v = store.get("t1", "counter").unwrap().unwrap();
+                            let n: i64 = v.clone().try_into().unwrap();
+                            let cmd = CommandRequest::new_hset("t1", "counter", (n + 1).into());
+                            let watch = Watch::new("t1", "counter", Some(v));
+                            let txn = CommandRequest::new_transaction(vec![cmd], vec![watch]);
+                            let res = dispatch(txn, store);
+                            if res.status == 200 {
+                                break;
+                            }
+                            assert_eq!(res.status, 409);
+                        }
+                    }
+                });
+            }
+        });
+
+        let res = dispatch(CommandRequest::new_hget("t1", "counter"), store);
+        assert_res_ok(&res, &[(threads * times).into()], &[]);
+    }
+
+    #[test]
+    fn hset_with_ttl_should_expire() {
+        with_stores(|run| {
+            let ttl = Duration::from_millis(50);
+            run(CommandRequest::new_hset_with_ttl("t1", "u1", "v1".into(), ttl));
+            set_key_pairs(run, "t1", vec![("u2", "v2")]);
+
+            let res = run(CommandRequest::new_hget("t1", "u1"));
+            assert_res_ok(&res, &["v1".into()], &[]);
+
+            thread::sleep(ttl * 2);
+
+            // 过期的 key 读不到，也不会出现在 hgetall 里
+            let res = run(CommandRequest::new_hget("t1", "u1"));
+            assert_res_error(&res, 404, "Not found");
+            let res = run(CommandRequest::new_hexist("t1", "u1"));
+            assert_res_ok(&res, &[false.into()], &[]);
+            let res = run(CommandRequest::new_hgetall("t1"));
+            assert_res_ok(&res, &[], &[Kvpair::new("u2", "v2".into())]);
+        });
+    }
+
+    #[test]
+    fn hmset_with_ttl_should_expire() {
+        with_stores(|run| {
+            let ttl = Duration::from_millis(50);
+            let pairs = vec![Kvpair::new("u1", 1.into()), Kvpair::new("u2", 2.into())];
+            run(CommandRequest::new_hmset_with_ttl("t1", pairs, ttl));
+
+            thread::sleep(ttl * 2);
+
+            let res = run(CommandRequest::new_hgetall("t1"));
+            assert_res_ok(&res, &[], &[]);
+        });
+    }
+
+    #[test]
+    fn expire_and_ttl_should_work() {
+        with_stores(|run| {
+            let res = run(CommandRequest::new_ttl("t1", "u1"));
+            assert_res_ok(&res, &[(-2).into()], &[]);
+
+            let res = run(CommandRequest::new_expire("t1", "u1", Duration::from_secs(10)));
+            assert_res_ok(&res, &[false.into()], &[]);
+
+            set_key_pairs(run, "t1", vec![("u1", "v1")]);
+            let res = run(CommandRequest::new_ttl("t1", "u1"));
+            assert_res_ok(&res, &[(-1).into()], &[]);
+
+            let res = run(CommandRequest::new_expire("t1", "u1", Duration::from_secs(10)));
+            assert_res_ok(&res, &[true.into()], &[]);
+            let ttl: i64 = run(CommandRequest::new_ttl("t1", "u1")).values[0]
+                .clone()
+                .try_into()
+                .unwrap();
+            assert!(ttl > 9000 && ttl <= 10000);
+
+            // 重新 hset 之后过期时间被清除
+            set_key_pairs(run, "t1", vec![("u1", "v2")]);
+            let res = run(CommandRequest::new_ttl("t1", "u1"));
+            assert_res_ok(&res, &[(-1).into()], &[]);
+        });
+    }
+
+    #[test]
+    fn huge_ttl_should_be_rejected() {
+        with_stores(|run| {
+            let ttl = Duration::from_millis(u64::MAX);
+            let res = run(CommandRequest::new_hset_with_ttl("t1", "u1", "v1".into(), ttl));
+            assert_res_error(&res, 400, "too large");
+            let pairs = vec![Kvpair::new("u1", 1.into())];
+            let res = run(CommandRequest::new_hmset_with_ttl("t1", pairs, ttl));
+            assert_res_error(&res, 400, "too large");
+
+            // 出错的命令什么都没有写入，也没有改变已有 key 的过期时间
+            let res = run(CommandRequest::new_hexist("t1", "u1"));
+            assert_res_ok(&res, &[false.into()], &[]);
+            set_key_pairs(run, "t1", vec![("u1", "v1")]);
+            let res = run(CommandRequest::new_expire("t1", "u1", ttl));
+            assert_res_error(&res, 400, "too large");
+            let res = run(CommandRequest::new_ttl("t1", "u1"));
+            assert_res_ok(&res, &[(-1).into()], &[]);
+        });
+    }
+
+    #[test]
+    fn persist_should_work() {
+        with_stores(|run| {
+            let ttl = Duration::from_millis(50);
+            set_key_pairs(run, "t1", vec![("u1", "v1")]);
+
+            let res = run(CommandRequest::new_persist("t1", "u1"));
+            assert_res_ok(&res, &[false.into()], &[]);
+
+            run(CommandRequest::new_expire("t1", "u1", ttl));
+            let res = run(CommandRequest::new_persist("t1", "u1"));
+            assert_res_ok(&res, &[true.into()], &[]);
+
+            thread::sleep(ttl * 2);
+            let res = run(CommandRequest::new_hget("t1", "u1"));
+            assert_res_ok(&res, &["v1".into()], &[]);
+        });
+    }
+
+    fn set_key_pairs<T: Into<Value>>(
+        run: &dyn Fn(CommandRequest) -> CommandResponse,
+        table: &str,
+        pairs: Vec<(&str, T)>,
+    ) {
+        pairs
+            .into_iter()
+            .map(|(k, v)| CommandRequest::new_hset(table, k, v.into()))
+            .for_each(|cmd| {
+                run(cmd);
+            });
+    }
+}
@@ -1,10 +1,33 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use tracing::debug;
+use futures::{executor, future::BoxFuture, stream, StreamExt};
+use tokio::{
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
 
 use crate::{*, command_request::RequestData};
 
+mod auth;
 mod command_service;
+mod replication;
+mod scan_service;
+mod topic;
+mod topic_service;
+
+pub use auth::{Access, Authorizer};
+use replication::{Primary, Role};
+pub use scan_service::ScanService;
+pub use topic::{Broadcaster, Topic};
+pub use topic_service::{StreamingResponse, TopicService};
+
+/// 在 spawn_blocking 里遍历 table 时，最多缓存多少个还没有发出去的 Response
+const SCAN_CHANNEL_CAPACITY: usize = 4;
 
 /// 对 Command 的处理的抽象
 pub trait CommandService {
@@ -15,94 +38,410 @@ pub trait CommandService {
 /// Service 数据结构
 pub struct Service<Store = MemTable> {
     inner: Arc<ServiceInner<Store>>,
+    broadcaster: Arc<Broadcaster>,
 }
 
 impl<Store> Clone for  Service<Store> {
     fn clone(&self) -> Self {
         Self { 
             inner: Arc::clone(&self.inner), 
+            broadcaster: Arc::clone(&self.broadcaster),
         }
     }
 }
 
+/// 收到请求时的 hook
+pub type ReceivedHook = Box<dyn Fn(&CommandRequest) + Send + Sync>;
+/// 命令执行完的 hook
+pub type ExecutedHook = Box<dyn Fn(&CommandResponse) + Send + Sync>;
+/// 发送响应之前的 hook，可以修改响应
+pub type BeforeSendHook = Box<dyn Fn(&mut CommandResponse) + Send + Sync>;
+/// 命令的第一个 response 准备好发送时的 hook，参数是命令的名字，response，以及处理的时间
+pub type CompletedHook = Box<dyn Fn(&'static str, &CommandResponse, Duration) + Send + Sync>;
+/// 响应写到连接上之后的 hook
+pub type AfterSendHook = Box<dyn Fn() + Send + Sync>;
+/// 执行命令之前的异步 hook，返回 Some(response) 时不再执行命令，直接返回这个 response
+pub type InterceptHook = Box<dyn Fn(&CommandRequest) -> HookFuture + Send + Sync>;
+/// 异步 hook 返回的 future
+pub type HookFuture = BoxFuture<'static, Option<CommandResponse>>;
+
 /// Service 内部数据结构
 pub struct ServiceInner<Store> {
     store: Store,
-    on_received: Vec<fn(&CommandRequest)>,
-    on_executed: Vec<fn(&CommandResponse)>,
-    on_before_send: Vec<fn(&mut CommandResponse)>,
-    on_after_send: Vec<fn()>,
+    authorizer: Authorizer,
+    role: Role,
+    on_received: Vec<ReceivedHook>,
+    on_intercept: Vec<InterceptHook>,
+    on_executed: Vec<ExecutedHook>,
+    on_before_send: Vec<BeforeSendHook>,
+    on_completed: Vec<CompletedHook>,
+    on_after_send: Vec<AfterSendHook>,
 }
 
 impl<Store: Storage> ServiceInner<Store>  {
     pub fn new(store: Store) -> Self {
         Self { 
             store, 
+            authorizer: Authorizer::default(),
+            role: Role::Standalone,
             on_received: Vec::new(), 
+            on_intercept: Vec::new(),
             on_executed: Vec::new(), 
             on_before_send: Vec::new(), 
+            on_completed: Vec::new(),
             on_after_send: Vec::new(), 
         }
     }
 
-    pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
-        self.on_received.push(f);
+    /// 设置认证和授权的规则，缺省所有的 table 都可以匿名访问
+    pub fn auth(mut self, authorizer: impl Into<Authorizer>) -> Self {
+        self.authorizer = authorizer.into();
+        self
+    }
+
+    /// 作为 primary，把成功执行的写命令的修改同步给连上来的 replica
+    pub fn primary(mut self) -> Self {
+        self.role = Role::Primary(Primary::new());
+        self
+    }
+
+    /// 作为 primary_addr 的 replica，所有的写命令都返回重定向，带上 primary 的地址
+    ///
+    /// 需要调用 Service::start_replica 从 primary 同步数据
+    pub fn replica_of(mut self, primary_addr: impl Into<String>) -> Self {
+        self.role = Role::Replica(primary_addr.into());
+        self
+    }
+
+    pub fn fn_received(mut self, f: impl Fn(&CommandRequest) + Send + Sync + 'static) -> Self {
+        self.on_received.push(Box::new(f));
+        self
+    }
+
+    /// 在通过授权之后、执行命令之前调用，可以是异步的
+    ///
+    /// 按注册的顺序调用，任何一个返回 Some(response)，命令就不再执行，直接返回这个 response
+    pub fn fn_intercept<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&CommandRequest) -> HookFuture + Send + Sync + 'static,
+    {
+        self.on_intercept.push(Box::new(f));
         self
     }
 
-    pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
-        self.on_executed.push(f);
+    pub fn fn_executed(mut self, f: impl Fn(&CommandResponse) + Send + Sync + 'static) -> Self {
+        self.on_executed.push(Box::new(f));
         self
     }
 
-    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse)) -> Self {
-        self.on_before_send.push(f);
+    pub fn fn_before_send<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut CommandResponse) + Send + Sync + 'static,
+    {
+        self.on_before_send.push(Box::new(f));
         self
     }
 
-    pub fn fn_after_send(mut self, f: fn()) -> Self {
-        self.on_after_send.push(f);
+    /// 流式的命令只在第一个 response 时调用，所以处理的时间是到第一个 response 的时间
+    pub fn fn_completed<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&'static str, &CommandResponse, Duration) + Send + Sync + 'static,
+    {
+        self.on_completed.push(Box::new(f));
+        self
+    }
+
+    pub fn fn_after_send(mut self, f: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_after_send.push(Box::new(f));
         self
     }
 }
 
-impl<Store: Storage> Service<Store> {
-    pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
-        debug!("Got request: {:?}", cmd);
+impl<Store: Storage + Send + Sync + 'static> Service<Store> {
+    /// 以匿名身份执行命令
+    pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+        self.execute_as(cmd, None)
+    }
+
+    /// 以 identity 的身份执行命令，identity 为 None 表示匿名
+    pub fn execute_as(&self, cmd: CommandRequest, identity: Option<&str>) -> StreamingResponse {
+        self.execute_on(cmd, identity, None)
+    }
+
+    /// 以 identity 的身份执行从连接 connection 上收到的命令，订阅只能由同一个连接取消
+    ///
+    /// 返回的每个 response 都经过 on_before_send，它们写到连接上之后需要调用 after_send
+    pub fn execute_on(
+        &self,
+        cmd: CommandRequest,
+        identity: Option<&str>,
+        connection: Option<u64>,
+    ) -> StreamingResponse {
+        debug!("Got request: {:?} from {:?}", cmd, identity);
+        let start = Instant::now();
+        let name = cmd.name();
         // 发送 on_received 事件
         self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd, &self.inner.store);
-        debug!("Executed response: {:?}", res);
-        // 发送 on_executed 事件
-        self.inner.on_executed.notify(&res);
-        self.inner.on_before_send.notify(&mut res);
+
+        let mut res = self.handle(cmd, identity, connection);
         if !self.inner.on_before_send.is_empty() {
-            debug!("Modified response: {:?}", res);
+            let inner = Arc::clone(&self.inner);
+            res = Box::pin(res.map(move |res| {
+                let mut res = res.as_ref().clone();
+                inner.on_before_send.notify(&mut res);
+                debug!("Modified response: {:?}", res);
+                Arc::new(res)
+            }));
+        }
+
+        if self.inner.on_completed.is_empty() {
+            return res;
+        }
+        let inner = Arc::clone(&self.inner);
+        let mut first = true;
+        Box::pin(res.inspect(move |res| {
+            if std::mem::take(&mut first) {
+                for f in &inner.on_completed {
+                    f(name, res, start.elapsed())
+                }
+            }
+        }))
+    }
+
+    fn handle(
+        &self,
+        cmd: CommandRequest,
+        identity: Option<&str>,
+        connection: Option<u64>,
+    ) -> StreamingResponse {
+        // Auth 只返回 token 对应的身份，由连接记住这个身份
+        if let Some(RequestData::Auth(param)) = &cmd.request_data {
+            let res = match self.authenticate(&param.token) {
+                Ok(identity) => Value::from(identity).into(),
+                Err(e) => e.into(),
+            };
+            return once_response(res);
+        }
+
+        if let Err(e) = self.inner.authorizer.authorize(&cmd, identity) {
+            warn!("Reject request from {:?}: {}", identity, e);
+            return once_response(e.into());
+        }
+
+        // replica 只能读，写命令要发给 primary
+        if let Role::Replica(primary) = &self.inner.role {
+            if cmd.is_write() {
+                return once_response(KvError::Redirect(primary.clone()).into());
+            }
+        }
+
+        if self.inner.on_intercept.is_empty() {
+            return self.run(cmd, connection);
+        }
+
+        // 等所有的 intercept hook 都放行之后再执行命令
+        let service = self.clone();
+        let res = async move {
+            for f in &service.inner.on_intercept {
+                if let Some(res) = f(&cmd).await {
+                    debug!("Request {:?} is intercepted: {:?}", cmd, res);
+                    return once_response(res);
+                }
+            }
+            service.run(cmd, connection)
+        };
+        Box::pin(stream::once(res).flatten())
+    }
+
+    fn run(&self, cmd: CommandRequest, connection: Option<u64>) -> StreamingResponse {
+        // pub/sub 类的命令不经过 store，直接交给 broadcaster 处理
+        if cmd.is_pubsub() {
+            let res = dispatch_stream(cmd, Arc::clone(&self.broadcaster), connection);
+            return self.notify_executed(res);
         }
 
+        if let Some(RequestData::Replicate(_)) = &cmd.request_data {
+            return self.notify_executed(self.replicate());
+        }
+
+        // 不会阻塞的 store 直接在当前的 task 里执行
+        if !self.inner.store.is_blocking() {
+            if cmd.is_scan() {
+                return self.notify_executed(dispatch_scan(cmd, &self.inner.store));
+            }
+            return once_response(self.inner.execute(cmd));
+        }
+
+        // 会阻塞的 store 放到 spawn_blocking 里执行，不占用 tokio 的工作线程
+        let inner = Arc::clone(&self.inner);
+        if cmd.is_scan() {
+            let (tx, rx) = mpsc::channel(SCAN_CHANNEL_CAPACITY);
+            task::spawn_blocking(move || {
+                // 下游发送得慢的时候 blocking_send 会等待，不会一次把整个 table 读进内存
+                for res in executor::block_on_stream(dispatch_scan(cmd, &inner.store)) {
+                    if tx.blocking_send(res).is_err() {
+                        break;
+                    }
+                }
+            });
+            return self.notify_executed(Box::pin(ReceiverStream::new(rx)));
+        }
+
+        let handle = task::spawn_blocking(move || inner.execute(cmd));
+        Box::pin(stream::once(async move {
+            let res = handle
+                .await
+                .unwrap_or_else(|e| KvError::Internal(e.to_string()).into());
+            Arc::new(res)
+        }))
+    }
+
+    /// 流式的命令每产生一个 response，发送一次 on_executed 事件
+    fn notify_executed(&self, res: StreamingResponse) -> StreamingResponse {
+        if self.inner.on_executed.is_empty() {
+            return res;
+        }
+        let inner = Arc::clone(&self.inner);
+        Box::pin(res.inspect(move |res| inner.on_executed.notify(res.as_ref())))
+    }
+}
+
+impl<Store: Storage> ServiceInner<Store> {
+    /// 执行一个普通的命令，并发送 on_executed 事件
+    fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+        let res = match &self.role {
+            Role::Primary(primary) if cmd.is_write() => {
+                primary.execute(cmd, &self.store, |cmd| dispatch(cmd, &self.store))
+            }
+            _ => dispatch(cmd, &self.store),
+        };
+        debug!("Executed response: {:?}", res);
+        // 发送 on_executed 事件
+        self.on_executed.notify(&res);
         res
     }
 }
 
+impl<Store> Service<Store> {
+    /// 用 token 换取身份
+    pub fn authenticate(&self, token: &str) -> Result<String, KvError> {
+        self.inner.authorizer.authenticate(token)
+    }
+
+    /// 一个 response 已经写到连接上，发送 on_after_send 事件
+    pub fn after_send(&self) {
+        for f in &self.inner.on_after_send {
+            f()
+        }
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> Service<Store> {
+    /// 启动一个后台任务，每隔 interval 清理一次已经过期的 key
+    ///
+    /// 读取时会惰性地删除过期的 key，这里清理的是长期没人访问的 key。
+    /// 任务只持有 store 的弱引用，Service 全部 drop 之后任务自动退出
+    pub fn start_expiry_sweeper(&self, interval: Duration) -> JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let inner = match inner.upgrade() {
+                    Some(inner) => inner,
+                    None => break,
+                };
+                let result = if inner.store.is_blocking() {
+                    task::spawn_blocking(move || inner.store.purge_expired())
+                        .await
+                        .unwrap_or_else(|e| Err(KvError::Internal(e.to_string())))
+                } else {
+                    inner.store.purge_expired()
+                };
+                match result {
+                    Ok(0) => {}
+                    Ok(n) => debug!("Purged {} expired keys", n),
+                    Err(e) => warn!("Failed to purge expired keys: {:?}", e),
+                }
+            }
+        })
+    }
+
+    /// 把存储里还没有持久化的写入刷到磁盘上
+    pub async fn flush(&self) -> Result<(), KvError> {
+        if !self.inner.store.is_blocking() {
+            return self.inner.store.flush();
+        }
+        let inner = Arc::clone(&self.inner);
+        task::spawn_blocking(move || inner.store.flush())
+            .await
+            .unwrap_or_else(|e| Err(KvError::Internal(e.to_string())))
+    }
+}
+
 impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     fn from(inner: ServiceInner<Store>) -> Self {
         Self {
-            inner: Arc::new(inner)
+            inner: Arc::new(inner),
+            broadcaster: Default::default(),
         }
     }
 }
 
-/// 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
+/// 从 Request 中得到 Response
 pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
     match cmd.request_data {
         Some(RequestData::Hget(param)) => param.execute(store),
-        Some(RequestData::Hgetall(param)) => param.execute(store),
+        Some(RequestData::Hmget(param)) => param.execute(store),
+        Some(RequestData::Hscan(param)) => param.execute(store),
         Some(RequestData::Hset(param)) => param.execute(store),
+        Some(RequestData::Hmset(param)) => param.execute(store),
+        Some(RequestData::Hdel(param)) => param.execute(store),
+        Some(RequestData::Hmdel(param)) => param.execute(store),
+        Some(RequestData::Hexist(param)) => param.execute(store),
+        Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Expire(param)) => param.execute(store),
+        Some(RequestData::Ttl(param)) => param.execute(store),
+        Some(RequestData::Persist(param)) => param.execute(store),
+        Some(RequestData::Transaction(param)) => param.execute(store),
+        Some(RequestData::Hincrby(param)) => param.execute(store),
+        Some(RequestData::Hincrbyfloat(param)) => param.execute(store),
+        Some(RequestData::Hcas(param)) => param.execute(store),
         None => KvError::InvalidCommand("Request has no data".into()).into(),
-        _ => KvError::Internal("Not implemented".into()).into(),
+        // 遍历和 pub/sub 的命令需要用 dispatch_scan 或 dispatch_stream 处理
+        Some(data) => unsupported(&data, "dispatch").into(),
     }
 }
 
+/// 从遍历 table 的 Request 中得到一个 stream response
+pub fn dispatch_scan(cmd: CommandRequest, store: &impl Storage) -> StreamingResponse {
+    match cmd.request_data {
+        Some(RequestData::Hgetall(param)) => param.execute(store),
+        Some(RequestData::Hrange(param)) => param.execute(store),
+        None => once_response(KvError::InvalidCommand("Request has no data".into()).into()),
+        Some(data) => once_response(unsupported(&data, "dispatch_scan").into()),
+    }
+}
+
+/// 从 Request 中得到一个 stream response，owner 是发出命令的连接
+pub fn dispatch_stream(
+    cmd: CommandRequest,
+    topic: impl Topic,
+    owner: Option<u64>,
+) -> StreamingResponse {
+    match cmd.request_data {
+        Some(RequestData::Publish(param)) => param.execute(topic, owner),
+        Some(RequestData::Subscribe(param)) => param.execute(topic, owner),
+        Some(RequestData::Unsubscribe(param)) => param.execute(topic, owner),
+        None => once_response(KvError::InvalidCommand("Request has no data".into()).into()),
+        Some(data) => once_response(unsupported(&data, "dispatch_stream").into()),
+    }
+}
+
+/// 命令交给了处理不了它的 dispatch 函数
+fn unsupported(data: &RequestData, dispatcher: &str) -> KvError {
+    KvError::InvalidCommand(format!("{:?} is not supported by {}", data, dispatcher))
+}
+
 /// 事件通知（不可变事件）
 pub trait Notify<Arg> {
     fn notify(&self, arg: &Arg);
@@ -113,7 +452,7 @@ pub trait NotifyMut<Arg> {
     fn notify(&self, arg: &mut Arg);
 }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg)>{
+impl<Arg> Notify<Arg> for Vec<Box<dyn Fn(&Arg) + Send + Sync>> {
     #[inline]
     fn notify(&self, arg: &Arg) {
         for f in self {
@@ -122,7 +461,7 @@ impl<Arg> Notify<Arg> for Vec<fn(&Arg)>{
     }
 }
 
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)>{
+impl<Arg> NotifyMut<Arg> for Vec<Box<dyn Fn(&mut Arg) + Send + Sync>> {
     #[inline]
     fn notify(&self, arg: &mut Arg) {
         for f in self {
@@ -131,17 +470,47 @@ impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)>{
     }
 }
 
+/// 只有一个 response 的 stream
+fn once_response(res: CommandResponse) -> StreamingResponse {
+    Box::pin(stream::once(async { Arc::new(res) }))
+}
+
+#[cfg(test)]
+use crate::{Kvpair, Value};
+
+// 测试成功返回的结果
+#[cfg(test)]
+pub fn assert_res_ok(res: &CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    let mut sorted_pairs = res.pairs.clone();
+    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(sorted_pairs, pairs);
+}
+
+// 测试失败返回的结果
+#[cfg(test)]
+pub fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
 #[cfg(test)]
 mod tests {
     use http::StatusCode;
     use tracing::info;
 
     use super::*;
-    use crate::{MemTable, Value};
-    use std::thread;
+    use crate::{MemTable, SledDb, Value};
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tempfile::tempdir;
 
-    #[test]
-    fn service_should_works() {
+    #[tokio::test]
+    async fn service_should_works() {
         // 我们需要一个 service 结构至少包含 Storage
         // let service = Service::new(MemTable::default());
         let service: Service = ServiceInner::new(MemTable::default()).into();
@@ -149,20 +518,38 @@ mod tests {
         // service 可以运行在多线程环境下，它的 clone 应该是轻量级的
         let cloned = service.clone();
 
-        // 创建一个线程，在 table t1 中写入 k1, v1
-        let handle = thread::spawn(move || {
-            let res = cloned.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-            assert_res_ok(res, &[Value::default()], &[]);
-        });
-        handle.join().unwrap();
+        // 创建一个 task，在 table t1 中写入 k1, v1
+        tokio::spawn(async move {
+            let mut res = cloned.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+            let data = res.next().await.unwrap();
+            assert_res_ok(&data, &[Value::default()], &[]);
+        })
+        .await
+        .unwrap();
 
-        // 在当前线程下读取 table t1 的 k1，应该返回 v1
-        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
-        assert_res_ok(res, &["v1".into()], &[]);
+        // 在当前 task 下读取 table t1 的 k1，应该返回 v1
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &["v1".into()], &[]);
     }
 
-    #[test]
-    fn event_registration_should_work() {
+    #[tokio::test]
+    async fn dispatch_should_reject_commands_it_cannot_handle() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_error(&res, 400, "not supported by dispatch");
+
+        let mut res = dispatch_scan(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(&res.next().await.unwrap(), 400, "not supported by dispatch_scan");
+        assert!(res.next().await.is_none());
+
+        let topic = Arc::new(Broadcaster::default());
+        let mut res = dispatch_stream(CommandRequest::new_hget("t1", "k1"), topic, None);
+        assert_res_error(&res.next().await.unwrap(), 400, "not supported by dispatch_stream");
+    }
+
+    #[tokio::test]
+    async fn event_registration_should_work() {
         fn b(cmd: &CommandRequest) {
             info!("Got {:?}", cmd);
         }
@@ -184,31 +571,189 @@ mod tests {
             .fn_after_send(e)
             .into();
         
-        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-        assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = res.next().await.unwrap();
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
-}
 
-#[cfg(test)]
-use crate::{Kvpair, Value};
+    #[tokio::test]
+    async fn service_pub_sub_should_work() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
 
-// 测试成功返回的结果
-#[cfg(test)]
-pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(res.pairs, pairs);
-}
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"));
+        let id = sub.next().await.unwrap();
+        assert_eq!(id.status, 200);
 
-// 测试失败返回的结果
-#[cfg(test)]
-pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.pairs, &[]);
-}
\ No newline at end of file
+        let mut res = service.execute(CommandRequest::new_publish("lobby", vec!["hello".into()]));
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+
+        let data = sub.next().await.unwrap();
+        assert_res_ok(&data, &["hello".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn expiry_sweeper_should_purge_expired_keys() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let ttl = Duration::from_millis(20);
+        let cmd = CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), ttl);
+        service.execute(cmd).next().await.unwrap();
+
+        let handle = service.start_expiry_sweeper(Duration::from_millis(10));
+        tokio::time::sleep(ttl * 3).await;
+
+        // 过期的 key 已经被后台任务清理掉，不需要等到下次读取
+        assert_eq!(service.inner.store.purge_expired().unwrap(), 0);
+        assert!(service.inner.store.get_all("t1").unwrap().is_empty());
+
+        // service drop 掉之后，后台任务也会退出
+        drop(service);
+        tokio::time::timeout(Duration::from_millis(100), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn service_should_enforce_table_acl() {
+        let mut config = AuthConfig::default();
+        config.tokens.insert("secret".into(), "alice".into());
+        let acl = TableAcl {
+            read: vec!["*".into()],
+            write: vec!["alice".into()],
+        };
+        config.tables.insert("users".into(), acl);
+        let service: Service = ServiceInner::new(MemTable::default()).auth(config).into();
+
+        let cmd = CommandRequest::new_hset("users", "k1", "v1".into());
+        let res = service.execute(cmd.clone()).next().await.unwrap();
+        assert_res_error(&res, 401, "requires authentication");
+        let res = service.execute_as(cmd.clone(), Some("bob")).next().await.unwrap();
+        assert_res_error(&res, 403, "bob cannot write table users");
+        let res = service.execute_as(cmd, Some("alice")).next().await.unwrap();
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        // 拒绝的请求不会执行
+        let res = service.execute_as(CommandRequest::new_hgetall("users"), Some("bob"));
+        let res: Vec<_> = res.collect().await;
+        assert_eq!(res[0].pairs, vec![Kvpair::new("k1", "v1".into())]);
+    }
+
+    #[tokio::test]
+    async fn service_auth_should_return_identity() {
+        let mut config = AuthConfig::default();
+        config.tokens.insert("secret".into(), "alice".into());
+        let service: Service = ServiceInner::new(MemTable::default()).auth(config).into();
+
+        let res = service.execute(CommandRequest::new_auth("secret")).next().await.unwrap();
+        assert_res_ok(&res, &["alice".into()], &[]);
+        let res = service.execute(CommandRequest::new_auth("bad")).next().await.unwrap();
+        assert_res_error(&res, 401, "invalid token");
+    }
+
+    #[tokio::test]
+    async fn blocking_store_should_not_block_runtime() {
+        // store 的操作要等 runtime 上的 task 放行才能完成
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let rx = std::sync::Mutex::new(rx);
+        let released = Arc::new(AtomicBool::new(false));
+        let r = released.clone();
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        let service: Service<SledDb> = ServiceInner::new(store)
+            .fn_executed(move |_| {
+                let ok = rx.lock().unwrap().recv_timeout(Duration::from_secs(5)).is_ok();
+                r.store(ok, Ordering::SeqCst);
+            })
+            .into();
+
+        // 单线程的 runtime 上，store 的操作卡住 runtime 的话放行的 task 就没机会执行
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        tokio::spawn(async move {
+            let _ = tx.send(());
+        });
+
+        let res = res.next().await.unwrap();
+        assert_res_ok(&res, &[Value::default()], &[]);
+        assert!(released.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn blocking_store_should_stream_scan_results() {
+        let dir = tempdir().unwrap();
+        let service: Service<SledDb> = ServiceInner::new(SledDb::new(dir.path())).into();
+        let n = 300;
+        for i in 0..n {
+            let cmd = CommandRequest::new_hset("t1", format!("k{:03}", i), (i as i64).into());
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        let res: Vec<_> = service.execute(CommandRequest::new_hgetall("t1")).collect().await;
+        assert!(res.last().unwrap().stream_end);
+        let pairs: Vec<_> = res.iter().flat_map(|res| res.pairs.iter()).collect();
+        assert_eq!(pairs.len(), n);
+        assert_eq!(pairs[0].key, "k000");
+    }
+
+    #[tokio::test]
+    async fn hooks_should_capture_state() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let executed = Arc::new(AtomicUsize::new(0));
+        let (r, e) = (received.clone(), executed.clone());
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(move |_| {
+                r.fetch_add(1, Ordering::SeqCst);
+            })
+            .fn_executed(move |_| {
+                e.fetch_add(1, Ordering::SeqCst);
+            })
+            .fn_before_send(|res| res.message = "hooked".into())
+            .into();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.message, "hooked");
+
+        // 流式的命令每个 response 都会经过 hook
+        let res: Vec<_> = service.execute(CommandRequest::new_hgetall("t1")).collect().await;
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().all(|res| res.message == "hooked"));
+
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+        assert_eq!(executed.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn intercept_hook_should_short_circuit_request() {
+        let executed = Arc::new(AtomicUsize::new(0));
+        let e = executed.clone();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_intercept(|cmd| {
+                let blocked = matches!(
+                    &cmd.request_data,
+                    Some(RequestData::Hset(param)) if param.table == "readonly"
+                );
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    blocked.then(|| KvError::InvalidCommand("table is readonly".into()).into())
+                })
+            })
+            .fn_executed(move |_| {
+                e.fetch_add(1, Ordering::SeqCst);
+            })
+            .into();
+
+        let cmd = CommandRequest::new_hset("readonly", "k1", "v1".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&res, 400, "table is readonly");
+        assert_eq!(executed.load(Ordering::SeqCst), 0);
+        assert!(service.inner.store.get_all("readonly").unwrap().is_empty());
+
+        // 没有被拦截的命令正常执行
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&res, &[Value::default()], &[]);
+        assert_eq!(executed.load(Ordering::SeqCst), 1);
+    }
+}
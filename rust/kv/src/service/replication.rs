@@ -0,0 +1,551 @@
+use futures::{Future, StreamExt};
+use http::StatusCode;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::{self, JoinHandle},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info, warn};
+
+use super::{dispatch, once_response, Service, ServiceInner, StreamingResponse};
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, Kvpair,
+    ProstClientStream, Storage, Value,
+};
+
+/// primary 给每个 replica 缓存的还没有发出去的写命令的数量，超过了 replica 就要重新全量同步
+const REPLICATION_BACKLOG: usize = 4096;
+/// 全量同步时，每个 response 里最多包含多少个 key
+const SNAPSHOT_BATCH: usize = 128;
+/// 发给 replica 的 response 最多缓存多少个
+const REPLICA_CHANNEL_CAPACITY: usize = 4;
+/// replica 和 primary 断开之后，隔多久重连
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Service 在主从复制里的角色
+pub(crate) enum Role {
+    Standalone,
+    Primary(Primary),
+    /// 参数是 primary 的地址，写命令会被重定向到这里
+    Replica(String),
+}
+
+/// primary 这边的状态：把写命令修改过的 key 的最新状态广播给所有的 replica
+pub(crate) struct Primary {
+    /// 每个写命令广播一组命令；None 表示读不出修改之后的状态，replica 需要重新全量同步
+    sender: broadcast::Sender<Option<Arc<Vec<CommandRequest>>>>,
+    /// 执行写命令、读取修改后的状态和广播在同一把锁里，
+    /// replica 收到的顺序就是写命令在 primary 上执行的顺序
+    lock: Mutex<()>,
+    /// 下一个 replica 的编号
+    next_id: AtomicU32,
+}
+
+impl Primary {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(REPLICATION_BACKLOG).0,
+            lock: Mutex::new(()),
+            next_id: AtomicU32::new(1),
+        }
+    }
+
+    /// 用 f 执行写命令，成功之后把它修改过的 key 在 store 里的最新状态广播给所有的 replica
+    ///
+    /// 每个 key 的状态是一个带过期时间点的 Hset，key 不存在时是一个 Hdel。它们重复执行的结果一样，
+    /// 所以 replica 全量同步时，snapshot 里已经包含的修改再执行一次也没有关系
+    pub fn execute(
+        &self,
+        cmd: CommandRequest,
+        store: &impl Storage,
+        f: impl FnOnce(CommandRequest) -> CommandResponse,
+    ) -> CommandResponse {
+        let mut keys: Vec<_> = written_keys(&cmd)
+            .into_iter()
+            .map(|(table, key)| (table.to_string(), key.to_string()))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        // 锁里没有数据，poison 了也没关系
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let res = f(cmd);
+        if res.status != StatusCode::OK.as_u16() as u32 || keys.is_empty() {
+            return res;
+        }
+        let updates = keys
+            .iter()
+            .map(|(table, key)| key_state(store, table, key))
+            .collect::<Result<Vec<_>, _>>();
+        let updates = match updates {
+            Ok(updates) => Some(Arc::new(updates)),
+            Err(e) => {
+                warn!("Failed to read written keys, replicas will resync: {:?}", e);
+                None
+            }
+        };
+        // 没有 replica 的时候 send 会返回错误，不用管
+        let _ = self.sender.send(updates);
+        res
+    }
+}
+
+impl<Store: Storage + Send + Sync + 'static> Service<Store> {
+    /// 处理 replica 发来的 Replicate 命令：先发 snapshot，再发后续的写命令
+    pub(super) fn replicate(&self) -> StreamingResponse {
+        let primary = match &self.inner.role {
+            Role::Primary(primary) => primary,
+            _ => {
+                let msg = "Replicate is only supported on a primary".into();
+                return once_response(KvError::InvalidCommand(msg).into());
+            }
+        };
+        // 先订阅再做 snapshot，做 snapshot 期间执行的写命令在 snapshot 之后发给 replica
+        let mut updates = primary.sender.subscribe();
+        let id = primary.next_id.fetch_add(1, Ordering::Relaxed);
+        info!("Replica {} starts full sync", id);
+
+        let (tx, rx) = mpsc::channel(REPLICA_CHANNEL_CAPACITY);
+        let inner = Arc::clone(&self.inner);
+        tokio::spawn(async move {
+            let res: CommandResponse = Value::from(id as i64).into();
+            if tx.send(Arc::new(res)).await.is_err() {
+                return;
+            }
+
+            // 遍历 store 可能比较慢，放在 spawn_blocking 里
+            let snapshot_tx = tx.clone();
+            let result = task::spawn_blocking(move || send_snapshot(&inner.store, &snapshot_tx))
+                .await
+                .unwrap_or_else(|e| Err(KvError::Internal(e.to_string())));
+            let res = match result {
+                Ok(()) => CommandResponse::ok(),
+                Err(e) => {
+                    warn!("Failed to send snapshot to replica {}: {:?}", id, e);
+                    let _ = tx.send(Arc::new(e.into())).await;
+                    let _ = tx.send(Arc::new(CommandResponse::stream_end())).await;
+                    return;
+                }
+            };
+            if tx.send(Arc::new(res)).await.is_err() {
+                return;
+            }
+            info!("Replica {} finished full sync", id);
+
+            loop {
+                let res = match updates.recv().await {
+                    Ok(Some(commands)) => CommandResponse {
+                        commands: commands.as_ref().clone(),
+                        ..CommandResponse::ok()
+                    },
+                    Ok(None) => {
+                        let msg = "Failed to read the state of written keys".into();
+                        let _ = tx.send(Arc::new(KvError::Internal(msg).into())).await;
+                        let _ = tx.send(Arc::new(CommandResponse::stream_end())).await;
+                        break;
+                    }
+                    // replica 太慢，中间丢了命令，只能让它重新全量同步
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Replica {} lagged behind {} commands", id, n);
+                        let msg = format!("Replica lagged behind {} commands", n);
+                        let _ = tx.send(Arc::new(KvError::Internal(msg).into())).await;
+                        let _ = tx.send(Arc::new(CommandResponse::stream_end())).await;
+                        break;
+                    }
+                    // Service 已经被 drop 了
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if tx.send(Arc::new(res)).await.is_err() {
+                    break;
+                }
+            }
+            info!("Replica {} disconnected", id);
+        });
+        Box::pin(ReceiverStream::new(rx))
+    }
+
+    /// 作为 replica 启动一个后台任务，从 primary 同步数据
+    ///
+    /// connect 负责建立到 primary 的连接，需要登录的话也在里面完成。连接断开之后
+    /// 每隔 RECONNECT_INTERVAL 重连一次，每次连上都重新做全量同步。
+    /// 任务只持有 store 的弱引用，Service 全部 drop 之后任务自动退出
+    pub fn start_replica<F, Fut>(&self, connect: F) -> JoinHandle<()>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<ProstClientStream, KvError>> + Send,
+    {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            loop {
+                match sync_from_primary(&inner, &connect).await {
+                    Ok(()) => info!("Replication stream from primary is closed"),
+                    Err(e) => warn!("Failed to replicate from primary: {:?}", e),
+                }
+                if inner.strong_count() == 0 {
+                    break;
+                }
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+            }
+        })
+    }
+}
+
+/// 连接 primary，做一次全量同步，然后一直执行 primary 发来的写命令，直到连接断开
+async fn sync_from_primary<Store, F, Fut>(
+    inner: &Weak<ServiceInner<Store>>,
+    connect: &F,
+) -> Result<(), KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<ProstClientStream, KvError>>,
+{
+    let client = connect().await?;
+    let mut stream = client.execute_streaming(CommandRequest::new_replicate()).await?;
+    info!("Connected to primary as replica {}", stream.id);
+
+    // 全量同步时收到的 key，同步结束后删掉 replica 上其它的 key；None 表示全量同步已经结束
+    let mut synced: Option<HashMap<String, HashSet<String>>> = Some(HashMap::new());
+    while let Some(res) = stream.next().await {
+        let res = res?.into_result()?;
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
+        if let Some(keys) = synced.as_mut() {
+            if res.status != StatusCode::PARTIAL_CONTENT.as_u16() as u32 {
+                let keys = synced.take().unwrap_or_default();
+                let removed = with_store(inner, move |store| remove_stale(store, &keys)).await?;
+                info!("Full sync finished, removed {} stale keys", removed);
+                continue;
+            }
+            for cmd in &res.commands {
+                for (table, key) in written_keys(cmd) {
+                    keys.entry(table.to_string()).or_default().insert(key.to_string());
+                }
+            }
+        }
+
+        let commands = res.commands;
+        with_store(inner, move |store| {
+            for cmd in commands {
+                debug!("Replicate command: {:?}", cmd);
+                dispatch(cmd, store).into_result()?;
+            }
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// 把 store 里所有的数据分批发给 replica，每批是一个状态码为 206 的 response
+///
+/// 带过期时间的 key 用带过期时间点的 Hset 同步，其它的 key 合并成 Hmset
+fn send_snapshot(
+    store: &impl Storage,
+    tx: &mpsc::Sender<Arc<CommandResponse>>,
+) -> Result<(), KvError> {
+    for table in store.tables()? {
+        let mut pairs = Vec::new();
+        let mut commands = Vec::new();
+        let mut count = 0;
+        for pair in store.get_iter(&table)? {
+            let key = pair.key;
+            match read_entry(store, &table, &key)? {
+                Some((value, Some(at))) => {
+                    let cmd = CommandRequest::new_hset_with_expire_at(&table, key, value, Some(at));
+                    commands.push(cmd);
+                }
+                Some((value, None)) => pairs.push(Kvpair::new(key, value)),
+                // 遍历到之后被删掉或者过期了，不用同步
+                None => continue,
+            }
+            count += 1;
+            if count == SNAPSHOT_BATCH {
+                count = 0;
+                if !send_batch(tx, &table, &mut pairs, &mut commands) {
+                    return Ok(());
+                }
+            }
+        }
+        if count > 0 && !send_batch(tx, &table, &mut pairs, &mut commands) {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// 发送一批 snapshot，replica 已经断开的话返回 false
+fn send_batch(
+    tx: &mpsc::Sender<Arc<CommandResponse>>,
+    table: &str,
+    pairs: &mut Vec<Kvpair>,
+    commands: &mut Vec<CommandRequest>,
+) -> bool {
+    if !pairs.is_empty() {
+        commands.push(CommandRequest::new_hmset(table, std::mem::take(pairs)));
+    }
+    let res = CommandResponse {
+        status: StatusCode::PARTIAL_CONTENT.as_u16() as _,
+        commands: std::mem::take(commands),
+        ..Default::default()
+    };
+    // 下游发送得慢的时候 blocking_send 会等待，不会一次把整个 store 读进内存
+    tx.blocking_send(Arc::new(res)).is_ok()
+}
+
+/// 读取 key 当前的 value 和过期时间，key 不存在（或者已经过期）时返回 None
+///
+/// 先读过期时间再读 value：两次读之间 key 过期了的话读到的 value 是 None，
+/// 不会把一个会过期的 key 当成不过期的
+fn read_entry(
+    store: &impl Storage,
+    table: &str,
+    key: &str,
+) -> Result<Option<(Value, Option<u64>)>, KvError> {
+    let expire_at = store.expire_at(table, key)?;
+    Ok(store.get(table, key)?.map(|value| (value, expire_at)))
+}
+
+/// key 当前的状态：带过期时间点的 Hset，key 不存在（或者已经过期）时是 Hdel
+fn key_state(store: &impl Storage, table: &str, key: &str) -> Result<CommandRequest, KvError> {
+    Ok(match read_entry(store, table, key)? {
+        Some((value, expire_at)) => {
+            CommandRequest::new_hset_with_expire_at(table, key, value, expire_at)
+        }
+        None => CommandRequest::new_hdel(table, key),
+    })
+}
+
+/// 写命令修改了哪些 key，需要和 CommandRequest::is_write 保持一致
+fn written_keys(cmd: &CommandRequest) -> Vec<(&str, &str)> {
+    match &cmd.request_data {
+        Some(RequestData::Hset(param)) => param
+            .pair
+            .iter()
+            .map(|pair| (param.table.as_str(), pair.key.as_str()))
+            .collect(),
+        Some(RequestData::Hmset(param)) => param
+            .pairs
+            .iter()
+            .map(|pair| (param.table.as_str(), pair.key.as_str()))
+            .collect(),
+        Some(RequestData::Hdel(param)) => vec![(&param.table, &param.key)],
+        Some(RequestData::Hmdel(param)) => param
+            .keys
+            .iter()
+            .map(|key| (param.table.as_str(), key.as_str()))
+            .collect(),
+        Some(RequestData::Expire(param)) => vec![(&param.table, &param.key)],
+        Some(RequestData::Persist(param)) => vec![(&param.table, &param.key)],
+        Some(RequestData::Hincrby(param)) => vec![(&param.table, &param.key)],
+        Some(RequestData::Hincrbyfloat(param)) => vec![(&param.table, &param.key)],
+        Some(RequestData::Hcas(param)) => vec![(&param.table, &param.key)],
+        Some(RequestData::Transaction(param)) => {
+            param.commands.iter().flat_map(written_keys).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// 删掉 replica 上不在 snapshot 里的 key，它们在断开连接期间已经在 primary 上删掉了
+fn remove_stale(
+    store: &impl Storage,
+    keys: &HashMap<String, HashSet<String>>,
+) -> Result<usize, KvError> {
+    let mut removed = 0;
+    for table in store.tables()? {
+        let synced = keys.get(&table);
+        let stale: Vec<_> = store
+            .get_iter(&table)?
+            .filter(|pair| !synced.is_some_and(|keys| keys.contains(&pair.key)))
+            .map(|pair| pair.key)
+            .collect();
+        for key in stale {
+            store.del(&table, &key)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// 访问 store，会阻塞的 store 放到 spawn_blocking 里
+async fn with_store<Store, T, F>(inner: Arc<ServiceInner<Store>>, f: F) -> Result<T, KvError>
+where
+    Store: Storage + Send + Sync + 'static,
+    T: Send + 'static,
+    F: FnOnce(&Store) -> Result<T, KvError> + Send + 'static,
+{
+    if !inner.store.is_blocking() {
+        return f(&inner.store);
+    }
+    task::spawn_blocking(move || f(&inner.store))
+        .await
+        .unwrap_or_else(|e| Err(KvError::Internal(e.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{storage::now_ms, utils::serve, MemTable, ServiceInner};
+
+    #[tokio::test]
+    async fn replica_should_sync_snapshot_then_updates() -> Result<()> {
+        let primary: Service = ServiceInner::new(MemTable::new()).primary().into();
+        execute(&primary, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        execute(&primary, CommandRequest::new_hset("t2", "k2", "v2".into())).await;
+        let ttl = Duration::from_secs(60);
+        execute(&primary, CommandRequest::new_hset_with_ttl("t1", "k3", "v3".into(), ttl)).await;
+        let addr = start_server(primary.clone()).await?;
+
+        // replica 上之前留下的、primary 上已经没有的数据会被删掉
+        let store = MemTable::new();
+        store.set("t1", "stale".into(), "v0".into())?;
+        let replica: Service = ServiceInner::new(store).replica_of(addr.to_string()).into();
+        let handle = replica.start_replica(move || async move {
+            ProstClientStream::new(TcpStream::connect(addr).await?).await
+        });
+
+        let store = &replica.inner.store;
+        eventually(|| store.get("t2", "k2").unwrap().is_some()).await;
+        eventually(|| !store.contains("t1", "stale").unwrap()).await;
+        assert_eq!(store.get("t1", "k1")?, Some("v1".into()));
+        // 同步的是过期的时间点，和 primary 上的一样
+        let expire_at = primary.inner.store.expire_at("t1", "k3")?;
+        assert!(expire_at.is_some());
+        assert_eq!(store.expire_at("t1", "k3")?, expire_at);
+
+        // 全量同步之后的写命令按顺序同步过来
+        let pairs = vec![Kvpair::new("k4", "v4".into()), Kvpair::new("k5", "v5".into())];
+        execute(&primary, CommandRequest::new_hmset("t1", pairs)).await;
+        execute(&primary, CommandRequest::new_hdel("t1", "k1")).await;
+        execute(&primary, CommandRequest::new_hmdel("t2", vec!["k2".into()])).await;
+        // 其它的写命令同步的是修改之后 key 的状态
+        execute(&primary, CommandRequest::new_hincrby("t1", "n", 2)).await;
+        execute(&primary, CommandRequest::new_expire("t1", "k5", ttl)).await;
+        execute(&primary, CommandRequest::new_persist("t1", "k3")).await;
+        let cas = CommandRequest::new_hcas("t1", "k4", Some("v4".into()), "v4.1".into());
+        execute(&primary, cas).await;
+        let txn = vec![
+            CommandRequest::new_hset("t2", "k6", "v6".into()),
+            CommandRequest::new_hdel("t1", "k4"),
+        ];
+        execute(&primary, CommandRequest::new_transaction(txn, vec![])).await;
+        execute(&primary, CommandRequest::new_hset("t1", "last", "v7".into())).await;
+
+        eventually(|| store.contains("t1", "last").unwrap()).await;
+        assert!(!store.contains("t1", "k1")?);
+        assert!(!store.contains("t2", "k2")?);
+        assert!(!store.contains("t1", "k4")?);
+        assert_eq!(store.get("t1", "n")?, Some(2.into()));
+        assert_eq!(store.get("t2", "k6")?, Some("v6".into()));
+        assert_eq!(store.get("t1", "k5")?, Some("v5".into()));
+        assert_eq!(store.expire_at("t1", "k5")?, primary.inner.store.expire_at("t1", "k5")?);
+        assert!(store.expire_at("t1", "k5")?.is_some());
+        assert_eq!(store.expire_at("t1", "k3")?, None);
+
+        handle.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn primary_should_broadcast_state_of_written_keys() {
+        let store = MemTable::new();
+        let primary = Primary::new();
+        let mut updates = primary.sender.subscribe();
+        let run = |cmd| primary.execute(cmd, &store, |cmd| dispatch(cmd, &store));
+
+        // 失败的命令不广播
+        let failed = CommandRequest::new_hdel("t1", "k1");
+        primary.execute(failed, &store, |_| KvError::Internal("oops".into()).into());
+
+        // 广播的是修改之后 key 的状态，而不是命令本身
+        run(CommandRequest::new_hincrby("t1", "k1", 1));
+        let expected = CommandRequest::new_hset_with_expire_at("t1", "k1", 1.into(), None);
+        assert_eq!(updates.recv().await.unwrap(), Some(Arc::new(vec![expected])));
+
+        let expire_at = now_ms() + 60_000;
+        store.expire("t1", "k1", Some(expire_at)).unwrap();
+        run(CommandRequest::new_hincrby("t1", "k1", 1));
+        let expected =
+            CommandRequest::new_hset_with_expire_at("t1", "k1", 2.into(), Some(expire_at));
+        assert_eq!(updates.recv().await.unwrap(), Some(Arc::new(vec![expected])));
+
+        run(CommandRequest::new_hmdel("t1", vec!["k1".into(), "k2".into()]));
+        let expected = vec![
+            CommandRequest::new_hdel("t1", "k1"),
+            CommandRequest::new_hdel("t1", "k2"),
+        ];
+        assert_eq!(updates.recv().await.unwrap(), Some(Arc::new(expected)));
+        assert!(updates.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn replica_should_redirect_writes_to_primary() {
+        let inner = ServiceInner::new(MemTable::new()).replica_of("10.0.0.1:9527");
+        let replica: Service = inner.into();
+        replica.inner.store.set("t1", "k1".into(), "v1".into()).unwrap();
+
+        let res = execute(&replica, CommandRequest::new_hset("t1", "k1", "v2".into())).await;
+        assert_eq!(res.status, 307);
+        let e = res.into_result().unwrap_err();
+        assert!(matches!(e, KvError::Redirect(addr) if addr == "10.0.0.1:9527"));
+        let res = execute(&replica, CommandRequest::new_hincrby("t1", "k2", 1)).await;
+        assert_eq!(res.status, 307);
+
+        // 读命令正常执行
+        let res = execute(&replica, CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.values, vec!["v1".into()]);
+    }
+
+    #[tokio::test]
+    async fn only_primary_should_accept_replicate() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = execute(&service, CommandRequest::new_replicate()).await;
+        assert_eq!(res.status, 400);
+
+        let primary: Service = ServiceInner::new(MemTable::new()).primary().into();
+        execute(&primary, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        let res: Vec<_> = primary.execute(CommandRequest::new_replicate()).take(3).collect().await;
+        assert_eq!(res[0].values, vec![Value::from(1)]);
+        assert_eq!(res[1].status, 206);
+        assert_eq!(res[1].commands.len(), 1);
+        assert_eq!(res[2].status, 200);
+        assert!(res[2].commands.is_empty());
+    }
+
+    async fn execute(service: &Service, cmd: CommandRequest) -> CommandResponse {
+        service.execute(cmd).next().await.unwrap().as_ref().clone()
+    }
+
+    async fn start_server(service: Service) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(listener, service));
+        Ok(addr)
+    }
+
+    /// 等到 f 返回 true，最多等 2 秒
+    async fn eventually(f: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if f() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("condition is not met in time");
+    }
+}
@@ -0,0 +1,123 @@
+use futures::{stream, StreamExt};
+use std::sync::Arc;
+
+use crate::{CommandResponse, Hgetall, Hrange, KvError, Kvpair, Storage, StreamingResponse};
+
+/// 每个 Response 里最多放多少个 kv pair
+const SCAN_BATCH_SIZE: usize = 128;
+
+/// 对遍历 table 类 Command 的处理的抽象
+pub trait ScanService {
+    /// 处理 Command，返回多个 Response 组成的 stream，最后一个是 stream_end
+    fn execute(self, store: &impl Storage) -> StreamingResponse;
+}
+
+impl ScanService for Hgetall {
+    fn execute(self, store: &impl Storage) -> StreamingResponse {
+        into_streaming_response(store.get_iter(&self.table))
+    }
+}
+
+impl ScanService for Hrange {
+    fn execute(self, store: &impl Storage) -> StreamingResponse {
+        let Hrange {
+            table,
+            prefix,
+            start,
+            end,
+        } = self;
+        let iter = store.get_iter(&table).map(|iter| {
+            iter.filter(move |pair| {
+                pair.key.starts_with(&prefix)
+                    && pair.key >= start
+                    && (end.is_empty() || pair.key < end)
+            })
+        });
+        into_streaming_response(iter)
+    }
+}
+
+/// 把 iterator 按 SCAN_BATCH_SIZE 分批转换成 Response，最后补上结束标记
+///
+/// iterator 是惰性的，下游发送得慢的时候不会一次把整个 table 读进内存
+fn into_streaming_response<I>(iter: Result<I, KvError>) -> StreamingResponse
+where
+    I: Iterator<Item = Kvpair> + Send + 'static,
+{
+    let end = stream::once(async { Arc::new(CommandResponse::stream_end()) });
+    match iter {
+        Ok(iter) => {
+            let data = stream::iter(iter)
+                .chunks(SCAN_BATCH_SIZE)
+                .map(|pairs| Arc::new(pairs.into()));
+            Box::pin(data.chain(end))
+        }
+        Err(e) => {
+            let res: CommandResponse = e.into();
+            Box::pin(stream::once(async { Arc::new(res) }).chain(end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, dispatch_scan, CommandRequest, MemTable, SledDb};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn hgetall_should_stream_pairs_in_batches() {
+        let store = MemTable::new();
+        let n = SCAN_BATCH_SIZE * 2 + 1;
+        for i in 0..n {
+            store.set("t1", format!("k{}", i), (i as i64).into()).unwrap();
+        }
+
+        let res: Vec<_> = dispatch_scan(CommandRequest::new_hgetall("t1"), &store)
+            .collect()
+            .await;
+
+        // 3 个数据的 response，加上一个结束标记
+        assert_eq!(res.len(), 4);
+        assert_eq!(res[0].pairs.len(), SCAN_BATCH_SIZE);
+        assert_eq!(res[2].pairs.len(), 1);
+        assert!(res[..3].iter().all(|res| res.status == 200 && !res.stream_end));
+        assert!(res[3].stream_end);
+        assert_eq!(res.iter().map(|res| res.pairs.len()).sum::<usize>(), n);
+    }
+
+    #[tokio::test]
+    async fn hgetall_empty_table_should_only_return_stream_end() {
+        let store = MemTable::new();
+        let res: Vec<_> = dispatch_scan(CommandRequest::new_hgetall("t1"), &store)
+            .collect()
+            .await;
+        assert_eq!(res.len(), 1);
+        assert!(res[0].stream_end);
+    }
+
+    #[tokio::test]
+    async fn hrange_should_filter_by_prefix_and_range() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        for key in ["a1", "b1", "b2", "b3", "c1"] {
+            store.set("t1", key.into(), key.into()).unwrap();
+        }
+
+        let cmd = CommandRequest::new_hrange("t1", "b", "b2", "");
+        let mut res = dispatch_scan(cmd, &store);
+        let data = res.next().await.unwrap();
+        let pairs = &[
+            Kvpair::new("b2", "b2".into()),
+            Kvpair::new("b3", "b3".into()),
+        ];
+        assert_res_ok(&data, &[], pairs);
+        assert!(res.next().await.unwrap().stream_end);
+
+        let cmd = CommandRequest::new_hrange("t1", "", "a", "b2");
+        let res: Vec<_> = dispatch_scan(cmd, &store).collect().await;
+        let keys: Vec<_> = res.iter().flat_map(|res| res.pairs.iter()).map(|p| &p.key).collect();
+        assert_eq!(keys, vec!["a1", "b1"]);
+        assert!(res[1].stream_end);
+    }
+}
@@ -0,0 +1,252 @@
+use dashmap::{DashMap, DashSet};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::{CommandResponse, KvError, Value};
+
+/// 每个订阅者最多积压的数据，超过之后订阅者跟不上了，取消它的订阅
+const BROADCAST_CAPACITY: usize = 128;
+
+/// 下一个 subscription id
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// 获取下一个 subscription id
+fn get_next_subscription_id() -> u32 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// owner 是订阅所在的连接，None 表示不经过网络、在进程内直接调用
+pub trait Topic: Send + Sync + 'static {
+    /// 订阅某个主题
+    fn subscribe(self, name: String, owner: Option<u64>) -> mpsc::Receiver<Arc<CommandResponse>>;
+    /// 取消对主题的订阅，只能取消同一个 owner 在这个主题上的订阅
+    fn unsubscribe(self, name: String, id: u32, owner: Option<u64>) -> Result<u32, KvError>;
+    /// 往主题里发布一个数据，订阅者按照发布的顺序收到
+    fn publish(self, name: String, value: Arc<CommandResponse>);
+}
+
+/// 一个订阅：推送数据的 channel，以及订阅所在的连接
+struct Subscription {
+    owner: Option<u64>,
+    tx: mpsc::Sender<Arc<CommandResponse>>,
+}
+
+/// 用于主题发布和订阅的数据结构
+#[derive(Default)]
+pub struct Broadcaster {
+    /// 所有的主题列表
+    topics: DashMap<String, DashSet<u32>>,
+    /// 所有的订阅列表
+    subscriptions: DashMap<u32, Subscription>,
+}
+
+impl Topic for Arc<Broadcaster> {
+    fn subscribe(self, name: String, owner: Option<u64>) -> mpsc::Receiver<Arc<CommandResponse>> {
+        let id = {
+            let entry = self.topics.entry(name.clone()).or_default();
+            let id = get_next_subscription_id();
+            entry.value().insert(id);
+            id
+        };
+
+        // 生成一个 mpsc channel
+        let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+
+        // 第一个消息是 subscription id，channel 是新建的，一定有空位
+        let v: Value = (id as i64).into();
+        if let Err(e) = tx.try_send(Arc::new(v.into())) {
+            warn!("Failed to send subscription id: {}. Error: {:?}", id, e);
+        }
+
+        // 把 tx 存入 subscription table
+        self.subscriptions.insert(id, Subscription { owner, tx });
+        debug!("Subscription {} is added to topic {}", id, name);
+
+        // 返回 rx 给网络处理的上下文
+        rx
+    }
+
+    fn unsubscribe(self, name: String, id: u32, owner: Option<u64>) -> Result<u32, KvError> {
+        // 别的连接的订阅和不存在的订阅一样处理，不暴露它的存在
+        let owned = matches!(self.subscriptions.get(&id), Some(sub) if sub.owner == owner);
+        if owned {
+            if let Some(id) = self.remove_subscription(&name, id) {
+                return Ok(id);
+            }
+        }
+        Err(KvError::NotFound(name, id.to_string()))
+    }
+
+    fn publish(self, name: String, value: Arc<CommandResponse>) {
+        let ids = match self.topics.get(&name) {
+            Some(topic) => topic.value().iter().map(|id| *id).collect::<Vec<_>>(),
+            None => return,
+        };
+
+        // 在调用者这里直接放进每个订阅者的 channel，不会等待，保证先发布的先送达
+        let mut dead = vec![];
+        for id in ids {
+            if let Some(sub) = self.subscriptions.get(&id) {
+                // 订阅者已经断开，或者积压得太多跟不上了
+                if let Err(e) = sub.tx.try_send(value.clone()) {
+                    warn!("Publish to {} failed! error: {:?}", id, e);
+                    dead.push(id);
+                }
+            }
+        }
+
+        // 清理掉这些订阅，订阅的 stream 收完积压的数据之后结束
+        for id in dead {
+            self.remove_subscription(&name, id);
+        }
+    }
+}
+
+impl Broadcaster {
+    /// 删除主题 name 下的订阅 id，订阅不在这个主题下时返回 None
+    fn remove_subscription(&self, name: &str, id: u32) -> Option<u32> {
+        {
+            let v = self.topics.get_mut(name)?;
+            // 在 topics 表里找到 topic 的 subscription id，删除
+            v.remove(&id)?;
+
+            // 如果这个 topic 为空，则也删除 topic
+            if v.is_empty() {
+                info!("Topic: {:?} is deleted", name);
+                drop(v);
+                self.topics.remove(name);
+            }
+        }
+
+        debug!("Subscription {} is removed!", id);
+        // 在 subscription 表中同样删除，drop 掉 tx 之后订阅的 stream 就结束了
+        self.subscriptions.remove(&id).map(|(id, _)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use tokio::sync::mpsc::Receiver;
+
+    use crate::assert_res_ok;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn pub_sub_should_work() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby = "lobby".to_string();
+
+        // subscribe
+        let mut stream1 = b.clone().subscribe(lobby.clone(), None);
+        let mut stream2 = b.clone().subscribe(lobby.clone(), None);
+
+        // publish
+        let v: Value = "hello".into();
+        b.clone().publish(lobby.clone(), Arc::new(v.clone().into()));
+
+        // subscribers 应该能收到 publish 的数据
+        let id1 = get_id(&mut stream1).await;
+        let id2 = get_id(&mut stream2).await;
+
+        assert!(id1 != id2);
+
+        let res1 = stream1.recv().await.unwrap();
+        let res2 = stream2.recv().await.unwrap();
+
+        assert_eq!(res1, res2);
+        assert_res_ok(&res1, &[v], &[]);
+
+        // 如果 subscriber 取消订阅，则收不到新数据
+        let result = b.clone().unsubscribe(lobby.clone(), id1, None).unwrap();
+        assert_eq!(result, id1);
+
+        // publish
+        let v: Value = "world".into();
+        b.clone().publish(lobby, Arc::new(v.clone().into()));
+
+        assert!(stream1.recv().await.is_none());
+        let res2 = stream2.recv().await.unwrap();
+        assert_res_ok(&res2, &[v], &[]);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_unknown_id_should_fail() {
+        let b = Arc::new(Broadcaster::default());
+        let result = b.unsubscribe("lobby".into(), 9999, None);
+        assert!(matches!(result, Err(KvError::NotFound(_, _))));
+    }
+
+    #[tokio::test]
+    async fn publish_should_deliver_in_order() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().subscribe("lobby".into(), None);
+        get_id(&mut stream).await;
+
+        let n = 100i64;
+        for i in 0..n {
+            let v: Value = i.into();
+            b.clone().publish("lobby".into(), Arc::new(v.into()));
+        }
+        for i in 0..n {
+            let res = stream.recv().await.unwrap();
+            assert_res_ok(&res, &[i.into()], &[]);
+        }
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_should_only_remove_own_subscription_of_the_topic() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().subscribe("lobby".into(), Some(1));
+        let id = get_id(&mut stream).await;
+
+        // 主题不对，或者是别的连接，都当作找不到
+        let result = b.clone().unsubscribe("other".into(), id, Some(1));
+        assert!(matches!(result, Err(KvError::NotFound(_, _))));
+        let result = b.clone().unsubscribe("lobby".into(), id, Some(2));
+        assert!(matches!(result, Err(KvError::NotFound(_, _))));
+        let result = b.clone().unsubscribe("lobby".into(), id, None);
+        assert!(matches!(result, Err(KvError::NotFound(_, _))));
+
+        // 订阅还在，能收到数据
+        let v: Value = "hello".into();
+        b.clone().publish("lobby".into(), Arc::new(v.clone().into()));
+        assert_res_ok(&stream.recv().await.unwrap(), &[v], &[]);
+
+        assert_eq!(b.clone().unsubscribe("lobby".into(), id, Some(1)).unwrap(), id);
+        assert!(stream.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_be_removed() {
+        let b = Arc::new(Broadcaster::default());
+        let mut stream = b.clone().subscribe("lobby".into(), None);
+        let id = get_id(&mut stream).await;
+
+        // 订阅者一直不读，积压满了之后订阅被取消，已经积压的数据还能读到
+        for i in 0..=BROADCAST_CAPACITY as i64 {
+            let v: Value = i.into();
+            b.clone().publish("lobby".into(), Arc::new(v.into()));
+        }
+        let mut received = 0;
+        while stream.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, BROADCAST_CAPACITY);
+        assert!(b.unsubscribe("lobby".into(), id, None).is_err());
+    }
+
+    pub async fn get_id(res: &mut Receiver<Arc<CommandResponse>>) -> u32 {
+        let id: i64 = res.recv().await.unwrap().as_ref().clone().values[0]
+            .clone()
+            .try_into()
+            .unwrap();
+        id as u32
+    }
+}
@@ -0,0 +1,123 @@
+use futures::{stream, Stream, StreamExt};
+use std::{pin::Pin, sync::Arc};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{CommandResponse, Publish, Subscribe, Topic, Unsubscribe};
+
+pub type StreamingResponse = Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>;
+
+/// 对 pub/sub 类 Command 的处理的抽象
+pub trait TopicService {
+    /// 处理 Command，返回 Response，owner 是发出命令的连接
+    fn execute(self, topic: impl Topic, owner: Option<u64>) -> StreamingResponse;
+}
+
+impl TopicService for Subscribe {
+    fn execute(self, topic: impl Topic, owner: Option<u64>) -> StreamingResponse {
+        let rx = topic.subscribe(self.topic, owner);
+        // 取消订阅之后 rx 会结束，这时候再补发一个结束标记
+        let end = stream::once(async { Arc::new(CommandResponse::stream_end()) });
+        Box::pin(ReceiverStream::new(rx).chain(end))
+    }
+}
+
+impl TopicService for Unsubscribe {
+    fn execute(self, topic: impl Topic, owner: Option<u64>) -> StreamingResponse {
+        let res = match topic.unsubscribe(self.topic, self.id, owner) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
+impl TopicService for Publish {
+    fn execute(self, topic: impl Topic, _owner: Option<u64>) -> StreamingResponse {
+        topic.publish(self.topic, Arc::new(self.data.into()));
+        Box::pin(stream::once(async { Arc::new(CommandResponse::ok()) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, dispatch_stream, Broadcaster, CommandRequest};
+    use futures::StreamExt;
+    use std::convert::TryInto;
+
+    #[tokio::test]
+    async fn dispatch_publish_should_work() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        let mut res = dispatch_stream(cmd, topic, None);
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &[], &[]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_subscribe_should_work() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_subscribe("lobby");
+        let mut res = dispatch_stream(cmd, topic, None);
+        let id = get_id(&mut res).await;
+        assert!(id > 0);
+    }
+
+    #[tokio::test]
+    async fn dispatch_subscribe_abnormal_quit_should_be_removed_on_next_publish() {
+        let topic = Arc::new(Broadcaster::default());
+        let id = {
+            let cmd = CommandRequest::new_subscribe("lobby");
+            let mut res = dispatch_stream(cmd, topic.clone(), None);
+            let id = get_id(&mut res).await;
+            drop(res);
+            id as u32
+        };
+
+        // publish 时，这个 subscription 已经失效，所以会被删除
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        let mut res = dispatch_stream(cmd, topic.clone(), None);
+        assert_res_ok(&res.next().await.unwrap(), &[], &[]);
+
+        // 如果再尝试删除，应该返回 NotFound
+        let result = topic.unsubscribe("lobby".into(), id, None);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn dispatch_unsubscribe_should_end_subscription() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_subscribe("lobby");
+        let mut sub = dispatch_stream(cmd, topic.clone(), None);
+        let id = get_id(&mut sub).await;
+
+        let cmd = CommandRequest::new_unsubscribe("lobby", id as _);
+        let mut res = dispatch_stream(cmd, topic, None);
+        let data = res.next().await.unwrap();
+        assert_res_ok(&data, &[], &[]);
+
+        // 订阅的 stream 收到结束标记后结束
+        let data = sub.next().await.unwrap();
+        assert!(data.stream_end);
+        assert!(sub.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn dispatch_unsubscribe_random_id_should_error() {
+        let topic = Arc::new(Broadcaster::default());
+
+        let cmd = CommandRequest::new_unsubscribe("lobby", 9999);
+        let mut res = dispatch_stream(cmd, topic, None);
+        let data = res.next().await.unwrap();
+
+        assert_res_error(&data, 404, "Not found");
+    }
+
+    pub async fn get_id(res: &mut StreamingResponse) -> u32 {
+        let id: i64 = res.next().await.unwrap().as_ref().clone().values[0]
+            .clone()
+            .try_into()
+            .unwrap();
+        id as u32
+    }
+}
@@ -0,0 +1,560 @@
+use bytes::BytesMut;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    storage::{
+        add_float, add_integer,
+        wal::{decode_records, encode_record, Wal, WalOp},
+    },
+    KvError, Kvpair, MemTable, Storage, TxnOp, Value, Watch,
+};
+
+/// 日志文件的名字
+const WAL_FILE: &str = "wal.log";
+/// snapshot 文件的名字，写的时候先写到临时文件里
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+/// snapshot 里每条记录最多包含的 key 数量
+const SNAPSHOT_BATCH: usize = 1024;
+/// 日志超过这个大小时做一次 snapshot
+pub const DEFAULT_SNAPSHOT_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// 数据放在 MemTable 里，所有的写操作都先追加到日志（write-ahead log）里，重启后可以恢复
+///
+/// 日志超过一定大小后，把日志轮转成 wal.<n>.log，再把全部数据写成 snapshot，
+/// 写完之后删掉轮转出去的日志；打开时先加载 snapshot，再依次重放轮转出去的日志和当前的日志
+pub struct DurableMemTable {
+    table: MemTable,
+    dir: PathBuf,
+    // 写操作在这把锁里写日志、修改内存，所以日志的顺序就是修改的顺序
+    wal: Mutex<Wal>,
+    // 下一个轮转出去的日志的序号；做 snapshot 时持有这把锁，同一时间只有一个 snapshot
+    rotation: Mutex<u64>,
+    snapshot_threshold: u64,
+}
+
+impl DurableMemTable {
+    /// 打开 path 目录下的数据，目录不存在时创建
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let dir = path.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let table = MemTable::new();
+
+        // snapshot 是写完之后 rename 过来的，不应该有不完整的记录
+        let mut keys = 0;
+        match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(data) => {
+                let (records, valid) = decode_records(&data);
+                if valid < data.len() {
+                    let msg = format!("snapshot in {} is corrupted", dir.display());
+                    return Err(KvError::Internal(msg));
+                }
+                for ops in records {
+                    keys += ops.len();
+                    replay(&table, ops)?;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // 上次的 snapshot 没写完，轮转出去的日志还在，按顺序重放
+        let mut count = 0;
+        let rotated = rotated_logs(&dir)?;
+        for (_, path) in &rotated {
+            let data = fs::read(path)?;
+            let (records, valid) = decode_records(&data);
+            if valid < data.len() {
+                warn!("Discard {} bytes of torn record in {}", data.len() - valid, path.display());
+            }
+            count += records.len();
+            for ops in records {
+                replay(&table, ops)?;
+            }
+        }
+        let next_rotation = rotated.last().map_or(0, |(n, _)| n + 1);
+
+        let (wal, records) = Wal::open(&dir.join(WAL_FILE))?;
+        count += records.len();
+        for ops in records {
+            replay(&table, ops)?;
+        }
+        info!(
+            "Loaded {} keys from snapshot and {} records from log in {}",
+            keys,
+            count,
+            dir.display()
+        );
+
+        Ok(Self {
+            table,
+            dir,
+            wal: Mutex::new(wal),
+            rotation: Mutex::new(next_rotation),
+            snapshot_threshold: DEFAULT_SNAPSHOT_THRESHOLD,
+        })
+    }
+
+    /// 设置日志超过多大时做 snapshot
+    pub fn with_snapshot_threshold(mut self, threshold: u64) -> Self {
+        self.snapshot_threshold = threshold;
+        self
+    }
+
+    /// 把全部数据写成 snapshot，然后删掉已经包含在 snapshot 里的日志
+    pub fn snapshot(&self) -> Result<(), KvError> {
+        let mut rotation = self.rotation.lock().unwrap_or_else(|e| e.into_inner());
+        self.write_snapshot(&mut rotation)
+    }
+
+    fn lock_wal(&self) -> MutexGuard<'_, Wal> {
+        // 日志的每条记录都是一次写完的，poison 了也可以继续用
+        self.wal.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 执行一次写操作：f 根据当前的数据检查操作，算出要记录的操作和返回值，
+    /// 然后先写日志，再修改内存
+    ///
+    /// 这些都在日志的锁里完成，f 看到的数据不会被其它写操作修改；
+    /// f 返回错误或者写日志失败时，内存里的数据没有变化
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&MemTable) -> Result<(Vec<WalOp>, T), KvError>,
+    ) -> Result<T, KvError> {
+        let (result, size) = {
+            let mut wal = self.lock_wal();
+            let (ops, result) = f(&self.table)?;
+            if !ops.is_empty() {
+                wal.append(&ops)?;
+                // 一条记录里有多个操作（事务）时，读操作要么看到全部，要么都看不到
+                match ops.len() {
+                    1 => replay(&self.table, ops)?,
+                    _ => self.table.atomically(|| replay(&self.table, ops))?,
+                }
+            }
+            (result, wal.size())
+        };
+
+        // 在日志的锁外面做 snapshot，不阻塞其它写操作；已经有 snapshot 在做的话跳过
+        if size >= self.snapshot_threshold {
+            if let Ok(mut rotation) = self.rotation.try_lock() {
+                // 记录已经写进日志了，snapshot 失败不影响这次写操作
+                if let Err(e) = self.write_snapshot(&mut rotation) {
+                    warn!("Failed to write snapshot to {}: {:?}", self.dir.display(), e);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// 调用者需要持有 rotation 的锁
+    ///
+    /// 只在轮转日志时持有日志的锁，之后的写操作记录在新的日志里。
+    /// snapshot 是在锁外面读取的，可能包含轮转之后的一些写操作，
+    /// 因为日志记录的是操作之后 key 的状态，重放新的日志之后结果还是一样的
+    fn write_snapshot(&self, rotation: &mut u64) -> Result<(), KvError> {
+        let rotated = self.dir.join(rotated_log_name(*rotation));
+        self.lock_wal().rotate(&rotated)?;
+        *rotation += 1;
+
+        let entries = self.table.entries();
+        let mut buf = BytesMut::new();
+        for chunk in entries.chunks(SNAPSHOT_BATCH) {
+            let ops: Vec<_> = chunk
+                .iter()
+                .cloned()
+                .map(|(table, key, value, expire_at)| WalOp::Set {
+                    table,
+                    key,
+                    value,
+                    expire_at,
+                })
+                .collect();
+            encode_record(&ops, &mut buf)?;
+        }
+
+        // rename 是原子的，崩溃时留下的要么是旧的 snapshot，要么是新的
+        let tmp = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+        // 让 rename 落盘；有的平台不能打开目录，忽略错误
+        if let Ok(dir) = File::open(&self.dir) {
+            let _ = dir.sync_all();
+        }
+
+        // 在删除之前崩溃也没关系，日志里的操作重放多次的结果是一样的
+        for (_, path) in rotated_logs(&self.dir)? {
+            fs::remove_file(path)?;
+        }
+        debug!("Wrote snapshot of {} keys to {}", entries.len(), self.dir.display());
+        Ok(())
+    }
+}
+
+/// 轮转出去的第 n 个日志的文件名
+fn rotated_log_name(n: u64) -> String {
+    format!("wal.{}.log", n)
+}
+
+/// 目录里轮转出去的日志，按序号排好
+fn rotated_logs(dir: &Path) -> Result<Vec<(u64, PathBuf)>, KvError> {
+    let mut logs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let n = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("wal."))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|n| n.parse::<u64>().ok());
+        if let Some(n) = n {
+            logs.push((n, entry.path()));
+        }
+    }
+    logs.sort_unstable();
+    Ok(logs)
+}
+
+/// 把一条记录里的操作应用到 MemTable 上
+fn replay(table: &MemTable, ops: Vec<WalOp>) -> Result<(), KvError> {
+    for op in ops {
+        match op {
+            WalOp::Set {
+                table: name,
+                key,
+                value,
+                expire_at,
+            } => {
+                table.set_with_expire(&name, key, value, expire_at)?;
+            }
+            WalOp::Del { table: name, key } => {
+                table.del(&name, &key)?;
+            }
+            WalOp::Expire {
+                table: name,
+                key,
+                expire_at,
+            } => {
+                table.expire(&name, &key, expire_at)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 写操作都通过 write 完成：检查操作，写日志，再修改内存，返回给调用者之前记录已经落盘
+impl Storage for DurableMemTable {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.table.get(table, key)
+    }
+
+    fn set_with_expire(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        expire_at: Option<u64>,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(|t| {
+            let old = t.get(table, &key)?;
+            let op = WalOp::Set {
+                table: table.into(),
+                key,
+                value,
+                expire_at,
+            };
+            Ok((vec![op], old))
+        })
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.table.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|t| {
+            let old = t.get(table, key)?;
+            // key 不存在（或者已经过期）时重放出来的结果也一样，不需要记录
+            let ops = match old {
+                Some(_) => vec![WalOp::Del {
+                    table: table.into(),
+                    key: key.into(),
+                }],
+                None => vec![],
+            };
+            Ok((ops, old))
+        })
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.table.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
+        self.table.get_iter(table)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.table.tables()
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        self.table.scan(table, prefix, start_after, limit)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.write(|t| {
+            let (value, expire_at) = t.get_entry(table, key).unzip();
+            let n = add_integer(value.as_ref(), delta)?;
+            Ok((vec![set_op(table, key, n.into(), expire_at.flatten())], n))
+        })
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.write(|t| {
+            let (value, expire_at) = t.get_entry(table, key).unzip();
+            let n = add_float(value.as_ref(), delta)?;
+            Ok((vec![set_op(table, key, n.into(), expire_at.flatten())], n))
+        })
+    }
+
+    fn cas(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        self.write(|t| {
+            let (current, expire_at) = t.get_entry(table, key).unzip();
+            if current.as_ref() != expected {
+                return Ok((vec![], (false, current)));
+            }
+            // cas 保留 key 的过期时间
+            let op = set_op(table, key, new.clone(), expire_at.flatten());
+            Ok((vec![op], (true, Some(new))))
+        })
+    }
+
+    fn transaction(&self, watches: &[Watch], ops: &[TxnOp]) -> Result<Vec<Option<Value>>, KvError> {
+        self.write(|t| {
+            for w in watches {
+                if t.get(&w.table, &w.key)? != w.value {
+                    return Err(KvError::WatchFailed(w.table.clone(), w.key.clone()));
+                }
+            }
+
+            // 按顺序算出每个操作的结果，事务里前面的写操作对后面的 Get 可见
+            let mut written: HashMap<(&str, &str), Option<Value>> = HashMap::new();
+            let mut result = Vec::with_capacity(ops.len());
+            let mut log = Vec::new();
+            for op in ops {
+                let (table, key) = op.target();
+                let value = match written.get(&(table, key)) {
+                    Some(value) => value.clone(),
+                    None => t.get(table, key)?,
+                };
+                result.push(value);
+                match op {
+                    TxnOp::Get { .. } => {}
+                    TxnOp::Set { value, .. } => {
+                        written.insert((table, key), Some(value.clone()));
+                        log.push(set_op(table, key, value.clone(), None));
+                    }
+                    TxnOp::Del { .. } => {
+                        written.insert((table, key), None);
+                        log.push(WalOp::Del {
+                            table: table.into(),
+                            key: key.into(),
+                        });
+                    }
+                }
+            }
+            // 整个事务写成一条记录，重放时要么都生效，要么都不生效
+            Ok((log, result))
+        })
+    }
+
+    fn expire(&self, table: &str, key: &str, expire_at: Option<u64>) -> Result<bool, KvError> {
+        self.write(|t| {
+            let found = t.contains(table, key)?;
+            let ops = match found {
+                true => vec![WalOp::Expire {
+                    table: table.into(),
+                    key: key.into(),
+                    expire_at,
+                }],
+                false => vec![],
+            };
+            Ok((ops, found))
+        })
+    }
+
+    fn expire_at(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        self.table.expire_at(table, key)
+    }
+
+    // 过期时间记录在日志里，重放之后过期的 key 还是过期的，清理不需要记录
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        self.table.purge_expired()
+    }
+}
+
+fn set_op(table: &str, key: &str, value: Value, expire_at: Option<u64>) -> WalOp {
+    WalOp::Set {
+        table: table.into(),
+        key: key.into(),
+        value,
+        expire_at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::storage::now_ms;
+
+    #[test]
+    fn data_should_survive_reopen() {
+        let dir = tempdir().unwrap();
+        {
+            let store = DurableMemTable::open(dir.path()).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+            store.del("t1", "k2").unwrap();
+            store.incr("t1", "count", 5).unwrap();
+            store.expire("t1", "count", Some(now_ms() + 60_000)).unwrap();
+            store.incr("t1", "count", 1).unwrap();
+            store.cas("t1", "k1", Some(&"v1".into()), "v1.1".into()).unwrap();
+            store.cas("t1", "k1", Some(&"nope".into()), "v1.2".into()).unwrap();
+            store.set("t1", "gone".into(), "x".into()).unwrap();
+            store.expire("t1", "gone", Some(now_ms() - 1)).unwrap();
+            let ops = vec![
+                TxnOp::Set {
+                    table: "t2".into(),
+                    key: "a".into(),
+                    value: 1.into(),
+                },
+                TxnOp::Del {
+                    table: "t1".into(),
+                    key: "k1".into(),
+                },
+            ];
+            store.transaction(&[], &ops).unwrap();
+            store.flush().unwrap();
+        }
+
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert_eq!(store.get("t1", "count").unwrap(), Some(6.into()));
+        // incr 保留了过期时间
+        assert!(store.expire_at("t1", "count").unwrap().is_some());
+        assert_eq!(store.get("t1", "gone").unwrap(), None);
+        assert_eq!(store.get("t2", "a").unwrap(), Some(1.into()));
+    }
+
+    #[test]
+    fn snapshot_should_replace_log() {
+        let dir = tempdir().unwrap();
+        {
+            let store = DurableMemTable::open(dir.path())
+                .unwrap()
+                .with_snapshot_threshold(1024);
+            for i in 0..100 {
+                store.set("t1", format!("k{}", i), i.into()).unwrap();
+            }
+            // 超过阈值之后做了 snapshot，日志被清空过
+            let wal = fs::metadata(dir.path().join(WAL_FILE)).unwrap().len();
+            assert!(wal < 1024);
+            assert!(dir.path().join(SNAPSHOT_FILE).exists());
+            store.del("t1", "k0").unwrap();
+        }
+
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get_all("t1").unwrap().len(), 99);
+        assert_eq!(store.get("t1", "k99").unwrap(), Some(99.into()));
+
+        // 显式地做 snapshot，之后日志是空的
+        store.snapshot().unwrap();
+        assert_eq!(fs::metadata(dir.path().join(WAL_FILE)).unwrap().len(), 0);
+        drop(store);
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get_all("t1").unwrap().len(), 99);
+    }
+
+    #[test]
+    fn rotated_log_should_be_replayed_until_snapshot_succeeds() {
+        let dir = tempdir().unwrap();
+        {
+            let store = DurableMemTable::open(dir.path()).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.snapshot().unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+        }
+
+        // 模拟轮转了日志之后，snapshot 还没写完就崩溃
+        let rotated = dir.path().join(rotated_log_name(3));
+        fs::rename(dir.path().join(WAL_FILE), &rotated).unwrap();
+
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        store.del("t1", "k1").unwrap();
+
+        // 新的 snapshot 写完之后，轮转出去的日志都删掉了
+        store.snapshot().unwrap();
+        assert!(!rotated.exists());
+        assert!(rotated_logs(dir.path()).unwrap().is_empty());
+        drop(store);
+
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn torn_last_record_should_be_discarded() {
+        let dir = tempdir().unwrap();
+        {
+            let store = DurableMemTable::open(dir.path()).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+        }
+
+        // 模拟写最后一条记录的时候崩溃
+        let path = dir.path().join(WAL_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        {
+            let store = DurableMemTable::open(dir.path()).unwrap();
+            assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+            assert_eq!(store.get("t1", "k2").unwrap(), None);
+            // 损坏的部分被截掉了，之后追加的记录可以正常读出来
+            store.set("t1", "k3".into(), "v3".into()).unwrap();
+        }
+
+        let store = DurableMemTable::open(dir.path()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+    }
+}
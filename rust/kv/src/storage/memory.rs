@@ -1,10 +1,121 @@
-use crate::{KvError, Kvpair, Storage, Value, StorageIter};
-use dashmap::{mapref::one::Ref, DashMap};
+use crate::{
+    storage::{add_float, add_integer, now_ms},
+    KvError, Kvpair, Storage, StorageIter, TxnOp, Value, Watch,
+};
+use dashmap::{
+    mapref::{entry::Entry as MapEntry, one::Ref},
+    DashMap,
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// key 锁的分段数量
+const LOCK_STRIPES: usize = 64;
 
 /// 使用 DashMap 构建的 MemTable，实现了 Storage trait。
 #[derive(Clone, Debug, Default)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: DashMap<String, DashMap<String, Entry>>,
+    locks: KeyLocks,
+}
+
+/// 按 key 分段的锁，所有的写操作都要先拿到 key 所在分段的锁
+///
+/// 事务需要同时锁住多个 key，按分段的序号从小到大加锁，这样就不会死锁。
+/// 读操作不拿分段的锁，而是持有 view 的读锁；一次修改多个 key 的写操作持有 view 的写锁，
+/// 读操作就看不到只做了一半的修改。需要两种锁的时候先拿 view，再拿分段的锁
+#[derive(Debug)]
+struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+    view: RwLock<()>,
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        Self {
+            stripes: (0..LOCK_STRIPES).map(|_| Mutex::default()).collect(),
+            view: RwLock::default(),
+        }
+    }
+}
+
+// clone 出来的 MemTable 是独立的数据，不需要共享锁
+impl Clone for KeyLocks {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl KeyLocks {
+    fn index(&self, table: &str, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (table, key).hash(&mut hasher);
+        hasher.finish() as usize % self.stripes.len()
+    }
+
+    /// 锁住一个 key
+    fn lock(&self, table: &str, key: &str) -> MutexGuard<'_, ()> {
+        self.lock_index(self.index(table, key))
+    }
+
+    /// 按顺序锁住一组 key
+    fn lock_all<'a>(
+        &self,
+        keys: impl Iterator<Item = (&'a str, &'a str)>,
+    ) -> Vec<MutexGuard<'_, ()>> {
+        let mut indexes: Vec<_> = keys.map(|(table, key)| self.index(table, key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        indexes.into_iter().map(|i| self.lock_index(i)).collect()
+    }
+
+    fn lock_index(&self, index: usize) -> MutexGuard<'_, ()> {
+        // 锁里没有数据，poison 了也没关系
+        self.stripes[index].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 读操作持有的锁，不能在持有它的时候再去拿一次
+    fn read(&self) -> RwLockReadGuard<'_, ()> {
+        self.view.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 一次修改多个 key 时持有的锁，期间所有的读操作都要等待
+    fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.view.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// MemTable 里存放的数据，带上可选的过期时间
+#[derive(Clone, Debug, Default)]
+struct Entry {
+    value: Value,
+    /// 过期时间（unix 时间戳，毫秒）
+    expire_at: Option<u64>,
+}
+
+impl Entry {
+    fn new(value: Value) -> Self {
+        Self {
+            value,
+            expire_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expire_at, Some(at) if at <= now)
+    }
+
+    /// 没有过期的话返回 value
+    fn into_live_value(self, now: u64) -> Option<Value> {
+        if self.is_expired(now) {
+            None
+        } else {
+            Some(self.value)
+        }
+    }
 }
 
 impl MemTable {
@@ -14,7 +125,7 @@ impl MemTable {
     }
 
     /// 如果名为 name 的 hashtable 不存在，则创建，否则返回
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Entry>> {
         match self.tables.get(name) {
             Some(table) => table,
             None => {
@@ -23,48 +134,289 @@ impl MemTable {
             }
         }
     }
+
+    /// 获取没有过期的 entry，如果已经过期，顺便删除掉
+    fn get_live_entry(&self, table: &str, key: &str) -> Option<Entry> {
+        let table = self.get_or_create_table(table);
+        let now = now_ms();
+        let entry = table.get(key).map(|v| v.value().clone())?;
+        if entry.is_expired(now) {
+            table.remove_if(key, |_, v| v.is_expired(now));
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// 原子地读取并修改 key：f 拿到当前的 value，返回新的 value（None 表示不修改）和结果
+    ///
+    /// 修改时保留 key 的过期时间
+    fn update<T>(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(Option<&Value>) -> Result<(Option<Value>, T), KvError>,
+    ) -> Result<T, KvError> {
+        let _guard = self.locks.lock(table, key);
+        let table = self.get_or_create_table(table);
+        let now = now_ms();
+        // entry 会一直锁住 key 所在的 shard，直到修改完成
+        let entry = table.entry(key.into());
+        match entry {
+            MapEntry::Occupied(mut entry) => {
+                let expired = entry.get().is_expired(now);
+                let current = Some(&entry.get().value).filter(|_| !expired);
+                let (new, result) = f(current)?;
+                match new {
+                    Some(value) if expired => {
+                        entry.insert(Entry::new(value));
+                    }
+                    Some(value) => entry.get_mut().value = value,
+                    None => {}
+                }
+                Ok(result)
+            }
+            MapEntry::Vacant(entry) => {
+                let (new, result) = f(None)?;
+                if let Some(value) = new {
+                    entry.insert(Entry::new(value));
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    /// 执行 f 里的多个写操作，读操作要么看到全部的修改，要么一个都看不到
+    ///
+    /// f 里不能有读操作
+    pub(crate) fn atomically<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _view = self.locks.exclusive();
+        f()
+    }
+
+    /// 导出所有没有过期的数据：table，key，value 和过期时间
+    pub(crate) fn entries(&self) -> Vec<(String, String, Value, Option<u64>)> {
+        let _view = self.locks.read();
+        let now = now_ms();
+        self.tables
+            .iter()
+            .flat_map(|table| {
+                table
+                    .iter()
+                    .filter(|v| !v.value().is_expired(now))
+                    .map(|v| {
+                        let entry = v.value();
+                        (table.key().clone(), v.key().clone(), entry.value.clone(), entry.expire_at)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// 读取没有过期的 value 和它的过期时间
+    pub(crate) fn get_entry(&self, table: &str, key: &str) -> Option<(Value, Option<u64>)> {
+        let _view = self.locks.read();
+        self.get_live_entry(table, key).map(|entry| (entry.value, entry.expire_at))
+    }
+
+    /// 写入 key，调用者需要持有 key 的锁
+    fn insert(&self, table: &str, key: String, entry: Entry) -> Option<Value> {
+        // 重新设置的 key 不再带有之前的过期时间
+        let table = self.get_or_create_table(table);
+        let old = table.insert(key, entry);
+        old.and_then(|entry| entry.into_live_value(now_ms()))
+    }
+
+    /// 删除 key，调用者需要持有 key 的锁
+    fn remove(&self, table: &str, key: &str) -> Option<Value> {
+        let table = self.get_or_create_table(table);
+        let old = table.remove(key).map(|(_k, v)| v);
+        old.and_then(|entry| entry.into_live_value(now_ms()))
+    }
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        let _view = self.locks.read();
+        Ok(self.get_live_entry(table, key).map(|entry| entry.value))
     }
 
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+    fn set_with_expire(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        expire_at: Option<u64>,
+    ) -> Result<Option<Value>, KvError> {
+        let _guard = self.locks.lock(table, &key);
+        Ok(self.insert(table, key, Entry { value, expire_at }))
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        let _view = self.locks.read();
+        Ok(self.get_live_entry(table, key).is_some())
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v)| v))
+        let _guard = self.locks.lock(table, key);
+        Ok(self.remove(table, key))
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let _view = self.locks.read();
         let table = self.get_or_create_table(table);
+        let now = now_ms();
         Ok(table
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
+            .filter(|v| !v.value().is_expired(now))
+            .map(|v| Kvpair::new(v.key(), v.value().value.clone()))
             .collect())
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
         // 使用 clone() 来获取 table 的 snapshot
-        let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
+        let table = {
+            let _view = self.locks.read();
+            self.get_or_create_table(table).clone()
+        };
+        let now = now_ms();
+        let data = table
+            .into_iter()
+            .filter_map(move |(k, entry)| entry.into_live_value(now).map(|v| (k, v)));
+        let iter = StorageIter::new(data);
         // let iter = table.into_iter().map(|data| data.into());
         Ok(Box::new(iter))
     }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self.tables.iter().map(|table| table.key().clone()).collect())
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        // DashMap 是无序的，只能把符合条件的 key 都找出来，排序后再取前 limit 个
+        let _view = self.locks.read();
+        let table = self.get_or_create_table(table);
+        let now = now_ms();
+        let mut pairs: Vec<_> = table
+            .iter()
+            .filter(|v| v.key().starts_with(prefix) && !v.value().is_expired(now))
+            .filter(|v| start_after.is_none_or(|start| v.key().as_str() > start))
+            .map(|v| Kvpair::new(v.key(), v.value().value.clone()))
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.update(table, key, |v| {
+            let n = add_integer(v, delta)?;
+            Ok((Some(n.into()), n))
+        })
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.update(table, key, |v| {
+            let n = add_float(v, delta)?;
+            Ok((Some(n.into()), n))
+        })
+    }
+
+    fn cas(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        self.update(table, key, |v| {
+            if v == expected {
+                Ok((Some(new.clone()), (true, Some(new.clone()))))
+            } else {
+                Ok((None, (false, v.cloned())))
+            }
+        })
+    }
+
+    fn transaction(&self, watches: &[Watch], ops: &[TxnOp]) -> Result<Vec<Option<Value>>, KvError> {
+        let keys = watches
+            .iter()
+            .map(|w| (w.table.as_str(), w.key.as_str()))
+            .chain(ops.iter().map(|op| op.target()));
+        // 先挡住读操作，再锁住涉及的 key
+        let _view = self.locks.exclusive();
+        let _guards = self.locks.lock_all(keys);
+
+        for w in watches {
+            if self.get_live_entry(&w.table, &w.key).map(|e| e.value) != w.value {
+                return Err(KvError::WatchFailed(w.table.clone(), w.key.clone()));
+            }
+        }
+
+        // 所有涉及的 key 都已经锁住了，下面的操作不会失败，也不会被其它写操作打断
+        let result = ops
+            .iter()
+            .map(|op| match op {
+                TxnOp::Get { table, key } => self.get_live_entry(table, key).map(|e| e.value),
+                TxnOp::Set { table, key, value } => {
+                    self.insert(table, key.clone(), Entry::new(value.clone()))
+                }
+                TxnOp::Del { table, key } => self.remove(table, key),
+            })
+            .collect();
+        Ok(result)
+    }
+
+    fn expire(&self, table: &str, key: &str, expire_at: Option<u64>) -> Result<bool, KvError> {
+        let _guard = self.locks.lock(table, key);
+        let table = self.get_or_create_table(table);
+        let now = now_ms();
+        let found = match table.get_mut(key) {
+            Some(mut entry) if !entry.is_expired(now) => {
+                entry.expire_at = expire_at;
+                true
+            }
+            _ => false,
+        };
+        Ok(found)
+    }
+
+    fn expire_at(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let _view = self.locks.read();
+        Ok(self
+            .get_live_entry(table, key)
+            .and_then(|entry| entry.expire_at))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let mut count = 0;
+        for table in self.tables.iter() {
+            table.retain(|_, entry| {
+                let expired = entry.is_expired(now);
+                count += expired as usize;
+                !expired
+            });
+        }
+        Ok(count)
+    }
+
+    // 所有的数据都在内存里，直接在 tokio 的工作线程里访问
+    fn is_blocking(&self) -> bool {
+        false
+    }
 }
 
 impl From<(String, Value)> for Kvpair {
     fn from(data: (String, Value)) -> Self {
         Kvpair::new(data.0, data.1)
     }
-}
\ No newline at end of file
+}
@@ -1,16 +1,34 @@
+mod durable;
 mod memory;
 mod sleddb;
+mod wal;
+pub use durable::{DurableMemTable, DEFAULT_SNAPSHOT_THRESHOLD};
 pub use memory::MemTable;
 pub use sleddb::SledDb;
 
-use crate::{KvError, Kvpair, Value};
+use std::{
+    convert::TryFrom,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{KvError, Kvpair, Value, Watch};
 
 /// 对存储的抽象，我们不关心数据存在哪儿，但需要定义外界如何和存储打交道
 pub trait Storage {
     /// 从一个 HashTable 里获取一个 key 的 value
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.set_with_expire(table, key, value, None)
+    }
+    /// 原子地设置 key 的 value 和过期时间（None 表示不过期），返回旧的 value
+    fn set_with_expire(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        expire_at: Option<u64>,
+    ) -> Result<Option<Value>, KvError>;
     /// 查看 HashTable 中是否有 key
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
     /// 从 HashTable 中删除一个 key
@@ -18,7 +36,97 @@ pub trait Storage {
     /// 遍历 HashTable，返回所有 kv pair（这个接口不好）
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     /// 遍历 HashTable，返回 kv pair 的 Iterator
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError>;
+    /// 返回所有 table 的名字，其中可能有已经没有 key 的 table
+    fn tables(&self) -> Result<Vec<String>, KvError>;
+    /// 按 key 的顺序遍历 HashTable，返回 key 以 prefix 开头，并且大于 start_after 的最多 limit 个 kv pair
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError>;
+    /// 原子地把 key 的整数 value 加上 delta，key 不存在时从 0 开始，返回新的 value
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError>;
+    /// 原子地把 key 的浮点数 value 加上 delta，key 不存在时从 0 开始，返回新的 value
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError>;
+    /// 如果 key 当前的 value 等于 expected（None 表示 key 不存在），原子地把它设置成 new
+    ///
+    /// 返回是否设置成功，以及 key 当前的 value。incr 和 cas 都会保留 key 的过期时间
+    fn cas(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<(bool, Option<Value>), KvError>;
+    /// 在一个事务里执行一组操作：先检查 watches，都满足才执行，要么全部生效，要么全部不生效
+    ///
+    /// 返回每个操作对应的 value：Get 返回当前的 value，Set/Del 返回旧的 value
+    fn transaction(&self, watches: &[Watch], ops: &[TxnOp]) -> Result<Vec<Option<Value>>, KvError>;
+    /// 设置 key 的过期时间（unix 时间戳，毫秒），None 表示不过期，返回 key 是否存在
+    fn expire(&self, table: &str, key: &str, expire_at: Option<u64>) -> Result<bool, KvError>;
+    /// 获取 key 的过期时间（unix 时间戳，毫秒）
+    fn expire_at(&self, table: &str, key: &str) -> Result<Option<u64>, KvError>;
+    /// 删除所有已经过期的 key，返回删除的数量
+    fn purge_expired(&self) -> Result<usize, KvError>;
+    /// 把缓存在内存里的写入持久化，服务器退出之前会调用
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+    /// 访问存储是否可能阻塞当前线程，比如需要磁盘 I/O
+    ///
+    /// Service 会把对阻塞的存储的操作放到 spawn_blocking 里执行，不会卡住其它连接
+    fn is_blocking(&self) -> bool {
+        true
+    }
+}
+
+/// 事务里对存储的操作
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxnOp {
+    Get { table: String, key: String },
+    Set { table: String, key: String, value: Value },
+    Del { table: String, key: String },
+}
+
+impl TxnOp {
+    /// 操作的 table 和 key
+    pub fn target(&self) -> (&str, &str) {
+        match self {
+            TxnOp::Get { table, key }
+            | TxnOp::Set { table, key, .. }
+            | TxnOp::Del { table, key } => (table, key),
+        }
+    }
+}
+
+/// incr 的计算：value 必须是整数，None 当作 0
+fn add_integer(value: Option<&Value>, delta: i64) -> Result<i64, KvError> {
+    let n = match value {
+        Some(v) => i64::try_from(v.clone())?,
+        None => 0,
+    };
+    n.checked_add(delta)
+        .ok_or_else(|| KvError::InvalidCommand(format!("{} + {} overflows", n, delta)))
+}
+
+/// incr_float 的计算：value 必须是浮点数，None 当作 0
+fn add_float(value: Option<&Value>, delta: f64) -> Result<f64, KvError> {
+    let n = match value {
+        Some(v) => f64::try_from(v.clone())?,
+        None => 0.0,
+    };
+    Ok(n + delta)
+}
+
+/// 当前的 unix 时间戳（毫秒）
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 /// 提供 Storage iterator，这样 trait 的实现者只需要
@@ -1,14 +1,38 @@
-use sled::{Db, IVec};
-use std::{convert::TryInto, path::Path, str};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree, Transactional,
+    },
+    Db, IVec, Tree,
+};
+use std::{convert::TryInto, ops::Bound, path::Path, str};
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use crate::{
+    storage::{add_float, add_integer, now_ms},
+    KvError, Kvpair, Storage, StorageIter, TxnOp, Value, Watch,
+};
+
+/// 存放过期时间的 tree 的名字
+const EXPIRATIONS_TREE: &str = "__expirations__";
 
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    // key 的过期时间单独存放在一个 tree 里，key 是 full key，value 是 u64 的时间戳
+    expirations: Tree,
+}
 
 impl SledDb {
+    /// 打开 path 下的数据库，打不开时 panic，只在确定路径可用的地方（比如测试）使用
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        Self::open(path).unwrap()
+    }
+
+    /// 打开 path 下的数据库，路径不可写或者被别的进程占用时返回错误
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let db = sled::open(path)?;
+        let expirations = db.open_tree(EXPIRATIONS_TREE)?;
+        Ok(Self { db, expirations })
     }
 
     // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
@@ -21,6 +45,58 @@ impl SledDb {
     fn get_table_prefix(table: &str) -> String {
         format!("{}:", table)
     }
+
+    /// 原子地读取并修改 key：f 拿到当前的 value，返回新的 value（None 表示不修改）和结果
+    ///
+    /// 读取、检查过期和写入在同一个事务里，如果中间 key 被别人修改了就重试，所以 f 可能被调用多次。
+    /// 修改时保留 key 的过期时间，已经过期的 key 当作不存在，写入之后不再过期
+    fn update<T>(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl Fn(Option<&Value>) -> Result<(Option<Value>, T), KvError>,
+    ) -> Result<T, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let now = now_ms();
+        let result = (&*self.db, &self.expirations).transaction(|(db, expirations)| {
+            let current = txn_get(db, expirations, &name, now)?;
+            let (new, result) = f(current.as_ref()).map_err(ConflictableTransactionError::Abort)?;
+            if let Some(v) = new {
+                let data = Vec::<u8>::try_from(v).map_err(ConflictableTransactionError::Abort)?;
+                if current.is_none() {
+                    expirations.remove(name.as_bytes())?;
+                }
+                db.insert(name.as_bytes(), data)?;
+            }
+            Ok(result)
+        });
+        result.map_err(txn_error)
+    }
+
+    /// full key 是否已经过期，如果过期，顺便删除掉
+    fn expire_if_needed(&self, name: &str) -> Result<bool, KvError> {
+        // 大部分 key 都没有过期，先在事务外面检查，不用每次读都开事务
+        let now = now_ms();
+        if !is_expired(&self.expirations, name.as_bytes(), now)? {
+            return Ok(false);
+        }
+        self.remove_expired(name.as_bytes(), now)
+    }
+
+    /// 在一个事务里检查 key 是否过期，过期的话从两个 tree 里删除
+    ///
+    /// 检查之后 key 可能被别人重新设置了，所以要在事务里再检查一次，返回是否删除了
+    fn remove_expired(&self, name: &[u8], now: u64) -> Result<bool, KvError> {
+        let result = (&*self.db, &self.expirations).transaction(|(db, expirations)| {
+            if !txn_is_expired(expirations, name, now)? {
+                return Ok(false);
+            }
+            expirations.remove(name)?;
+            db.remove(name)?;
+            Ok(true)
+        });
+        result.map_err(txn_error)
+    }
 }
 
 /// 把 Option<Result<T, E>> flip 成 Result<Option<T>, E>
@@ -32,48 +108,243 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        if self.expire_if_needed(&name)? {
+            return Ok(None);
+        }
+        let result = self.db.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
         flip(result)
     }
 
-    fn set(
+    fn set_with_expire(
         &self,
         table: &str,
         key: String,
         value: Value,
+        expire_at: Option<u64>,
     ) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, &key);
         let data: Vec<u8> = value.try_into()?;
+        let now = now_ms();
 
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
-        flip(result)
+        // value 和过期时间在一个事务里修改，重新设置的 key 不再带有之前的过期时间
+        let result = (&*self.db, &self.expirations).transaction(|(db, expirations)| {
+            let old = txn_get(db, expirations, &name, now)?;
+            match expire_at {
+                Some(at) => expirations.insert(name.as_bytes(), &at.to_be_bytes()[..])?,
+                None => expirations.remove(name.as_bytes())?,
+            };
+            db.insert(name.as_bytes(), data.as_slice())?;
+            Ok(old)
+        });
+        result.map_err(txn_error)
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
         let name = SledDb::get_full_key(table, key);
+        if self.expire_if_needed(&name)? {
+            return Ok(false);
+        }
 
-        Ok(self.0.contains_key(name)?)
+        Ok(self.db.contains_key(name)?)
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
-
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
-        flip(result)
+        let now = now_ms();
+        let result = (&*self.db, &self.expirations).transaction(|(db, expirations)| {
+            let old = txn_get(db, expirations, &name, now)?;
+            expirations.remove(name.as_bytes())?;
+            db.remove(name.as_bytes())?;
+            Ok(old)
+        });
+        result.map_err(txn_error)
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
-
-        Ok(result)
+        Ok(self.get_iter(table)?.collect())
     }
 
-    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+    fn get_iter(
+        &self,
+        table: &str,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair> + Send>, KvError> {
         let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
+        let expirations = self.expirations.clone();
+        let now = now_ms();
+        // 过期的 key 不返回
+        let data = self.db.scan_prefix(prefix).filter(move |v| match v {
+            Ok((k, _)) => !is_expired(&expirations, k, now).unwrap_or_default(),
+            Err(_) => true,
+        });
+        let iter = StorageIter::new(data);
         Ok(Box::new(iter))
     }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        // full key 是 table:key，找到一个 table 之后直接跳到 "table;"，也就是下一个 table 开始的地方
+        let mut tables = Vec::new();
+        let mut start = Bound::Unbounded;
+        while let Some(item) = self.db.range::<Vec<u8>, _>((start, Bound::Unbounded)).next() {
+            let (k, _) = item?;
+            let name = String::from_utf8_lossy(&k);
+            match name.split_once(':') {
+                Some((table, _)) => {
+                    start = Bound::Included(format!("{};", table).into_bytes());
+                    tables.push(table.to_string());
+                }
+                None => start = Bound::Excluded(k.to_vec()),
+            }
+        }
+        Ok(tables)
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<Kvpair>, KvError> {
+        // sled 里的 key 是有序的，直接从 prefix 或者 start_after 开始往后遍历
+        let prefix = SledDb::get_full_key(table, prefix);
+        let start = match start_after.map(|key| SledDb::get_full_key(table, key)) {
+            Some(start) if start >= prefix => Bound::Excluded(start),
+            _ => Bound::Included(prefix.clone()),
+        };
+
+        let now = now_ms();
+        let mut pairs = Vec::new();
+        for item in self.db.range::<String, _>((start, Bound::Unbounded)) {
+            let (k, v) = item?;
+            if pairs.len() >= limit || !k.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if is_expired(&self.expirations, &k, now)? {
+                continue;
+            }
+            pairs.push(Kvpair::new(ivec_to_key(&k), v.as_ref().try_into()?));
+        }
+        Ok(pairs)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.update(table, key, |v| {
+            let n = add_integer(v, delta)?;
+            Ok((Some(n.into()), n))
+        })
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.update(table, key, |v| {
+            let n = add_float(v, delta)?;
+            Ok((Some(n.into()), n))
+        })
+    }
+
+    fn cas(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        new: Value,
+    ) -> Result<(bool, Option<Value>), KvError> {
+        self.update(table, key, |v| {
+            if v == expected {
+                Ok((Some(new.clone()), (true, Some(new.clone()))))
+            } else {
+                Ok((None, (false, v.cloned())))
+            }
+        })
+    }
+
+    fn transaction(&self, watches: &[Watch], ops: &[TxnOp]) -> Result<Vec<Option<Value>>, KvError> {
+        // 先在事务外把 value 编码好，事务的闭包在冲突时会被重复执行
+        let ops = ops
+            .iter()
+            .map(|op| {
+                let (table, key) = op.target();
+                let name = SledDb::get_full_key(table, key);
+                let data = match op {
+                    TxnOp::Set { value, .. } => Some(Vec::<u8>::try_from(value.clone())?),
+                    _ => None,
+                };
+                Ok((op, name, data))
+            })
+            .collect::<Result<Vec<_>, KvError>>()?;
+        let now = now_ms();
+
+        let result = (&*self.db, &self.expirations).transaction(|(db, expirations)| {
+            for w in watches {
+                let name = SledDb::get_full_key(&w.table, &w.key);
+                if txn_get(db, expirations, &name, now)? != w.value {
+                    let e = KvError::WatchFailed(w.table.clone(), w.key.clone());
+                    return Err(ConflictableTransactionError::Abort(e));
+                }
+            }
+
+            let mut result = Vec::with_capacity(ops.len());
+            for (op, name, data) in ops.iter() {
+                let value = txn_get(db, expirations, name, now)?;
+                match (op, data) {
+                    (TxnOp::Set { .. }, Some(data)) => {
+                        expirations.remove(name.as_bytes())?;
+                        db.insert(name.as_bytes(), data.as_slice())?;
+                    }
+                    (TxnOp::Del { .. }, _) => {
+                        expirations.remove(name.as_bytes())?;
+                        db.remove(name.as_bytes())?;
+                    }
+                    _ => {}
+                }
+                result.push(value);
+            }
+            Ok(result)
+        });
+
+        result.map_err(txn_error)
+    }
+
+    fn expire(&self, table: &str, key: &str, expire_at: Option<u64>) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let now = now_ms();
+        // 检查 key 存在和修改过期时间在一个事务里，不会给刚被删除的 key 留下过期时间
+        let result = (&*self.db, &self.expirations).transaction(|(db, expirations)| {
+            if txn_is_expired(expirations, name.as_bytes(), now)? || db.get(&name)?.is_none() {
+                return Ok(false);
+            }
+            match expire_at {
+                Some(at) => expirations.insert(name.as_bytes(), &at.to_be_bytes()[..])?,
+                None => expirations.remove(name.as_bytes())?,
+            };
+            Ok(true)
+        });
+        result.map_err(txn_error)
+    }
+
+    fn expire_at(&self, table: &str, key: &str) -> Result<Option<u64>, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        if self.expire_if_needed(&name)? {
+            return Ok(None);
+        }
+        Ok(self.expirations.get(name)?.map(|v| ivec_to_u64(&v)))
+    }
+
+    fn purge_expired(&self) -> Result<usize, KvError> {
+        let now = now_ms();
+        let mut count = 0;
+        for item in self.expirations.iter() {
+            let (k, v) = item?;
+            if ivec_to_u64(&v) <= now && self.remove_expired(&k, now)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        self.db.flush()?;
+        Ok(())
+    }
 }
 
 impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
@@ -93,4 +364,43 @@ fn ivec_to_key(ivec: &[u8]) -> &str {
     let mut iter = s.split(':');
     iter.next();
     iter.next().unwrap()
-}
\ No newline at end of file
+}
+
+fn ivec_to_u64(ivec: &[u8]) -> u64 {
+    ivec.try_into().map(u64::from_be_bytes).unwrap_or_default()
+}
+
+fn is_expired(expirations: &Tree, name: &[u8], now: u64) -> Result<bool, KvError> {
+    Ok(matches!(expirations.get(name)?, Some(v) if ivec_to_u64(&v) <= now))
+}
+
+fn txn_is_expired(
+    expirations: &TransactionalTree,
+    name: &[u8],
+    now: u64,
+) -> ConflictableTransactionResult<bool, KvError> {
+    Ok(matches!(expirations.get(name)?, Some(v) if ivec_to_u64(&v) <= now))
+}
+
+fn txn_error(e: TransactionError<KvError>) -> KvError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+/// 在事务里读取一个 key，过期的 key 当作不存在
+fn txn_get(
+    db: &TransactionalTree,
+    expirations: &TransactionalTree,
+    name: &str,
+    now: u64,
+) -> ConflictableTransactionResult<Option<Value>, KvError> {
+    if txn_is_expired(expirations, name.as_bytes(), now)? {
+        return Ok(None);
+    }
+    match db.get(name)? {
+        Some(v) => Ok(Some(v.as_ref().try_into().map_err(ConflictableTransactionError::Abort)?)),
+        None => Ok(None),
+    }
+}
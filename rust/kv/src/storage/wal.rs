@@ -0,0 +1,293 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::{
+    convert::TryFrom,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use crate::{KvError, Value};
+
+/// 每条记录前面是 payload 的长度和 crc32，各 4 个字节
+const HEADER_LEN: usize = 8;
+
+const OP_SET: u8 = 1;
+const OP_DEL: u8 = 2;
+const OP_EXPIRE: u8 = 3;
+
+/// 日志里的一个操作
+///
+/// 记录的是操作之后 key 的状态，而不是操作本身（比如 incr 记录成 Set），
+/// 所以同一段日志重放多次的结果是一样的
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum WalOp {
+    Set {
+        table: String,
+        key: String,
+        value: Value,
+        expire_at: Option<u64>,
+    },
+    Del {
+        table: String,
+        key: String,
+    },
+    Expire {
+        table: String,
+        key: String,
+        expire_at: Option<u64>,
+    },
+}
+
+/// 只能追加的日志文件，每条记录是一组要么都生效、要么都不生效的操作
+pub(crate) struct Wal {
+    file: File,
+    path: PathBuf,
+    size: u64,
+}
+
+impl Wal {
+    /// 打开日志文件，返回其中完整的记录
+    ///
+    /// 写到一半的最后一条记录（比如进程在写日志时崩溃）会被丢弃，并且从文件里截掉，
+    /// 这样之后追加的记录不会跟在损坏的数据后面
+    pub fn open(path: &Path) -> Result<(Self, Vec<Vec<WalOp>>), KvError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let (records, valid) = decode_records(&data);
+        if valid < data.len() {
+            warn!(
+                "Discard {} bytes of torn record at the end of {}",
+                data.len() - valid,
+                path.display()
+            );
+            file.set_len(valid as u64)?;
+            file.sync_all()?;
+        }
+        let wal = Self {
+            file,
+            path: path.to_path_buf(),
+            size: valid as u64,
+        };
+        Ok((wal, records))
+    }
+
+    /// 追加一条记录，记录落盘之后才返回
+    ///
+    /// 写失败时把文件截回写之前的长度，不在日志里留下不完整的记录
+    pub fn append(&mut self, ops: &[WalOp]) -> Result<(), KvError> {
+        let mut buf = BytesMut::new();
+        encode_record(ops, &mut buf)?;
+        let result = self
+            .file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = result {
+            let _ = self.file.set_len(self.size);
+            return Err(e.into());
+        }
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// 把当前的日志文件改名为 to，之后的记录写到一个新的空文件里
+    pub fn rotate(&mut self, to: &Path) -> Result<(), KvError> {
+        fs::rename(&self.path, to)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        self.size = 0;
+        // 让 rename 和新建的文件落盘；有的平台不能打开目录，忽略错误
+        if let Some(dir) = self.path.parent().and_then(|dir| File::open(dir).ok()) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+
+    /// 日志文件的大小
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// 把一组操作编码成一条记录：长度，crc32，payload
+pub(crate) fn encode_record(ops: &[WalOp], buf: &mut BytesMut) -> Result<(), KvError> {
+    let mut payload = BytesMut::new();
+    payload.put_u32_le(ops.len() as u32);
+    for op in ops {
+        match op {
+            WalOp::Set {
+                table,
+                key,
+                value,
+                expire_at,
+            } => {
+                payload.put_u8(OP_SET);
+                put_bytes(&mut payload, table.as_bytes());
+                put_bytes(&mut payload, key.as_bytes());
+                put_bytes(&mut payload, &Vec::<u8>::try_from(value.clone())?);
+                payload.put_u64_le(expire_at.unwrap_or_default());
+            }
+            WalOp::Del { table, key } => {
+                payload.put_u8(OP_DEL);
+                put_bytes(&mut payload, table.as_bytes());
+                put_bytes(&mut payload, key.as_bytes());
+            }
+            WalOp::Expire {
+                table,
+                key,
+                expire_at,
+            } => {
+                payload.put_u8(OP_EXPIRE);
+                put_bytes(&mut payload, table.as_bytes());
+                put_bytes(&mut payload, key.as_bytes());
+                payload.put_u64_le(expire_at.unwrap_or_default());
+            }
+        }
+    }
+
+    buf.reserve(HEADER_LEN + payload.len());
+    buf.put_u32_le(payload.len() as u32);
+    buf.put_u32_le(crc32fast::hash(&payload));
+    buf.put_slice(&payload);
+    Ok(())
+}
+
+/// 依次解码 data 里的记录，遇到不完整或者校验失败的记录就停下来
+///
+/// 返回解码出来的记录，以及它们在 data 里占用的长度
+pub(crate) fn decode_records(data: &[u8]) -> (Vec<Vec<WalOp>>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while data.len() - offset >= HEADER_LEN {
+        let mut header = &data[offset..offset + HEADER_LEN];
+        let len = header.get_u32_le() as usize;
+        let crc = header.get_u32_le();
+        let start = offset + HEADER_LEN;
+        let payload = match data.get(start..start + len) {
+            Some(payload) if crc32fast::hash(payload) == crc => payload,
+            _ => break,
+        };
+        match decode_ops(payload) {
+            Some(ops) => records.push(ops),
+            None => break,
+        }
+        offset = start + len;
+    }
+    (records, offset)
+}
+
+fn decode_ops(mut buf: &[u8]) -> Option<Vec<WalOp>> {
+    let count = get_u32(&mut buf)?;
+    let mut ops = Vec::new();
+    for _ in 0..count {
+        if !buf.has_remaining() {
+            return None;
+        }
+        let op = buf.get_u8();
+        let table = get_string(&mut buf)?;
+        let key = get_string(&mut buf)?;
+        let op = match op {
+            OP_SET => {
+                let value = Value::try_from(get_bytes(&mut buf)?).ok()?;
+                let expire_at = get_expire_at(&mut buf)?;
+                WalOp::Set {
+                    table,
+                    key,
+                    value,
+                    expire_at,
+                }
+            }
+            OP_DEL => WalOp::Del { table, key },
+            OP_EXPIRE => {
+                let expire_at = get_expire_at(&mut buf)?;
+                WalOp::Expire {
+                    table,
+                    key,
+                    expire_at,
+                }
+            }
+            _ => return None,
+        };
+        ops.push(op);
+    }
+    Some(ops)
+}
+
+fn put_bytes(buf: &mut BytesMut, data: &[u8]) {
+    buf.put_u32_le(data.len() as u32);
+    buf.put_slice(data);
+}
+
+fn get_u32(buf: &mut &[u8]) -> Option<u32> {
+    (buf.remaining() >= 4).then(|| buf.get_u32_le())
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = get_u32(buf)? as usize;
+    let data = buf.get(..len)?;
+    buf.advance(len);
+    Some(data)
+}
+
+fn get_string(buf: &mut &[u8]) -> Option<String> {
+    String::from_utf8(get_bytes(buf)?.to_vec()).ok()
+}
+
+/// 过期时间 0 表示不过期
+fn get_expire_at(buf: &mut &[u8]) -> Option<Option<u64>> {
+    (buf.remaining() >= 8).then(|| Some(buf.get_u64_le()).filter(|at| *at != 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_should_roundtrip_and_stop_at_torn_tail() {
+        let ops = vec![
+            WalOp::Set {
+                table: "t1".into(),
+                key: "k1".into(),
+                value: "v1".into(),
+                expire_at: Some(42),
+            },
+            WalOp::Del {
+                table: "t1".into(),
+                key: "k2".into(),
+            },
+            WalOp::Expire {
+                table: "t1".into(),
+                key: "k1".into(),
+                expire_at: None,
+            },
+        ];
+        let mut buf = BytesMut::new();
+        encode_record(&ops, &mut buf).unwrap();
+        encode_record(&ops[..1], &mut buf).unwrap();
+        let len = buf.len();
+
+        let (records, valid) = decode_records(&buf);
+        assert_eq!(records, vec![ops.clone(), ops[..1].to_vec()]);
+        assert_eq!(valid, len);
+
+        // 最后一条记录少了一个字节
+        let (records, valid) = decode_records(&buf[..len - 1]);
+        assert_eq!(records, vec![ops.clone()]);
+        assert!(valid < len - 1);
+
+        // 最后一条记录的内容被破坏了
+        let mut corrupted = buf.clone();
+        corrupted[len - 1] ^= 0xff;
+        let (records, _) = decode_records(&corrupted);
+        assert_eq!(records, vec![ops]);
+    }
+}
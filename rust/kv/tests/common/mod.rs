@@ -0,0 +1,28 @@
+// 每个测试文件只用到其中的一部分
+#![allow(dead_code)]
+
+use std::{net::TcpListener, time::Duration};
+
+use anyhow::Result;
+use kv::{start_client_with_config, ClientConfig, ProstClientStream};
+
+/// 找一个空闲的端口
+pub fn free_addr() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.to_string())
+}
+
+/// 服务器是在后台启动的，连接之前可能还没开始监听
+pub async fn connect(config: &ClientConfig) -> Result<ProstClientStream> {
+    let mut retries = 50;
+    loop {
+        match start_client_with_config(config).await {
+            Ok(client) => return Ok(client),
+            Err(_) if retries > 0 => {
+                retries -= 1;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
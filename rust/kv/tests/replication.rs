@@ -0,0 +1,70 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::{connect, free_addr};
+use kv::{
+    start_server_with_config, ClientConfig, CommandRequest, CommandResponse, KvError,
+    ProstClientStream, ReplicaConfig, ReplicationConfig, ServerConfig, StorageConfig,
+};
+
+#[tokio::test]
+async fn replica_should_follow_primary_and_redirect_writes() -> Result<()> {
+    let (primary_addr, replica_addr) = (free_addr()?, free_addr()?);
+    let mut primary_config = ServerConfig::load("fixtures/server.conf")?;
+    primary_config.general.addr = primary_addr.clone();
+    primary_config.storage = StorageConfig::MemTable;
+    primary_config.replication = Some(ReplicationConfig::Primary);
+
+    let mut primary_client = ClientConfig::load("fixtures/client.conf")?;
+    primary_client.general.addr = primary_addr.clone();
+    let mut replica_config = primary_config.clone();
+    replica_config.general.addr = replica_addr.clone();
+    replica_config.replication = Some(ReplicationConfig::Replica(ReplicaConfig {
+        primary: primary_client.clone(),
+        token: None,
+    }));
+    let mut replica_client = primary_client.clone();
+    replica_client.general.addr = replica_addr;
+
+    tokio::spawn(async move { start_server_with_config(&primary_config).await });
+    let primary = connect(&primary_client).await?;
+    let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+    primary.execute(cmd).await?.into_result()?;
+
+    tokio::spawn(async move { start_server_with_config(&replica_config).await });
+    let replica = connect(&replica_client).await?;
+
+    // 全量同步过来的数据
+    let res = eventually(&replica, CommandRequest::new_hget("t1", "k1")).await?;
+    assert_eq!(res.values, vec!["v1".into()]);
+
+    // 之后在 primary 上的写入
+    let cmd = CommandRequest::new_hset("t1", "k2", "v2".into());
+    primary.execute(cmd).await?.into_result()?;
+    let res = eventually(&replica, CommandRequest::new_hget("t1", "k2")).await?;
+    assert_eq!(res.values, vec!["v2".into()]);
+
+    // replica 拒绝写入，告诉客户端 primary 的地址
+    let cmd = CommandRequest::new_hset("t1", "k3", "v3".into());
+    let result = replica.execute(cmd).await?.into_result();
+    assert!(matches!(result, Err(KvError::Redirect(addr)) if addr == primary_addr));
+
+    Ok(())
+}
+
+/// replica 是异步同步数据的，重试直到命令成功
+async fn eventually(client: &ProstClientStream, cmd: CommandRequest) -> Result<CommandResponse> {
+    let mut retries = 100;
+    loop {
+        match client.execute(cmd.clone()).await?.into_result() {
+            Ok(res) => return Ok(res),
+            Err(_) if retries > 0 => {
+                retries -= 1;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,21 @@
+mod common;
+
+use anyhow::Result;
+use common::free_addr;
+use kv::{start_server_with_config, KvError, ServerConfig, StorageConfig};
+use tempfile::NamedTempFile;
+
+#[tokio::test]
+async fn server_should_fail_with_config_error_for_bad_sled_path() -> Result<()> {
+    // 路径是一个普通文件，sled 没法在这里建数据库
+    let file = NamedTempFile::new()?;
+    let path = file.path().display().to_string();
+    let mut config = ServerConfig::load("fixtures/server.conf")?;
+    config.general.addr = free_addr()?;
+    config.storage = StorageConfig::SledDb(path.clone());
+
+    let result = start_server_with_config(&config).await;
+    assert!(matches!(result, Err(KvError::ConfigError(p, _)) if p == path));
+
+    Ok(())
+}
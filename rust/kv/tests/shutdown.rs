@@ -0,0 +1,89 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::{connect, free_addr};
+use kv::{
+    start_client_with_config, start_server_with_shutdown, ClientConfig, CommandRequest,
+    ServerConfig, SledDb, Storage, StorageConfig,
+};
+use tempfile::tempdir;
+use tokio::{net::TcpStream, sync::oneshot};
+
+#[tokio::test]
+async fn server_should_shutdown_gracefully_and_flush_store() -> Result<()> {
+    let dir = tempdir()?;
+    let addr = free_addr()?;
+
+    let mut server_config = ServerConfig::load("fixtures/server.conf")?;
+    server_config.general.addr = addr.clone();
+    server_config.storage = StorageConfig::SledDb(dir.path().display().to_string());
+    let mut client_config = ClientConfig::load("fixtures/client.conf")?;
+    client_config.general.addr = addr;
+
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let shutdown = async {
+            let _ = stop_rx.await;
+        };
+        start_server_with_shutdown(&server_config, shutdown).await
+    });
+
+    let client = connect(&client_config).await?;
+    let res = client.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+    assert_eq!(res.status, 200);
+
+    // 通知服务器退出，服务器等连接处理完之后返回
+    stop_tx.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server).await???;
+
+    // 已有的连接被关闭，也不再接受新的连接
+    assert!(client.execute(CommandRequest::new_hget("t1", "k1")).await.is_err());
+    assert!(start_client_with_config(&client_config).await.is_err());
+
+    // 数据已经刷到磁盘上，服务器释放了数据库之后可以重新打开
+    let store = SledDb::new(dir.path());
+    assert_eq!(store.get("t1", "k1")?, Some("v1".into()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_should_close_remaining_connections_after_timeout() -> Result<()> {
+    let dir = tempdir()?;
+    let addr = free_addr()?;
+
+    let mut server_config = ServerConfig::load("fixtures/server.conf")?;
+    server_config.general.addr = addr.clone();
+    server_config.general.shutdown_timeout_secs = 1;
+    server_config.storage = StorageConfig::SledDb(dir.path().display().to_string());
+    let mut client_config = ClientConfig::load("fixtures/client.conf")?;
+    client_config.general.addr = addr.clone();
+
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let shutdown = async {
+            let _ = stop_rx.await;
+        };
+        start_server_with_shutdown(&server_config, shutdown).await
+    });
+
+    // 这个连接一直不做 TLS 握手，服务器退出时等不到它结束
+    let client = connect(&client_config).await?;
+    let _stuck = TcpStream::connect(&addr).await?;
+    // 后面的连接能执行命令，说明前面的连接已经被 accept 了
+    let another = start_client_with_config(&client_config).await?;
+    let res = another.execute(CommandRequest::new_hset("t1", "k1", "v1".into())).await?;
+    assert_eq!(res.status, 200);
+
+    stop_tx.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server).await???;
+    assert!(client.execute(CommandRequest::new_hget("t1", "k1")).await.is_err());
+
+    // 超时的连接被关掉之后不再持有数据库，可以重新打开
+    let store = SledDb::new(dir.path());
+    assert_eq!(store.get("t1", "k1")?, Some("v1".into()));
+
+    Ok(())
+}